@@ -0,0 +1,424 @@
+//! Commit-scoped write batching.
+//!
+//! Merging a commit makes OpenMLS call a dozen `write_*` and `delete_*`
+//! methods in a row. A [`SledStorageBatch`] buffers all of those writes and
+//! applies them in a single sled transaction on [`SledStorageBatch::commit`],
+//! so a crash can never leave a group with state from two different epochs.
+//!
+//! ```ignore
+//! let batch = storage.begin_batch();
+//! group.merge_staged_commit(&provider_for(&batch), staged_commit)?;
+//! batch.commit()?;
+//! ```
+//!
+//! The batch dereferences to a [`SledStorage`], so it can be handed to anything
+//! expecting a `StorageProvider`. Reads through the batch see its own pending
+//! writes. Dropping a batch without committing it discards every pending write.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use sled::IVec;
+
+use crate::{SledStorage, SledStorageError};
+
+/// A pending write to a single key.
+#[derive(Debug, Clone)]
+pub(crate) enum PendingWrite {
+    /// Replace the stored value with an encoded value.
+    Put(Vec<u8>),
+    /// Remove the key.
+    Remove,
+}
+
+/// Writes buffered by a batch, keyed by tree and then by key.
+#[derive(Debug, Default)]
+pub(crate) struct PendingWrites {
+    trees: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, PendingWrite>>,
+}
+
+impl PendingWrites {
+    fn tree(&mut self, tree: &[u8]) -> &mut BTreeMap<Vec<u8>, PendingWrite> {
+        self.trees.entry(tree.to_vec()).or_default()
+    }
+
+    pub(crate) fn put(&mut self, tree: &[u8], key: &[u8], value: Vec<u8>) {
+        self.tree(tree)
+            .insert(key.to_vec(), PendingWrite::Put(value));
+    }
+
+    pub(crate) fn remove(&mut self, tree: &[u8], key: &[u8]) {
+        self.tree(tree).insert(key.to_vec(), PendingWrite::Remove);
+    }
+
+    /// Resolves the value of a key by applying the pending write, if any, to
+    /// the stored value.
+    pub(crate) fn resolve(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+        stored: Option<IVec>,
+    ) -> Result<Option<IVec>, SledStorageError> {
+        match self.trees.get(tree).and_then(|writes| writes.get(key)) {
+            None => Ok(stored),
+            Some(PendingWrite::Put(value)) => Ok(Some(value.as_slice().into())),
+            Some(PendingWrite::Remove) => Ok(None),
+        }
+    }
+
+    /// Applies the pending writes to keys starting with `prefix` to the
+    /// stored entries under that prefix.
+    pub(crate) fn resolve_prefix(
+        &self,
+        tree: &[u8],
+        prefix: &[u8],
+        stored: &mut BTreeMap<IVec, IVec>,
+    ) {
+        let Some(writes) = self.trees.get(tree) else {
+            return;
+        };
+        let writes = writes
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, write) in writes {
+            match write {
+                PendingWrite::Put(value) => {
+                    stored.insert(key.as_slice().into(), value.as_slice().into());
+                }
+                PendingWrite::Remove => {
+                    stored.remove(key.as_slice());
+                }
+            }
+        }
+    }
+
+    /// Applies the pending writes to keys starting with `prefix` to the
+    /// stored keys under that prefix.
+    pub(crate) fn resolve_prefix_keys(
+        &self,
+        tree: &[u8],
+        prefix: &[u8],
+        stored: &mut BTreeSet<IVec>,
+    ) {
+        let Some(writes) = self.trees.get(tree) else {
+            return;
+        };
+        let writes = writes
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, write) in writes {
+            match write {
+                PendingWrite::Put(_) => {
+                    stored.insert(key.as_slice().into());
+                }
+                PendingWrite::Remove => {
+                    stored.remove(key.as_slice());
+                }
+            }
+        }
+    }
+
+    /// Returns the pending writes to keys starting with `prefix`, in order.
+    /// A value of `None` stands for a removal.
+    pub(crate) fn writes_with_prefix(
+        &self,
+        tree: &[u8],
+        prefix: &[u8],
+    ) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let Some(writes) = self.trees.get(tree) else {
+            return Vec::new();
+        };
+        writes
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, write)| match write {
+                PendingWrite::Put(value) => (key.clone(), Some(value.clone())),
+                PendingWrite::Remove => (key.clone(), None),
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.trees.values().map(BTreeMap::len).sum()
+    }
+}
+
+/// A set of writes that is applied atomically, or not at all.
+///
+/// Created by [`SledStorage::begin_batch`]. Dereferences to a [`SledStorage`]
+/// whose writes are buffered until [`SledStorageBatch::commit`] is called.
+/// Dropping the batch without committing rolls back every buffered write.
+pub struct SledStorageBatch {
+    storage: SledStorage,
+}
+
+impl SledStorage {
+    /// Starts a batch of writes.
+    ///
+    /// Batches do not nest: calling this on a batch starts an independent batch
+    /// that does not see the pending writes of the first one.
+    ///
+    /// # Returns
+    ///
+    /// A new, empty `SledStorageBatch`.
+    pub fn begin_batch(&self) -> SledStorageBatch {
+        SledStorageBatch {
+            storage: SledStorage {
+                db: self.db.clone(),
+                batch: Some(Mutex::new(PendingWrites::default())),
+                storage_key: self.storage_key.clone(),
+                key_hasher: self.key_hasher.clone(),
+                path: None,
+                secure_delete: self.secure_delete,
+                key_package_lifetime: self.key_package_lifetime,
+                dangling_proposal_refs: self.dangling_proposal_refs,
+                handles: self.handles.clone(),
+            },
+        }
+    }
+
+    /// Returns `true` if writes through this instance are buffered in a batch.
+    pub fn is_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    /// Locks the pending writes if this instance belongs to a batch.
+    pub(crate) fn pending(&self) -> Option<MutexGuard<'_, PendingWrites>> {
+        // The pending writes are never left half-updated, so a poisoned lock
+        // is still safe to use.
+        self.batch
+            .as_ref()
+            .map(|batch| batch.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Runs `f` so that all of its writes are applied atomically.
+    ///
+    /// Inside a batch the writes simply join the batch. Otherwise they are
+    /// collected in a batch of their own that is committed when `f` succeeds.
+    pub(crate) fn atomically<A>(
+        &self,
+        f: impl FnOnce(&SledStorage) -> Result<A, SledStorageError>,
+    ) -> Result<A, SledStorageError> {
+        if self.is_batch() {
+            return f(self);
+        }
+        let batch = self.begin_batch();
+        let result = f(&batch)?;
+        batch.commit()?;
+        Ok(result)
+    }
+}
+
+impl SledStorageBatch {
+    /// Applies all buffered writes in a single transaction.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`. On error none of
+    /// the buffered writes have been applied.
+    pub fn commit(self) -> Result<(), SledStorageError> {
+        let pending = match self.storage.pending() {
+            Some(mut pending) => std::mem::take(&mut *pending),
+            None => return Ok(()),
+        };
+        if pending.len() == 0 {
+            return Ok(());
+        }
+
+        let tree_names: Vec<&[u8]> = pending.trees.keys().map(Vec::as_slice).collect();
+
+        tracing::debug!(target: "openmls_sled_storage::batch", "Committing {} writes to {} trees", pending.len(), tree_names.len());
+
+        self.storage.transaction(&tree_names, |trees| {
+            for (tree, writes) in trees.iter().zip(pending.trees.values()) {
+                for (key, write) in writes {
+                    match write {
+                        PendingWrite::Put(value) => {
+                            tree.insert(key.as_slice(), value.as_slice())?;
+                        }
+                        PendingWrite::Remove => {
+                            tree.remove(key.as_slice())?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Discards all buffered writes. Equivalent to dropping the batch.
+    pub fn rollback(self) {}
+}
+
+impl Deref for SledStorageBatch {
+    type Target = SledStorage;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+impl Drop for SledStorageBatch {
+    fn drop(&mut self) {
+        if let Some(pending) = self.storage.pending() {
+            if pending.len() > 0 {
+                tracing::debug!(target: "openmls_sled_storage::batch", "Rolling back {} uncommitted writes", pending.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openmls_traits::storage::{Entity, CURRENT_VERSION};
+    use tempfile::tempdir;
+
+    #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+    struct TestEntity {
+        data: String,
+    }
+
+    impl Entity<CURRENT_VERSION> for TestEntity {}
+
+    fn entity(data: &str) -> TestEntity {
+        TestEntity {
+            data: data.to_string(),
+        }
+    }
+
+    fn bytes(data: &str) -> Vec<u8> {
+        serde_json::to_vec(&entity(data)).unwrap()
+    }
+
+    fn setup_storage() -> SledStorage {
+        SledStorage::new_from_path(tempdir().unwrap().path()).unwrap()
+    }
+
+    #[test]
+    fn test_writes_are_applied_on_commit() {
+        let storage = setup_storage();
+        let batch = storage.begin_batch();
+        assert!(batch.is_batch());
+
+        batch
+            .write::<CURRENT_VERSION>(b"tree1", b"key", bytes("value"))
+            .unwrap();
+        batch
+            .append::<CURRENT_VERSION>(b"tree2", b"key", bytes("item"))
+            .unwrap();
+
+        // Reads through the batch see its own writes, other readers do not.
+        let read: Option<TestEntity> = batch.read::<CURRENT_VERSION, _>(b"tree1", b"key").unwrap();
+        assert_eq!(read, Some(entity("value")));
+        let read: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(b"tree1", b"key")
+            .unwrap();
+        assert_eq!(read, None);
+
+        batch.commit().unwrap();
+
+        let read: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(b"tree1", b"key")
+            .unwrap();
+        assert_eq!(read, Some(entity("value")));
+        let read: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(b"tree2", b"key")
+            .unwrap();
+        assert_eq!(read, vec![entity("item")]);
+    }
+
+    #[test]
+    fn test_drop_rolls_back() {
+        let storage = setup_storage();
+        storage
+            .write::<CURRENT_VERSION>(b"tree", b"existing", bytes("value"))
+            .unwrap();
+
+        {
+            let batch = storage.begin_batch();
+            batch
+                .write::<CURRENT_VERSION>(b"tree", b"new", bytes("value"))
+                .unwrap();
+            batch
+                .delete::<CURRENT_VERSION>(b"tree", b"existing")
+                .unwrap();
+        }
+
+        let read: Option<TestEntity> = storage.read::<CURRENT_VERSION, _>(b"tree", b"new").unwrap();
+        assert_eq!(read, None);
+        let read: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(b"tree", b"existing")
+            .unwrap();
+        assert_eq!(read, Some(entity("value")));
+    }
+
+    #[test]
+    fn test_delete_in_batch() {
+        let storage = setup_storage();
+        storage
+            .write::<CURRENT_VERSION>(b"tree", b"key", bytes("value"))
+            .unwrap();
+
+        let batch = storage.begin_batch();
+        batch.delete::<CURRENT_VERSION>(b"tree", b"key").unwrap();
+        let read: Option<TestEntity> = batch.read::<CURRENT_VERSION, _>(b"tree", b"key").unwrap();
+        assert_eq!(read, None);
+        batch.commit().unwrap();
+
+        let read: Option<TestEntity> = storage.read::<CURRENT_VERSION, _>(b"tree", b"key").unwrap();
+        assert_eq!(read, None);
+    }
+
+    #[test]
+    fn test_list_edits_keep_concurrent_writes() {
+        let storage = setup_storage();
+        storage
+            .append::<CURRENT_VERSION>(b"tree", b"key", bytes("first"))
+            .unwrap();
+
+        let batch = storage.begin_batch();
+        batch
+            .append::<CURRENT_VERSION>(b"tree", b"key", bytes("batched"))
+            .unwrap();
+        batch
+            .remove_item::<CURRENT_VERSION>(b"tree", b"key", bytes("first"))
+            .unwrap();
+        let read: Vec<TestEntity> = batch
+            .read_list::<CURRENT_VERSION, _>(b"tree", b"key")
+            .unwrap();
+        assert_eq!(read, vec![entity("batched")]);
+
+        // Appended by someone else while the batch is open.
+        storage
+            .append::<CURRENT_VERSION>(b"tree", b"key", bytes("concurrent"))
+            .unwrap();
+
+        batch.commit().unwrap();
+
+        let read: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(b"tree", b"key")
+            .unwrap();
+        assert_eq!(read, vec![entity("batched"), entity("concurrent")]);
+    }
+
+    #[test]
+    fn test_failed_update_applies_nothing() {
+        let storage = setup_storage();
+
+        let result: Result<(), _> = storage.atomically(|storage| {
+            storage.write::<CURRENT_VERSION>(b"tree", b"key", bytes("value"))?;
+            storage.append::<CURRENT_VERSION>(b"list_tree", b"key", bytes("item"))?;
+            Err(SledStorageError::not_found())
+        });
+        assert!(result.is_err());
+
+        let read: Option<TestEntity> = storage.read::<CURRENT_VERSION, _>(b"tree", b"key").unwrap();
+        assert_eq!(read, None);
+        let read: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(b"list_tree", b"key")
+            .unwrap();
+        assert!(read.is_empty());
+    }
+}
@@ -0,0 +1,404 @@
+//! On-disk value encoding.
+//!
+//! Every value written to sled is wrapped in a small binary envelope:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 2    | Magic bytes, `b"OS"`                    |
+//! | 2      | 1    | Format version, currently [`FORMAT_VERSION`] |
+//! | 3      | 1    | Codec id, see [`Codec`]                 |
+//! | 4      | 4    | Checksum                                |
+//! | 8      | ..   | Payload                                 |
+//!
+//! The checksum is the CRC-32 of the first four header bytes and the payload,
+//! stored big-endian. It catches values that were corrupted on disk, see
+//! [`checksum_matches`]. Envelopes of format version 1 have no checksum field;
+//! their payload starts at offset 4. Only the schema migrations in
+//! [`crate::migrations`] read them, to add checksums to them. Everywhere else
+//! an outer envelope without a checksum is treated as corrupted. Envelopes
+//! inside an encrypted payload keep format version 1, since the ciphertext is
+//! authenticated already.
+//!
+//! For [`Codec::Entity`] the payload is the entity bytes exactly as OpenMLS
+//! handed them to the storage provider. For [`Codec::List`] the payload is a
+//! sequence of items, each prefixed with its length as a big-endian `u32`.
+//!
+//! For [`Codec::Encrypted`] the payload is a nonce followed by the ciphertext
+//! of another envelope, see [`crate::encryption`].
+//!
+//! Lists are only stored as a single [`Codec::List`] value in schema version 1.
+//! Since version 2 every list item is stored as an entity under a key of its
+//! own, see [`crate::helpers::list_item_key`].
+//!
+//! Databases written before the envelope was introduced stored every value as
+//! a JSON array of byte values. Those values never start with the magic bytes;
+//! they are rewritten by the schema migrations in [`crate::migrations`] using
+//! [`decode_legacy_entity`] and [`decode_legacy_list`].
+
+use crate::{helpers, SledStorageError};
+
+/// Magic bytes at the start of every encoded value.
+pub const MAGIC: [u8; 2] = *b"OS";
+
+/// The current version of the value envelope.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// The version of the value envelope without a checksum.
+pub const FORMAT_VERSION_UNCHECKED: u8 = 1;
+
+/// Length of the envelope header in bytes.
+pub const HEADER_LEN: usize = 8;
+
+/// Length of the header of envelopes without a checksum in bytes.
+const UNCHECKED_HEADER_LEN: usize = 4;
+
+/// Identifies how the payload of an envelope is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    /// A single entity, stored as raw bytes.
+    Entity = 0,
+    /// A list of entities, each prefixed with its big-endian `u32` length.
+    List = 1,
+    /// An encrypted envelope.
+    Encrypted = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = SledStorageError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Entity),
+            1 => Ok(Self::List),
+            2 => Ok(Self::Encrypted),
+            _ => Err(SledStorageError::malformed()),
+        }
+    }
+}
+
+/// Starts an envelope for a payload of the given codec and length. The
+/// checksum is filled in by [`seal`] once the payload has been appended.
+fn header(codec: Codec, payload_len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + payload_len);
+    out.extend_from_slice(&MAGIC);
+    out.push(FORMAT_VERSION);
+    out.push(codec as u8);
+    out.extend_from_slice(&[0; 4]);
+    out
+}
+
+/// Computes the checksum of an envelope from its first four header bytes and
+/// its payload.
+fn checksum(header: &[u8], payload: &[u8]) -> [u8; 4] {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..UNCHECKED_HEADER_LEN]);
+    hasher.update(payload);
+    hasher.finalize().to_be_bytes()
+}
+
+/// Fills in the checksum of an envelope started with [`header`].
+fn seal(mut out: Vec<u8>) -> Vec<u8> {
+    let (header, payload) = out.split_at(HEADER_LEN);
+    let checksum = checksum(header, payload);
+    out[UNCHECKED_HEADER_LEN..HEADER_LEN].copy_from_slice(&checksum);
+    out
+}
+
+/// Encodes a single entity.
+///
+/// # Arguments
+///
+/// * `value` - The serialized entity.
+///
+/// # Returns
+///
+/// The envelope bytes to be stored.
+pub fn encode_entity(value: &[u8]) -> Vec<u8> {
+    let mut out = header(Codec::Entity, value.len());
+    out.extend_from_slice(value);
+    seal(out)
+}
+
+/// Encodes a list of entities.
+///
+/// # Arguments
+///
+/// * `items` - The serialized entities, in order.
+///
+/// # Returns
+///
+/// The envelope bytes to be stored.
+pub fn encode_list<T: AsRef<[u8]>>(items: &[T]) -> Vec<u8> {
+    let payload_len = items.iter().map(|item| 4 + item.as_ref().len()).sum();
+    let mut out = header(Codec::List, payload_len);
+    for item in items {
+        let item = item.as_ref();
+        out.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        out.extend_from_slice(item);
+    }
+    seal(out)
+}
+
+/// Encodes an encrypted envelope.
+///
+/// # Arguments
+///
+/// * `payload` - The nonce followed by the ciphertext.
+///
+/// # Returns
+///
+/// The envelope bytes to be stored.
+pub fn encode_encrypted(payload: &[u8]) -> Vec<u8> {
+    let mut out = header(Codec::Encrypted, payload.len());
+    out.extend_from_slice(payload);
+    seal(out)
+}
+
+/// Returns `true` if the value is wrapped in an envelope.
+pub fn is_envelope(stored: &[u8]) -> bool {
+    stored.starts_with(&MAGIC)
+}
+
+/// Returns the length of the header of an envelope, based on its format
+/// version.
+fn header_len(stored: &[u8]) -> Option<usize> {
+    if !is_envelope(stored) || stored.len() < UNCHECKED_HEADER_LEN {
+        return None;
+    }
+    match stored[2] {
+        FORMAT_VERSION if stored.len() >= HEADER_LEN => Some(HEADER_LEN),
+        FORMAT_VERSION_UNCHECKED => Some(UNCHECKED_HEADER_LEN),
+        _ => None,
+    }
+}
+
+/// Splits an envelope into its codec and payload. The checksum is not
+/// verified, see [`checksum_matches`].
+fn decode(stored: &[u8]) -> Result<(Codec, &[u8]), SledStorageError> {
+    let header_len = header_len(stored).ok_or_else(SledStorageError::malformed)?;
+    let codec = Codec::try_from(stored[3])?;
+    Ok((codec, &stored[header_len..]))
+}
+
+/// Returns `true` if the value is an envelope of the current format version
+/// whose checksum matches its contents.
+///
+/// Envelopes of format version 1 have no checksum and are only accepted by
+/// the schema migrations, so this returns `false` for them.
+pub fn checksum_matches(stored: &[u8]) -> bool {
+    match header_len(stored) {
+        Some(HEADER_LEN) => {
+            let (header, payload) = stored.split_at(HEADER_LEN);
+            header[UNCHECKED_HEADER_LEN..] == checksum(header, payload)
+        }
+        _ => false,
+    }
+}
+
+/// Adds a checksum to an envelope of format version 1.
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled.
+///
+/// # Returns
+///
+/// The envelope with a checksum, or `None` if the value is not an envelope
+/// of format version 1.
+pub fn add_checksum(stored: &[u8]) -> Option<Vec<u8>> {
+    if header_len(stored) != Some(UNCHECKED_HEADER_LEN) {
+        return None;
+    }
+    let (codec, payload) = decode(stored).ok()?;
+    let mut out = header(codec, payload.len());
+    out.extend_from_slice(payload);
+    Some(seal(out))
+}
+
+/// Returns `true` if the value is an encrypted envelope.
+pub fn is_encrypted(stored: &[u8]) -> bool {
+    matches!(decode(stored), Ok((Codec::Encrypted, _)))
+}
+
+/// Decodes a value written with [`encode_entity`].
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled.
+///
+/// # Returns
+///
+/// The serialized entity, borrowed from `stored`.
+pub fn decode_entity(stored: &[u8]) -> Result<&[u8], SledStorageError> {
+    match decode(stored)? {
+        (Codec::Entity, payload) => Ok(payload),
+        _ => Err(SledStorageError::malformed()),
+    }
+}
+
+/// Decodes a value written with [`encode_list`].
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled.
+///
+/// # Returns
+///
+/// The serialized entities in order, borrowed from `stored`.
+pub fn decode_list(stored: &[u8]) -> Result<Vec<&[u8]>, SledStorageError> {
+    let mut payload = match decode(stored)? {
+        (Codec::List, payload) => payload,
+        _ => return Err(SledStorageError::malformed()),
+    };
+
+    let mut items = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < 4 {
+            return Err(SledStorageError::malformed());
+        }
+        let (len, rest) = payload.split_at(4);
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if rest.len() < len {
+            return Err(SledStorageError::malformed());
+        }
+        let (item, rest) = rest.split_at(len);
+        items.push(item);
+        payload = rest;
+    }
+    Ok(items)
+}
+
+/// Decodes a value written with [`encode_encrypted`].
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled.
+///
+/// # Returns
+///
+/// The nonce followed by the ciphertext, borrowed from `stored`.
+pub fn decode_encrypted(stored: &[u8]) -> Result<&[u8], SledStorageError> {
+    match decode(stored)? {
+        (Codec::Encrypted, payload) => Ok(payload),
+        _ => Err(SledStorageError::malformed()),
+    }
+}
+
+/// Decodes a single entity written before the envelope format.
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled, a JSON array of byte values.
+///
+/// # Returns
+///
+/// The serialized entity.
+pub fn decode_legacy_entity(stored: &[u8]) -> Result<Vec<u8>, SledStorageError> {
+    helpers::from_json(stored)
+}
+
+/// Decodes a list of entities written before the envelope format.
+///
+/// # Arguments
+///
+/// * `stored` - The bytes read from sled, a JSON array of JSON arrays of byte values.
+///
+/// # Returns
+///
+/// The serialized entities in order.
+pub fn decode_legacy_list(stored: &[u8]) -> Result<Vec<Vec<u8>>, SledStorageError> {
+    helpers::from_json(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_entity_layout() {
+        let encoded = encode_entity(b"{\"a\":1}");
+        assert_eq!(&encoded[..4], &[b'O', b'S', FORMAT_VERSION, 0]);
+        assert_eq!(
+            &encoded[4..HEADER_LEN],
+            crc32fast::hash(&[b"OS\x02\x00".as_slice(), b"{\"a\":1}"].concat()).to_be_bytes()
+        );
+        assert_eq!(&encoded[HEADER_LEN..], b"{\"a\":1}");
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let encoded = encode_entity(b"{\"a\":1}");
+        assert!(checksum_matches(&encoded));
+        for i in [2, 3, 4, HEADER_LEN, encoded.len() - 1] {
+            let mut corrupted = encoded.clone();
+            corrupted[i] ^= 0x01;
+            assert!(!checksum_matches(&corrupted), "{i}");
+        }
+    }
+
+    #[test]
+    fn test_unchecked_envelopes() {
+        let unchecked = [b"OS\x01\x00".as_slice(), b"{\"a\":1}"].concat();
+        assert!(!checksum_matches(&unchecked));
+        assert_eq!(decode_entity(&unchecked).unwrap(), b"{\"a\":1}");
+
+        let checked = add_checksum(&unchecked).unwrap();
+        assert_eq!(checked, encode_entity(b"{\"a\":1}"));
+        assert_eq!(add_checksum(&checked), None);
+    }
+
+    #[test]
+    fn test_entity_round_trip() {
+        let value = vec![0u8, 1, 2, 255];
+        let encoded = encode_entity(&value);
+        assert_eq!(decode_entity(&encoded).unwrap(), value.as_slice());
+    }
+
+    #[test]
+    fn test_list_round_trip() {
+        let items = vec![b"first".to_vec(), Vec::new(), b"third".to_vec()];
+        let encoded = encode_list(&items);
+        let decoded = decode_list(&encoded).unwrap();
+        assert_eq!(decoded, items);
+    }
+
+    #[test]
+    fn test_empty_list() {
+        let encoded = encode_list::<Vec<u8>>(&[]);
+        assert_eq!(encoded.len(), HEADER_LEN);
+        assert!(decode_list(&encoded).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_legacy_values() {
+        let entity = b"{\"data\":\"x\"}".to_vec();
+        let legacy = serde_json::to_vec(&entity).unwrap();
+        assert!(!is_envelope(&legacy));
+        assert!(decode_entity(&legacy).is_err());
+        assert_eq!(decode_legacy_entity(&legacy).unwrap(), entity);
+
+        let legacy_list = serde_json::to_vec(&vec![entity.clone(), entity.clone()]).unwrap();
+        assert!(!is_envelope(&legacy_list));
+        assert!(decode_list(&legacy_list).is_err());
+        assert_eq!(
+            decode_legacy_list(&legacy_list).unwrap(),
+            vec![entity.clone(), entity]
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_values() {
+        // Wrong codec
+        assert!(decode_list(&encode_entity(b"x")).is_err());
+        assert!(decode_entity(&encode_list(&[b"x"])).is_err());
+        assert!(decode_entity(&encode_encrypted(b"x")).is_err());
+        assert!(decode_encrypted(&encode_entity(b"x")).is_err());
+        // Unknown format version
+        assert!(decode_entity(&[b'O', b'S', FORMAT_VERSION + 1, 0]).is_err());
+        // Truncated list item
+        let mut truncated = encode_list(&[b"abcdef"]);
+        truncated.pop();
+        assert!(decode_list(&truncated).is_err());
+    }
+}
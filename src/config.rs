@@ -0,0 +1,80 @@
+//! Options for opening a [`SledStorage`](crate::SledStorage).
+
+use std::fmt;
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use crate::StorageKey;
+
+/// How values are encrypted at rest.
+#[derive(Clone, Default)]
+pub enum Encryption {
+    /// Values are stored in plaintext.
+    #[default]
+    None,
+    /// Values are encrypted with the given key.
+    Key(StorageKey),
+    /// Values are encrypted with a key derived from the given passphrase, see
+    /// [`crate::passphrase`]. The passphrase is zeroed when dropped.
+    Passphrase(Zeroizing<Vec<u8>>),
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Key(storage_key) => f.debug_tuple("Key").field(storage_key).finish(),
+            Self::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+/// What to do about a proposal reference whose queued proposal is missing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DanglingProposalRefs {
+    /// Fail with `SledStorageError::NotFound`.
+    #[default]
+    Error,
+    /// Skip the reference and remove it from the proposal queue.
+    Repair,
+}
+
+/// Options for opening a [`SledStorage`](crate::SledStorage).
+#[derive(Debug, Clone, Default)]
+pub struct SledStorageConfig {
+    /// How values are encrypted at rest.
+    pub encryption: Encryption,
+    /// Store keyed hashes of lookup keys, such as group ids and public keys,
+    /// instead of the keys themselves, see [`crate::keys`].
+    ///
+    /// Requires encryption and can only be turned on for a new database. Once
+    /// turned on it stays on for the lifetime of the database.
+    pub hash_lookup_keys: bool,
+    /// Scrub deleted secrets, such as old epoch secrets and key pairs, from
+    /// disk, see [`crate::secure_delete`].
+    ///
+    /// A pending scrub is carried out when a database is opened from a path
+    /// with this option turned on.
+    pub secure_delete: bool,
+    /// How long key packages written through
+    /// [`StorageProvider::write_key_package`] stay valid, see
+    /// [`crate::key_packages`]. `None` means they never expire.
+    ///
+    /// [`StorageProvider::write_key_package`]: openmls_traits::storage::StorageProvider::write_key_package
+    pub key_package_lifetime: Option<Duration>,
+    /// What [`StorageProvider::queued_proposals`] does about a proposal
+    /// reference whose queued proposal is missing.
+    ///
+    /// [`StorageProvider::queued_proposals`]: openmls_traits::storage::StorageProvider::queued_proposals
+    pub dangling_proposal_refs: DanglingProposalRefs,
+    /// Open a database whose rekey was interrupted, so that it can be resumed
+    /// with [`SledStorage::rekey`] or [`SledStorage::change_passphrase`].
+    ///
+    /// Such a database is refused otherwise, since some of its values are
+    /// already encrypted with the new key.
+    ///
+    /// [`SledStorage::rekey`]: crate::SledStorage::rekey
+    /// [`SledStorage::change_passphrase`]: crate::SledStorage::change_passphrase
+    pub resume_rekey: bool,
+}
@@ -0,0 +1,254 @@
+//! Encryption at rest.
+//!
+//! A [`SledStorage`](crate::SledStorage) constructed with a [`StorageKey`]
+//! encrypts every value with XChaCha20-Poly1305 before it is written to sled.
+//! The encrypted value is itself wrapped in a [`Codec::Encrypted`] envelope
+//! whose payload is a random 24-byte nonce followed by the ciphertext of the
+//! plaintext envelope.
+//!
+//! The name of the tree and the key a value is stored under are bound to the
+//! ciphertext as associated data, so a value copied to another key or tree
+//! fails to decrypt instead of being read as the wrong entity.
+//!
+//! Tree names and keys themselves are not encrypted.
+//!
+//! [`Codec::Encrypted`]: crate::codec::Codec::Encrypted
+
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{codec, SledStorageError};
+
+/// Length of a storage key in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// Length of the nonce at the start of an encrypted payload.
+const NONCE_LEN: usize = 24;
+
+/// A symmetric key used to encrypt values at rest.
+///
+/// The key material is zeroed when the key is dropped.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct StorageKey([u8; KEY_LEN]);
+
+impl StorageKey {
+    /// Creates a storage key from raw key material.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - 32 bytes of uniformly random key material.
+    ///
+    /// # Returns
+    ///
+    /// The new `StorageKey`.
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generates a new random storage key.
+    ///
+    /// # Returns
+    ///
+    /// The new `StorageKey`.
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl PartialEq for StorageKey {
+    /// Compares the key material in constant time.
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl Eq for StorageKey {}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+/// Builds the associated data binding a value to its location.
+fn associated_data(tree: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(4 + tree.len() + key.len());
+    aad.extend_from_slice(&(tree.len() as u32).to_be_bytes());
+    aad.extend_from_slice(tree);
+    aad.extend_from_slice(key);
+    aad
+}
+
+/// Encrypts an encoded value.
+///
+/// # Arguments
+///
+/// * `storage_key` - The key to encrypt with.
+/// * `tree` - The name of the tree the value is stored in.
+/// * `key` - The key the value is stored under.
+/// * `value` - The encoded value.
+///
+/// # Returns
+///
+/// A Result containing the encrypted envelope to be stored or a SledStorageError.
+pub(crate) fn encrypt(
+    storage_key: &StorageKey,
+    tree: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Result<Vec<u8>, SledStorageError> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(tree, key);
+    let ciphertext = storage_key
+        .cipher()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: value,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| SledStorageError::encryption())?;
+
+    let mut payload = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&ciphertext);
+    Ok(codec::encode_encrypted(&payload))
+}
+
+/// Decrypts a value written with [`encrypt`].
+///
+/// # Arguments
+///
+/// * `storage_key` - The key to decrypt with.
+/// * `tree` - The name of the tree the value was read from.
+/// * `key` - The key the value was read from.
+/// * `stored` - The bytes read from sled.
+///
+/// # Returns
+///
+/// A Result containing the encoded value, wiped when dropped, or a
+/// SledStorageError.
+///
+/// # Errors
+///
+/// Returns `SledStorageError::DecryptionError` if the value is not encrypted,
+/// was encrypted with a different key, was moved from another tree or key, or
+/// was tampered with.
+pub(crate) fn decrypt(
+    storage_key: &StorageKey,
+    tree: &[u8],
+    key: &[u8],
+    stored: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, SledStorageError> {
+    let payload = codec::decode_encrypted(stored).map_err(|_| SledStorageError::decryption())?;
+    if payload.len() < NONCE_LEN {
+        return Err(SledStorageError::decryption());
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let aad = associated_data(tree, key);
+    storage_key
+        .cipher()
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| SledStorageError::decryption())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let storage_key = StorageKey::generate();
+        let value = codec::encode_entity(b"secret");
+
+        let stored = encrypt(&storage_key, b"tree", b"key", &value).unwrap();
+        assert!(codec::is_encrypted(&stored));
+        assert!(!stored.windows(6).any(|window| window == b"secret"));
+
+        let decrypted = decrypt(&storage_key, b"tree", b"key", &stored).unwrap();
+        assert_eq!(*decrypted, value);
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut storage_key = StorageKey::from_bytes([7; KEY_LEN]);
+        storage_key.zeroize();
+        assert_eq!(storage_key.0, [0; KEY_LEN]);
+    }
+
+    #[test]
+    fn test_nonces_are_random() {
+        let storage_key = StorageKey::generate();
+        let first = encrypt(&storage_key, b"tree", b"key", b"value").unwrap();
+        let second = encrypt(&storage_key, b"tree", b"key", b"value").unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_value_is_bound_to_tree_and_key() {
+        let storage_key = StorageKey::generate();
+        let stored = encrypt(&storage_key, b"tree", b"key", b"value").unwrap();
+
+        assert_eq!(
+            decrypt(&storage_key, b"other_tree", b"key", &stored),
+            Err(SledStorageError::decryption())
+        );
+        assert_eq!(
+            decrypt(&storage_key, b"tree", b"other_key", &stored),
+            Err(SledStorageError::decryption())
+        );
+        // The length prefix keeps the boundary between tree and key fixed.
+        assert_eq!(
+            decrypt(&storage_key, b"tre", b"ekey", &stored),
+            Err(SledStorageError::decryption())
+        );
+    }
+
+    #[test]
+    fn test_rejects_wrong_key_and_tampering() {
+        let storage_key = StorageKey::generate();
+        let mut stored = encrypt(&storage_key, b"tree", b"key", b"value").unwrap();
+
+        assert_eq!(
+            decrypt(&StorageKey::generate(), b"tree", b"key", &stored),
+            Err(SledStorageError::decryption())
+        );
+
+        let last = stored.len() - 1;
+        stored[last] ^= 1;
+        assert_eq!(
+            decrypt(&storage_key, b"tree", b"key", &stored),
+            Err(SledStorageError::decryption())
+        );
+
+        // Plaintext values are not accepted either.
+        assert_eq!(
+            decrypt(
+                &storage_key,
+                b"tree",
+                b"key",
+                &codec::encode_entity(b"value")
+            ),
+            Err(SledStorageError::decryption())
+        );
+    }
+}
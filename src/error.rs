@@ -0,0 +1,402 @@
+//! Errors of the key store.
+//!
+//! Every [`SledStorageError`] that concerns a single entry carries an
+//! [`ErrorContext`]: the storage operation that failed, the name of the tree
+//! and a fingerprint of the key. Keys are never included verbatim, since they
+//! can contain group ids and other identifiers that do not belong in logs.
+//! The underlying error, if any, is available through
+//! [`std::error::Error::source`].
+
+use std::fmt;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use sled::transaction::TransactionError;
+
+/// Errors thrown by the key store.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SledStorageError {
+    #[error("Sled error{context}")]
+    SledError {
+        context: ErrorContext,
+        source: sled::Error,
+    },
+    #[error("Value could not be encoded{context}")]
+    EncodeError {
+        context: ErrorContext,
+        source: JsonError,
+    },
+    /// A stored value could not be decoded. `source` is set if the value is
+    /// not valid JSON for the requested type, and unset if its envelope is
+    /// malformed.
+    #[error("Value could not be decoded{context}")]
+    DecodeError {
+        context: ErrorContext,
+        source: Option<JsonError>,
+    },
+    #[error("Value does not exist{context}")]
+    NotFound { context: ErrorContext },
+    #[error("Database schema version {found} is newer than the supported version {supported}")]
+    UnsupportedSchemaVersion { found: u32, supported: u32 },
+    #[error("Encryption error{context}")]
+    EncryptionError { context: ErrorContext },
+    #[error("Value could not be decrypted with the configured storage key{context}")]
+    DecryptionError { context: ErrorContext },
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    #[error("An interrupted rekey has to be completed first")]
+    RekeyInProgress,
+    #[error("The storage key is derived from a passphrase")]
+    PassphraseProtected,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Corrupted value{context}")]
+    Corrupted { context: ErrorContext },
+}
+
+impl SledStorageError {
+    /// Creates an `EncodeError` without context.
+    pub(crate) fn encode(source: serde_json::Error) -> Self {
+        Self::EncodeError {
+            context: ErrorContext::default(),
+            source: JsonError::from(source),
+        }
+    }
+
+    /// Creates a `DecodeError` without context.
+    pub(crate) fn decode(source: serde_json::Error) -> Self {
+        Self::DecodeError {
+            context: ErrorContext::default(),
+            source: Some(JsonError::from(source)),
+        }
+    }
+
+    /// Creates a `DecodeError` for a malformed envelope, without context.
+    pub(crate) fn malformed() -> Self {
+        Self::DecodeError {
+            context: ErrorContext::default(),
+            source: None,
+        }
+    }
+
+    /// Creates a `NotFound` error without context.
+    pub(crate) fn not_found() -> Self {
+        Self::NotFound {
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Creates an `EncryptionError` without context.
+    pub(crate) fn encryption() -> Self {
+        Self::EncryptionError {
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Creates a `DecryptionError` without context.
+    pub(crate) fn decryption() -> Self {
+        Self::DecryptionError {
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Creates a `Corrupted` error without context.
+    pub(crate) fn corrupted() -> Self {
+        Self::Corrupted {
+            context: ErrorContext::default(),
+        }
+    }
+
+    /// Returns the context of the error, if the error concerns stored data.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Self::SledError { context, .. }
+            | Self::EncodeError { context, .. }
+            | Self::DecodeError { context, .. }
+            | Self::NotFound { context }
+            | Self::EncryptionError { context }
+            | Self::DecryptionError { context }
+            | Self::Corrupted { context } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn context_mut(&mut self) -> Option<&mut ErrorContext> {
+        match self {
+            Self::SledError { context, .. }
+            | Self::EncodeError { context, .. }
+            | Self::DecodeError { context, .. }
+            | Self::NotFound { context }
+            | Self::EncryptionError { context }
+            | Self::DecryptionError { context }
+            | Self::Corrupted { context } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Records the entry the error concerns, unless an inner call already
+    /// recorded one.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The name of the tree.
+    /// * `key` - The key of the entry. Only its fingerprint is kept.
+    pub(crate) fn in_entry(mut self, tree: &[u8], key: &[u8]) -> Self {
+        if let Some(context) = self.context_mut() {
+            if context.tree.is_none() {
+                context.tree = Some(String::from_utf8_lossy(tree).into_owned());
+                context.key = Some(KeyFingerprint::of(key));
+            }
+        }
+        self
+    }
+
+    /// Records the operation that failed, unless an inner call already
+    /// recorded one.
+    pub(crate) fn during(mut self, operation: Operation) -> Self {
+        if let Some(context) = self.context_mut() {
+            context.operation.get_or_insert(operation);
+        }
+        self
+    }
+}
+
+/// Runs `f`, recording the operation and the entry in any error it returns.
+///
+/// # Arguments
+///
+/// * `operation` - The storage operation `f` performs.
+/// * `tree` - The name of the tree.
+/// * `key` - The key of the entry.
+/// * `f` - The body of the operation.
+///
+/// # Returns
+///
+/// The Result returned by `f`.
+pub(crate) fn in_context<A>(
+    operation: Operation,
+    tree: &[u8],
+    key: &[u8],
+    f: impl FnOnce() -> Result<A, SledStorageError>,
+) -> Result<A, SledStorageError> {
+    f().map_err(|error| error.in_entry(tree, key).during(operation))
+}
+
+impl From<sled::Error> for SledStorageError {
+    fn from(source: sled::Error) -> Self {
+        Self::SledError {
+            context: ErrorContext::default(),
+            source,
+        }
+    }
+}
+
+impl From<TransactionError<SledStorageError>> for SledStorageError {
+    fn from(error: TransactionError<SledStorageError>) -> Self {
+        match error {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+/// Where an error happened.
+///
+/// Every field is optional, since not every error concerns a single entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The storage operation that failed.
+    pub operation: Option<Operation>,
+    /// The name of the tree.
+    pub tree: Option<String>,
+    /// The fingerprint of the key.
+    pub key: Option<KeyFingerprint>,
+}
+
+impl fmt::Display for ErrorContext {
+    /// Formats the context as a suffix of an error message, e.g.
+    /// ` (read in tree GroupState at key 0123456789abcdef)`. An empty context
+    /// formats as nothing.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operation.is_none() && self.tree.is_none() {
+            return Ok(());
+        }
+        write!(f, " (")?;
+        if let Some(operation) = self.operation {
+            write!(f, "{operation}")?;
+            if self.tree.is_some() {
+                write!(f, " ")?;
+            }
+        }
+        if let Some(tree) = &self.tree {
+            write!(f, "in tree {tree}")?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " at key {key}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The storage operations an error can happen in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation {
+    Read,
+    ReadList,
+    Write,
+    WriteList,
+    Append,
+    RemoveItem,
+    Delete,
+    DeleteList,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::ReadList => "read list",
+            Self::Write => "write",
+            Self::WriteList => "write list",
+            Self::Append => "append",
+            Self::RemoveItem => "remove item",
+            Self::Delete => "delete",
+            Self::DeleteList => "delete list",
+        })
+    }
+}
+
+/// A redacted stand-in for a key: the first 8 bytes of its SHA-256 hash.
+///
+/// The fingerprint is enough to tell keys apart in logs and to match an error
+/// against a key that is known, but does not reveal the key itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyFingerprint([u8; 8]);
+
+impl KeyFingerprint {
+    /// Computes the fingerprint of a key.
+    pub fn of(key: &[u8]) -> Self {
+        let hash = Sha256::digest(key);
+        let mut fingerprint = [0; 8];
+        fingerprint.copy_from_slice(&hash[..8]);
+        Self(fingerprint)
+    }
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// A JSON error, shared so that [`SledStorageError`] can stay `Clone`.
+///
+/// Two `JsonError`s are equal if they have the same message.
+#[derive(Debug, Clone)]
+pub struct JsonError(Arc<serde_json::Error>);
+
+impl JsonError {
+    /// Returns the underlying `serde_json` error.
+    pub fn inner(&self) -> &serde_json::Error {
+        &self.0
+    }
+}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(error: serde_json::Error) -> Self {
+        Self(Arc::new(error))
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl PartialEq for JsonError {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for JsonError {}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn test_source_is_chained() {
+        let json = serde_json::from_slice::<u32>(b"nope").unwrap_err();
+        let message = json.to_string();
+        let error = SledStorageError::decode(json);
+
+        let source = error.source().unwrap();
+        assert_eq!(source.to_string(), message);
+        assert!(source.downcast_ref::<JsonError>().is_some());
+
+        let error = SledStorageError::from(sled::Error::Unsupported("test".into()));
+        assert!(error
+            .source()
+            .unwrap()
+            .downcast_ref::<sled::Error>()
+            .is_some());
+        assert!(SledStorageError::malformed().source().is_none());
+    }
+
+    #[test]
+    fn test_context() {
+        let key = b"secret group id";
+        let error = SledStorageError::corrupted()
+            .in_entry(b"GroupState", key)
+            .during(Operation::Read)
+            // Outer calls don't overwrite the entry recorded first.
+            .in_entry(b"Other", b"other key")
+            .during(Operation::Delete);
+
+        let context = error.context().unwrap();
+        assert_eq!(context.operation, Some(Operation::Read));
+        assert_eq!(context.tree.as_deref(), Some("GroupState"));
+        assert_eq!(context.key, Some(KeyFingerprint::of(key)));
+
+        let message = error.to_string();
+        assert_eq!(
+            message,
+            format!(
+                "Corrupted value (read in tree GroupState at key {})",
+                KeyFingerprint::of(key)
+            )
+        );
+        assert!(!message.contains(&hex::encode(key)));
+        assert!(!message.contains("secret"));
+
+        assert_eq!(
+            SledStorageError::not_found().to_string(),
+            "Value does not exist"
+        );
+        assert!(SledStorageError::WrongPassphrase.context().is_none());
+    }
+
+    #[test]
+    fn test_equality() {
+        let decode = |bytes: &[u8]| {
+            SledStorageError::decode(serde_json::from_slice::<u32>(bytes).unwrap_err())
+        };
+        assert_eq!(decode(b"nope"), decode(b"nope"));
+        assert_ne!(decode(b"nope"), decode(b"\"nope\""));
+        assert_ne!(
+            SledStorageError::corrupted().in_entry(b"tree", b"a"),
+            SledStorageError::corrupted().in_entry(b"tree", b"b")
+        );
+    }
+}
@@ -0,0 +1,712 @@
+//! Garbage collection of state OpenMLS failed to delete.
+//!
+//! OpenMLS deletes the encryption key pairs of an epoch once they are no longer
+//! needed. If that delete never happens, for example because the process
+//! crashed in between, nothing looks the key pairs up again and they stay
+//! stored forever, defeating forward secrecy.
+//!
+//! The epochs are recovered from the group state: the current epoch is the
+//! `epoch` field of the stored group context. This depends on OpenMLS
+//! serializing its `GroupContext` as a JSON object with a numeric `epoch`
+//! field. A group whose context cannot be read that way is skipped and none
+//! of its key pairs are deleted. Epoch keys and leaf indices are assumed to be
+//! serialized as plain numbers, as OpenMLS does.
+//!
+//! With plain lookup keys the epoch of every key pair list is read from its
+//! key, so stale key pairs of any leaf are found. Hashed lookup keys cannot be
+//! read back, so then only the past epochs of the own leaf of a group are
+//! looked up. The epoch below which a group has been collected is stored, so
+//! each run only looks up the epochs that left the window since the last one.
+//!
+//! More generally, [`SledStorage::orphans`] finds entries nothing leads to
+//! anymore. The live groups are the ones with a group state or a group
+//! context, see [`SledStorage::group_ids`], and an entry is an orphan if:
+//!
+//! * it is group state of a group that is not live,
+//! * it is a proposal reference whose proposal is missing, or a queued
+//!   proposal no reference points at,
+//! * it records the creation time of a key package that is missing, or
+//! * it is an encryption or signature key pair whose public key appears
+//!   neither in the state of a live group nor in a stored key package.
+//!
+//! The public key of a key pair is the one array of bytes in the JSON encoding
+//! of its lookup key, so the check works no matter how OpenMLS wraps the key
+//! bytes. A key pair is referenced if an array with exactly these bytes occurs
+//! anywhere in the JSON of a value. This needs the plain lookup keys, so key
+//! pairs are never reported when lookup keys are hashed. PSKs and key packages
+//! are looked up by OpenMLS directly and are never orphans.
+//!
+//! A signature key pair is usually created before any group or key package
+//! uses it, such as the identity key of an account, so
+//! [`SledStorage::cleanup_orphans`] only deletes signature key pairs when
+//! asked to explicitly.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use openmls_traits::storage::CURRENT_VERSION;
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::Serialize;
+use sled::IVec;
+use zeroize::Zeroizing;
+
+use crate::helpers;
+use crate::traits::{
+    ENCRYPTION_KEY_PAIR_TREE, EPOCH_KEY_PAIRS_COLLECTED_TREE, EPOCH_KEY_PAIRS_TREE,
+    GROUP_ENTITY_TREES, GROUP_LIST_TREES, KEY_PACKAGE_INFO_TREE, KEY_PACKAGE_TREE,
+    PROPOSAL_QUEUE_REFS_TREE, QUEUED_PROPOSAL_TREE, SECRET_TREES, SIGNATURE_KEY_PAIR_TREE,
+};
+use crate::{Issue, IssueKind, KeyFingerprint, SledStorage, SledStorageError};
+
+/// Lookup keys of groups and the epoch below which their epoch key pairs are
+/// collected.
+type CollectedEpochs = Vec<(Vec<u8>, u64)>;
+
+/// An entry nothing leads to anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    /// The name of the tree holding the entry.
+    pub tree: String,
+    /// The key of the entry.
+    pub key: Vec<u8>,
+    /// Why the entry is an orphan.
+    pub reason: OrphanReason,
+}
+
+/// Why an entry is an orphan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanReason {
+    /// The entry belongs to a group without a group state or group context.
+    GroupMissing,
+    /// The proposal the reference points at is missing.
+    ProposalMissing,
+    /// No proposal reference points at the queued proposal.
+    ProposalUnreferenced,
+    /// The key package the entry describes is missing.
+    KeyPackageMissing,
+    /// The public key of the key pair is not used by any live group or key
+    /// package.
+    PublicKeyUnreferenced,
+}
+
+impl Orphan {
+    fn new(tree: &[u8], key: &[u8], reason: OrphanReason) -> Self {
+        Self {
+            tree: String::from_utf8_lossy(tree).into_owned(),
+            key: key.to_vec(),
+            reason,
+        }
+    }
+}
+
+/// The outcome of [`SledStorage::orphans`] and
+/// [`SledStorage::cleanup_orphans`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OrphanReport {
+    /// The orphans, grouped by tree.
+    pub orphans: Vec<Orphan>,
+    /// Values that could not be checked, in the order they were found.
+    pub issues: Vec<Issue>,
+}
+
+/// What [`SledStorage::cleanup_orphans`] deletes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CleanupOptions {
+    /// If `true`, the orphans are only reported and nothing is deleted.
+    pub dry_run: bool,
+    /// If `true`, unreferenced signature key pairs are deleted as well.
+    ///
+    /// Off by default, since a signature key pair that is not used by any
+    /// group or key package yet may still be needed, such as the identity key
+    /// of an account.
+    pub delete_signature_key_pairs: bool,
+}
+
+impl SledStorage {
+    /// Deletes the encryption key pairs of epochs that are outside the
+    /// retention window, in a single transaction.
+    ///
+    /// For every group with a group context, the key pairs of the `retention`
+    /// epochs before the current one, the current epoch and the next epoch are
+    /// kept, for every leaf. The key pairs of other epochs are deleted, see the
+    /// [module documentation](self) for which are found. Groups without a
+    /// group context, or whose context cannot be read, are left alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - The number of past epochs to keep key pairs for.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of deleted key pair lists, one per
+    /// epoch and leaf, or a `SledStorageError`. On error nothing has been
+    /// deleted.
+    pub fn collect_stale_epoch_key_pairs(&self, retention: u64) -> Result<usize, SledStorageError> {
+        tracing::debug!(target: "openmls_sled_storage::gc", "Collecting stale epoch key pairs");
+
+        self.atomically(|storage| {
+            let (stale, collected) = storage.stale_epoch_key_pairs(retention)?;
+            for key in &stale {
+                storage.delete_list::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE, key)?;
+            }
+            for (group_key, epoch) in &collected {
+                storage.write::<CURRENT_VERSION>(
+                    EPOCH_KEY_PAIRS_COLLECTED_TREE,
+                    group_key,
+                    helpers::to_json(epoch)?,
+                )?;
+            }
+            tracing::debug!(target: "openmls_sled_storage::gc", "Deleted {} stale epoch key pair lists", stale.len());
+            Ok(stale.len())
+        })
+    }
+
+    /// Finds the keys of the epoch key pair lists that are outside the
+    /// retention window.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - The number of past epochs to keep key pairs for.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the keys of the stale lists and, with hashed
+    /// lookup keys, the lookup keys of the groups whose epochs below the
+    /// given one are now collected, or a `SledStorageError`.
+    fn stale_epoch_key_pairs(
+        &self,
+        retention: u64,
+    ) -> Result<(Vec<Vec<u8>>, CollectedEpochs), SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE)?;
+        let list_keys = self
+            .scan_keys(&active_tree, &[])?
+            .iter()
+            .filter_map(|item_key| helpers::split_list_item_key(item_key))
+            .map(|(key, _)| key.to_vec())
+            .collect::<BTreeSet<_>>();
+
+        let mut stale = Vec::new();
+        let mut collected = Vec::new();
+        for group_key in self.group_ids()? {
+            let epoch = match self.current_epoch(&group_key) {
+                Ok(Some(epoch)) => epoch,
+                Ok(None) => continue,
+                Err(error) => {
+                    tracing::warn!(target: "openmls_sled_storage::gc", "Skipping group {}, its context cannot be read: {}", KeyFingerprint::of(&group_key), error);
+                    continue;
+                }
+            };
+            let window = epoch.saturating_sub(retention)..=epoch.saturating_add(1);
+
+            if self.hashes_lookup_keys() {
+                let Ok(Some(leaf_index)) = self.stored_own_leaf_index(&group_key) else {
+                    continue;
+                };
+                let from = self.collected_epochs(&group_key)?;
+                if from >= *window.start() {
+                    continue;
+                }
+                for past in from..*window.start() {
+                    let key = self.epoch_key_pairs_key_in(&group_key, &past, leaf_index)?;
+                    if list_keys.contains(&key) {
+                        stale.push(key);
+                    }
+                }
+                collected.push((group_key, *window.start()));
+                continue;
+            }
+
+            // The lookup key of the group is self-delimiting, so the lists
+            // starting with it are exactly the ones of the group.
+            stale.extend(
+                list_keys
+                    .range(group_key.clone()..)
+                    .take_while(|key| key.starts_with(&group_key))
+                    .filter(|key| {
+                        let epochs = plain_epochs(&key[group_key.len()..]);
+                        !epochs.is_empty() && !epochs.iter().any(|epoch| window.contains(epoch))
+                    })
+                    .cloned(),
+            );
+        }
+        Ok((stale, collected))
+    }
+
+    /// Reads the epoch below which the epoch key pairs of a group have been
+    /// collected with hashed lookup keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the epoch, or `0` if the group has not been
+    /// collected yet, or a `SledStorageError`.
+    fn collected_epochs(&self, group_key: &[u8]) -> Result<u64, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_COLLECTED_TREE)?;
+        match self.get(&active_tree, group_key)? {
+            Some(stored) => {
+                let entity =
+                    self.decode_value(EPOCH_KEY_PAIRS_COLLECTED_TREE, group_key, &stored)?;
+                helpers::from_json(&entity)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Finds all orphaned entries, see the [module documentation](self).
+    ///
+    /// Values that have to be decoded to match public keys, but cannot be, are
+    /// reported as issues and the scan continues. Key pairs are only reported
+    /// if no such issue was found, as the value that could not be checked may
+    /// use their public key.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the orphans, grouped by tree, and the issues, or
+    /// a `SledStorageError`.
+    pub fn orphans(&self) -> Result<OrphanReport, SledStorageError> {
+        let live = self.group_ids()?.into_iter().collect::<BTreeSet<_>>();
+        let mut report = OrphanReport::default();
+        // The public keys of key pairs not yet found in a live group or key
+        // package. Values are checked as they are streamed, so only these
+        // stay in memory.
+        let mut unreferenced = self.key_pair_public_keys()?;
+
+        for tree in GROUP_ENTITY_TREES.into_iter().chain(GROUP_LIST_TREES) {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            self.for_each_prefixed(&active_tree, &[], |key, value| {
+                let group_key = if GROUP_LIST_TREES.contains(&tree) {
+                    helpers::split_list_item_key(key).map(|(group_key, _)| group_key)
+                } else {
+                    Some(key)
+                };
+                if !group_key.is_some_and(|group_key| live.contains(group_key)) {
+                    report
+                        .orphans
+                        .push(Orphan::new(tree, key, OrphanReason::GroupMissing));
+                } else if !SECRET_TREES.contains(&tree) {
+                    self.find_public_keys(tree, key, value, &mut unreferenced, &mut report.issues);
+                }
+                Ok(())
+            })?;
+        }
+
+        let active_tree = self.open_tree::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE)?;
+        for item_key in self.scan_keys(&active_tree, &[])? {
+            let list_key = helpers::split_list_item_key(&item_key).map(|(key, _)| key);
+            if !list_key.is_some_and(|key| live.iter().any(|group_key| key.starts_with(group_key)))
+            {
+                report.orphans.push(Orphan::new(
+                    EPOCH_KEY_PAIRS_TREE,
+                    &item_key,
+                    OrphanReason::GroupMissing,
+                ));
+            }
+        }
+
+        // Proposal references of groups that are not live were reported
+        // above.
+        let (missing, unreferenced_proposals) =
+            self.proposal_queue_mismatches(|group_key| live.contains(group_key))?;
+        for item_key in missing {
+            report.orphans.push(Orphan::new(
+                PROPOSAL_QUEUE_REFS_TREE,
+                &item_key,
+                OrphanReason::ProposalMissing,
+            ));
+        }
+        for key in unreferenced_proposals {
+            let reason = if live
+                .iter()
+                .any(|group_key| key.starts_with(&self.proposal_prefix(group_key)))
+            {
+                OrphanReason::ProposalUnreferenced
+            } else {
+                OrphanReason::GroupMissing
+            };
+            report
+                .orphans
+                .push(Orphan::new(QUEUED_PROPOSAL_TREE, &key, reason));
+        }
+
+        let key_packages_tree = self.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_TREE)?;
+        let info_tree = self.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_INFO_TREE)?;
+        self.for_each_prefixed(&key_packages_tree, &[], |key, value| {
+            self.find_public_keys(
+                KEY_PACKAGE_TREE,
+                key,
+                value,
+                &mut unreferenced,
+                &mut report.issues,
+            );
+            Ok(())
+        })?;
+        for key in self.scan_keys(&info_tree, &[])? {
+            if self.get(&key_packages_tree, &key)?.is_none() {
+                report.orphans.push(Orphan::new(
+                    KEY_PACKAGE_INFO_TREE,
+                    &key,
+                    OrphanReason::KeyPackageMissing,
+                ));
+            }
+        }
+
+        if report.issues.is_empty() && !unreferenced.is_empty() {
+            for tree in [ENCRYPTION_KEY_PAIR_TREE, SIGNATURE_KEY_PAIR_TREE] {
+                let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+                for key in self.scan_keys(&active_tree, &[])? {
+                    if public_key(&key).is_some_and(|public_key| unreferenced.contains(&public_key))
+                    {
+                        report.orphans.push(Orphan::new(
+                            tree,
+                            &key,
+                            OrphanReason::PublicKeyUnreferenced,
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Collects the public keys of all encryption and signature key pairs.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the public keys or a `SledStorageError`. The set
+    /// is empty if lookup keys are hashed, as the public keys cannot be
+    /// recovered then.
+    fn key_pair_public_keys(&self) -> Result<BTreeSet<Vec<u8>>, SledStorageError> {
+        let mut public_keys = BTreeSet::new();
+        if self.hashes_lookup_keys() {
+            return Ok(public_keys);
+        }
+        for tree in [ENCRYPTION_KEY_PAIR_TREE, SIGNATURE_KEY_PAIR_TREE] {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            for key in active_tree.iter().keys() {
+                public_keys.extend(public_key(&key?));
+            }
+            if let Some(pending) = self.pending() {
+                for (key, value) in pending.writes_with_prefix(&active_tree.name(), &[]) {
+                    if value.is_some() {
+                        public_keys.extend(public_key(&key));
+                    }
+                }
+            }
+        }
+        Ok(public_keys)
+    }
+
+    /// Removes the public keys found in a stored value from `unreferenced`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The name of the tree holding the value.
+    /// * `key` - The key of the value.
+    /// * `stored` - The stored value.
+    /// * `unreferenced` - The public keys not found so far.
+    /// * `issues` - Receives an issue if the value cannot be checked.
+    fn find_public_keys(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+        stored: &[u8],
+        unreferenced: &mut BTreeSet<Vec<u8>>,
+        issues: &mut Vec<Issue>,
+    ) {
+        if unreferenced.is_empty() {
+            return;
+        }
+        let issue = |kind| Issue {
+            tree: String::from_utf8_lossy(tree).into_owned(),
+            key: key.to_vec(),
+            kind,
+        };
+        match self.decode_value(tree, key, stored) {
+            Ok(value) => {
+                let found = for_each_byte_array(&value, |bytes| {
+                    unreferenced.remove(bytes);
+                });
+                if found.is_err() {
+                    issues.push(issue(IssueKind::InvalidJson));
+                }
+            }
+            Err(error) => issues.push(issue(IssueKind::Undecodable(error))),
+        }
+    }
+
+    /// Matches the proposal references of groups against the queued
+    /// proposals.
+    ///
+    /// References that cannot be decoded are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `include` - Tells for the lookup key of a group whether its
+    ///   references are matched.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the keys of the references whose proposal is
+    /// missing and the keys of the proposals no matched reference points at,
+    /// or a `SledStorageError`.
+    pub(crate) fn proposal_queue_mismatches(
+        &self,
+        include: impl Fn(&[u8]) -> bool,
+    ) -> Result<(Vec<IVec>, Vec<IVec>), SledStorageError> {
+        let refs_tree = self.open_tree::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE)?;
+        let proposals_tree = self.open_tree::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE)?;
+
+        let mut missing = Vec::new();
+        let mut referenced = BTreeSet::new();
+        for (item_key, value) in self.scan_prefix(&refs_tree, &[])? {
+            let Some((group_key, _)) = helpers::split_list_item_key(&item_key) else {
+                continue;
+            };
+            if !include(group_key) {
+                continue;
+            }
+            let Ok(proposal_ref) = self.decode_value(PROPOSAL_QUEUE_REFS_TREE, &item_key, &value)
+            else {
+                continue;
+            };
+            let proposal_key = self.proposal_key_in(group_key, &proposal_ref)?;
+            if self.get(&proposals_tree, &proposal_key)?.is_some() {
+                referenced.insert(proposal_key);
+            } else {
+                missing.push(item_key);
+            }
+        }
+
+        let mut unreferenced = self.scan_keys(&proposals_tree, &[])?;
+        unreferenced.retain(|key| !referenced.contains(&key[..]));
+        Ok((missing, unreferenced))
+    }
+
+    /// Deletes orphaned entries, see the [module documentation](self), in a
+    /// single transaction.
+    ///
+    /// Signature key pairs are only deleted if
+    /// [`CleanupOptions::delete_signature_key_pairs`] is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - What to delete.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the orphans that were, or would have been,
+    /// deleted and the issues found, or a `SledStorageError`. On error nothing
+    /// has been deleted.
+    pub fn cleanup_orphans(
+        &self,
+        options: CleanupOptions,
+    ) -> Result<OrphanReport, SledStorageError> {
+        tracing::debug!(target: "openmls_sled_storage::gc", "Cleaning up orphans, dry run: {}", options.dry_run);
+
+        self.atomically(|storage| {
+            let mut report = storage.orphans()?;
+            report.orphans.retain(|orphan| {
+                options.delete_signature_key_pairs
+                    || orphan.tree.as_bytes() != SIGNATURE_KEY_PAIR_TREE
+            });
+            if !options.dry_run {
+                for orphan in &report.orphans {
+                    storage.delete::<CURRENT_VERSION>(orphan.tree.as_bytes(), &orphan.key)?;
+                }
+            }
+            Ok(report)
+        })
+    }
+}
+
+/// Reads the epoch from the end of a plain epoch key pairs key.
+///
+/// The end is the JSON of the epoch directly followed by the JSON of the leaf
+/// index, so it may split into an epoch and a leaf index in more than one way.
+///
+/// # Arguments
+///
+/// * `epoch_and_leaf` - The key without the lookup key of the group.
+///
+/// # Returns
+///
+/// Every epoch the key can stand for. Empty if it is not an epoch and a leaf
+/// index serialized as plain numbers.
+fn plain_epochs(epoch_and_leaf: &[u8]) -> Vec<u64> {
+    (1..epoch_and_leaf.len())
+        .filter_map(|at| {
+            let (epoch, leaf_index) = epoch_and_leaf.split_at(at);
+            canonical_json::<u32>(leaf_index)?;
+            canonical_json::<u64>(epoch)
+        })
+        .collect()
+}
+
+/// Parses JSON that is exactly the encoding of the value it holds.
+fn canonical_json<T: Serialize + DeserializeOwned>(json: &[u8]) -> Option<T> {
+    let value = serde_json::from_slice(json).ok()?;
+    (serde_json::to_vec(&value).ok()? == json).then_some(value)
+}
+
+/// Finds the bytes of a public key in the JSON encoding of its lookup key.
+///
+/// # Arguments
+///
+/// * `key` - The lookup key.
+///
+/// # Returns
+///
+/// The one array of bytes in the key, or `None` if the key holds no or
+/// several different arrays of bytes, as then the public key is not known.
+fn public_key(key: &[u8]) -> Option<Vec<u8>> {
+    let mut arrays = BTreeSet::new();
+    for_each_byte_array(key, |bytes| {
+        arrays.insert(bytes.to_vec());
+    })
+    .ok()?;
+    let mut arrays = arrays.into_iter();
+    match (arrays.next(), arrays.next()) {
+        (Some(public_key), None) => Some(public_key),
+        _ => None,
+    }
+}
+
+/// Calls `f` with every non-empty array of bytes in a JSON document.
+///
+/// The document is walked as it is parsed, without building it up in memory.
+///
+/// # Arguments
+///
+/// * `json` - The JSON document.
+/// * `f` - Called with the items of each array whose items are all numbers
+///   from 0 to 255.
+///
+/// # Returns
+///
+/// A `Result` indicating success or the error if `json` is not valid JSON.
+fn for_each_byte_array(json: &[u8], mut f: impl FnMut(&[u8])) -> Result<(), serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    ByteArrays { found: &mut f }.deserialize(&mut deserializer)?;
+    deserializer.end()
+}
+
+/// Walks a JSON value and reports its arrays of bytes, see
+/// [`for_each_byte_array`]. Deserializes to the value itself if it is a byte.
+struct ByteArrays<'a> {
+    found: &'a mut dyn FnMut(&[u8]),
+}
+
+impl<'de> DeserializeSeed<'de> for ByteArrays<'_> {
+    type Value = Option<u8>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<u8>, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ByteArrays<'_> {
+    type Value = Option<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Option<u8>, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Option<u8>, E> {
+        Ok(u8::try_from(value).ok())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Option<u8>, E> {
+        Ok(u8::try_from(value).ok())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Option<u8>, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Option<u8>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Option<u8>, E> {
+        Ok(None)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Option<u8>, A::Error> {
+        // Set to `None` as soon as an item is not a byte.
+        let mut bytes = Some(Zeroizing::new(Vec::new()));
+        while let Some(item) = seq.next_element_seed(ByteArrays {
+            found: &mut *self.found,
+        })? {
+            match (item, &mut bytes) {
+                (Some(byte), Some(bytes)) => bytes.push(byte),
+                _ => bytes = None,
+            }
+        }
+        if let Some(bytes) = bytes.filter(|bytes| !bytes.is_empty()) {
+            (self.found)(&bytes);
+        }
+        Ok(None)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Option<u8>, A::Error> {
+        while map.next_key::<IgnoredAny>()?.is_some() {
+            map.next_value_seed(ByteArrays {
+                found: &mut *self.found,
+            })?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_epochs() {
+        assert_eq!(plain_epochs(b"73"), [7]);
+        // Either epoch 1 of leaf 23 or epoch 12 of leaf 3.
+        assert_eq!(plain_epochs(b"123"), [1, 12]);
+        // Leading zeros are not valid JSON.
+        assert_eq!(plain_epochs(b"103"), [10]);
+        assert!(plain_epochs(br#"{"epoch":1}3"#).is_empty());
+        assert!(plain_epochs(b"7").is_empty());
+    }
+
+    #[test]
+    fn test_public_key() {
+        assert_eq!(
+            public_key(br#"{"value":{"vec":[1,2,3]},"scheme":"x"}"#),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(public_key(b"[1,2,3]"), Some(vec![1, 2, 3]));
+        // Not a single array of bytes.
+        assert_eq!(public_key(br#"{"a":[1,2],"b":[3]}"#), None);
+        assert_eq!(public_key(b"[1,256]"), None);
+        assert_eq!(public_key(b"[]"), None);
+        assert_eq!(public_key(br#""abc""#), None);
+        assert_eq!(public_key(b"not json"), None);
+    }
+
+    #[test]
+    fn test_for_each_byte_array() {
+        let mut arrays = Vec::new();
+        for_each_byte_array(
+            br#"{"tree":[{"key":[1,2]},null,[3,[4]]],"n":-1,"s":"[5]","f":1.5}"#,
+            |bytes| arrays.push(bytes.to_vec()),
+        )
+        .unwrap();
+        assert_eq!(arrays, [vec![1, 2], vec![4]]);
+        assert!(for_each_byte_array(b"[1,2", |_| {}).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Operations on all state stored for a group.
+//!
+//! Groups are identified by their lookup key, the key their state is stored
+//! under: the JSON encoding of the group id, or its keyed hash if lookup keys
+//! are hashed (see [`crate::keys`]).
+
+use std::collections::BTreeSet;
+
+use openmls_traits::storage::{traits, CURRENT_VERSION};
+use serde::de::DeserializeOwned;
+use sled::IVec;
+
+use crate::helpers;
+use crate::traits::{
+    EPOCH_KEY_PAIRS_TREE, GROUP_CONTEXT_TREE, GROUP_ENTITY_TREES, GROUP_LIST_TREES,
+    GROUP_STATE_TREE, OWN_LEAF_NODE_INDEX_TREE, QUEUED_PROPOSAL_TREE,
+};
+use crate::{SledStorage, SledStorageError};
+
+/// Keys and raw values of entries in a tree.
+type Entries = Vec<(IVec, IVec)>;
+
+/// What is stored for a group, for diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupSummary {
+    /// The trees holding entries of the group. Trees without entries are left
+    /// out.
+    pub trees: Vec<TreeSummary>,
+    /// The own leaf index in the group, if one is stored.
+    pub own_leaf_index: Option<u32>,
+}
+
+/// The entries of a group in a single tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeSummary {
+    /// The name of the tree.
+    pub name: String,
+    /// The number of entries, counting every list item separately.
+    pub entries: usize,
+    /// The total size of the stored entries in bytes.
+    pub bytes: usize,
+}
+
+impl SledStorage {
+    /// Lists the lookup keys of all groups that have a group state or a
+    /// group context stored.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the lookup keys, in ascending order, or a
+    /// `SledStorageError`.
+    pub fn group_ids(&self) -> Result<Vec<Vec<u8>>, SledStorageError> {
+        let mut group_keys = BTreeSet::new();
+        for tree in [GROUP_STATE_TREE, GROUP_CONTEXT_TREE] {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            for key in self.scan_keys(&active_tree, &[])? {
+                group_keys.insert(key.to_vec());
+            }
+        }
+        Ok(group_keys.into_iter().collect())
+    }
+
+    /// Lists the ids of all groups that have a group state or a group context
+    /// stored.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `GroupId` - The type of the group ids.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the group ids or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::InvalidConfig` if lookup keys are hashed, as
+    /// the group ids cannot be recovered from their hashes. Use
+    /// [`Self::group_ids`] instead.
+    pub fn group_ids_typed<GroupId>(&self) -> Result<Vec<GroupId>, SledStorageError>
+    where
+        GroupId: traits::GroupId<CURRENT_VERSION> + DeserializeOwned,
+    {
+        if self.hashes_lookup_keys() {
+            return Err(SledStorageError::InvalidConfig(
+                "group ids cannot be recovered from hashed lookup keys".to_string(),
+            ));
+        }
+        self.group_ids()?
+            .iter()
+            .map(|key| helpers::from_json(key))
+            .collect()
+    }
+
+    /// Summarizes what is stored for a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group, as returned by
+    ///   [`Self::group_ids`].
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `GroupSummary` or a `SledStorageError`.
+    pub fn group_summary(&self, group_key: &[u8]) -> Result<GroupSummary, SledStorageError> {
+        let mut trees = Vec::new();
+        for (tree, entries) in self.group_entries(group_key)? {
+            if entries.is_empty() {
+                continue;
+            }
+            trees.push(TreeSummary {
+                name: String::from_utf8_lossy(tree).into_owned(),
+                entries: entries.len(),
+                bytes: entries.iter().map(|(_, value)| value.len()).sum(),
+            });
+        }
+
+        Ok(GroupSummary {
+            trees,
+            own_leaf_index: self.stored_own_leaf_index(group_key)?,
+        })
+    }
+
+    /// Reads the own leaf index in a group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the leaf index, or `None` if none is stored or it
+    /// is not a plain number, or a `SledStorageError`.
+    pub(crate) fn stored_own_leaf_index(
+        &self,
+        group_key: &[u8],
+    ) -> Result<Option<u32>, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(OWN_LEAF_NODE_INDEX_TREE)?;
+        match self.get(&active_tree, group_key)? {
+            Some(stored) => {
+                let entity = self.decode_value(OWN_LEAF_NODE_INDEX_TREE, group_key, &stored)?;
+                Ok(helpers::from_json(&entity).ok())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the current epoch of a group from its group context.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the epoch, or `None` if no group context is
+    /// stored or it has no numeric `epoch` field, or a `SledStorageError`.
+    pub(crate) fn current_epoch(&self, group_key: &[u8]) -> Result<Option<u64>, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(GROUP_CONTEXT_TREE)?;
+        match self.get(&active_tree, group_key)? {
+            Some(stored) => {
+                let entity = self.decode_value(GROUP_CONTEXT_TREE, group_key, &stored)?;
+                let context: serde_json::Value = helpers::from_json(&entity)?;
+                Ok(context.get("epoch").and_then(serde_json::Value::as_u64))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes all state stored for a group, such as when leaving or
+    /// forgetting it.
+    ///
+    /// This removes the entries of the group in every group-scoped tree,
+    /// including the encryption key pairs of all epochs and all queued
+    /// proposals, in a single transaction. Key packages, signature key pairs,
+    /// encryption key pairs and PSKs are not tied to a group and are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The id of the group to delete.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`. On error nothing
+    /// has been deleted.
+    pub fn delete_group<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), SledStorageError> {
+        tracing::debug!(target: "openmls_sled_storage::groups", "Deleting group");
+
+        let group_key = self.lookup_key(group_id)?;
+        self.atomically(|storage| {
+            for (tree, entries) in storage.group_entries(&group_key)? {
+                for (key, _) in entries {
+                    storage.delete::<CURRENT_VERSION>(tree, &key)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Finds the entries of a group in every group-scoped tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the keys and raw values of the entries per tree, or
+    /// a SledStorageError.
+    fn group_entries(
+        &self,
+        group_key: &[u8],
+    ) -> Result<Vec<(&'static [u8], Entries)>, SledStorageError> {
+        let mut entries = Vec::new();
+        for tree in GROUP_ENTITY_TREES {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            let entry = self.get(&active_tree, group_key)?;
+            let entry = entry.map(|value| (IVec::from(group_key), value));
+            entries.push((tree, entry.into_iter().collect()));
+        }
+        for tree in GROUP_LIST_TREES {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            entries.push((tree, self.scan_list(&active_tree, group_key)?));
+        }
+
+        let active_tree = self.open_tree::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE)?;
+        let proposal_prefix = self.proposal_prefix(group_key);
+        entries.push((
+            QUEUED_PROPOSAL_TREE,
+            self.scan_prefix(&active_tree, &proposal_prefix)?,
+        ));
+
+        // The keys of the epoch key pair lists start with the lookup key of
+        // the group, which is self-delimiting, but the length prefix of the
+        // item keys varies with the epoch, so the keys of the whole tree are
+        // scanned and only the values of the matching ones are read.
+        let active_tree = self.open_tree::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE)?;
+        let mut key_pairs = Vec::new();
+        for item_key in self.scan_keys(&active_tree, &[])? {
+            let belongs_to_group = helpers::split_list_item_key(&item_key)
+                .is_some_and(|(key, _)| key.starts_with(group_key));
+            if !belongs_to_group {
+                continue;
+            }
+            if let Some(value) = self.get(&active_tree, &item_key)? {
+                key_pairs.push((item_key, value));
+            }
+        }
+        entries.push((EPOCH_KEY_PAIRS_TREE, key_pairs));
+
+        Ok(entries)
+    }
+}
@@ -1,6 +1,8 @@
-use crate::SledStorage;
+use crate::{SledStorage, SledStorageError};
 use openmls_traits::storage::*;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::io;
 
 /// Builds a key with version and label.
 ///
@@ -15,6 +17,7 @@ use serde::Serialize;
 /// # Returns
 ///
 /// A Vec<u8> containing the label, key, and version number.
+#[deprecated(note = "stored entries are keyed through `crate::keys`, not with a label")]
 pub fn build_key_from_vec<const V: u16>(label: &[u8], key: Vec<u8>) -> Vec<u8> {
     let mut key_out = label.to_vec();
     key_out.extend_from_slice(&key);
@@ -22,11 +25,35 @@ pub fn build_key_from_vec<const V: u16>(label: &[u8], key: Vec<u8>) -> Vec<u8> {
     key_out
 }
 
+/// Returns the name of the sled tree holding `tree` for version `V` of the
+/// storage traits.
+///
+/// Every version of the storage traits gets a namespace of its own: the same
+/// logical tree is a different sled tree for each version. A
+/// `StorageProvider` implementation for a new version can thus share a
+/// database with the data of older versions, and migrate it lazily.
+///
+/// # Arguments
+///
+/// * `tree` - The name of the tree.
+///
+/// # Returns
+///
+/// The name of the sled tree, `tree` followed by the version.
+pub fn versioned_tree<const V: u16>(tree: &[u8]) -> Vec<u8> {
+    let mut name = tree.to_vec();
+    name.extend_from_slice(&V.to_be_bytes());
+    name
+}
+
 /// Builds a key with version and label, serializing the key.
 ///
 /// This function is similar to `build_key_from_vec`, but it takes a serializable key
 /// and serializes it before building the final key.
 ///
+/// Stored entries are not keyed this way; see [`crate::keys`] for the keys the
+/// storage provider uses.
+///
 /// # Arguments
 ///
 /// * `label` - A byte slice representing the label.
@@ -34,9 +61,16 @@ pub fn build_key_from_vec<const V: u16>(label: &[u8], key: Vec<u8>) -> Vec<u8> {
 ///
 /// # Returns
 ///
-/// A Vec<u8> containing the label, serialized key, and version number.
-pub fn build_key<const V: u16, K: Serialize>(label: &[u8], key: K) -> Vec<u8> {
-    build_key_from_vec::<V>(label, serde_json::to_vec(&key).unwrap())
+/// A Result containing a Vec<u8> with the label, serialized key, and version
+/// number, or a `SledStorageError::EncodeError` if the key cannot be
+/// serialized.
+#[deprecated(note = "stored entries are keyed through `crate::keys`, not with a label")]
+#[allow(deprecated)]
+pub fn build_key<const V: u16, K: Serialize>(
+    label: &[u8],
+    key: K,
+) -> Result<Vec<u8>, SledStorageError> {
+    Ok(build_key_from_vec::<V>(label, to_json(&key)?))
 }
 
 /// Generates a unique identifier for epoch key pairs.
@@ -57,17 +91,142 @@ pub fn epoch_key_pairs_id(
     epoch: &impl traits::EpochKey<CURRENT_VERSION>,
     leaf_index: u32,
 ) -> Result<Vec<u8>, <SledStorage as StorageProvider<CURRENT_VERSION>>::Error> {
-    let mut key = serde_json::to_vec(group_id)?;
-    key.extend_from_slice(&serde_json::to_vec(epoch)?);
-    key.extend_from_slice(&serde_json::to_vec(&leaf_index)?);
+    let mut key = to_json(group_id)?;
+    key.extend_from_slice(&to_json(epoch)?);
+    key.extend_from_slice(&to_json(&leaf_index)?);
     Ok(key)
 }
+
+/// Serializes a value to JSON.
+///
+/// # Arguments
+///
+/// * `value` - The value to serialize.
+///
+/// # Returns
+///
+/// A Result containing the JSON encoding of the value or a
+/// `SledStorageError::EncodeError`.
+pub(crate) fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SledStorageError> {
+    serde_json::to_vec(value).map_err(SledStorageError::encode)
+}
+
+/// Deserializes a value from JSON.
+///
+/// # Arguments
+///
+/// * `bytes` - The JSON encoding of the value.
+///
+/// # Returns
+///
+/// A Result containing the value or a `SledStorageError::DecodeError`.
+pub(crate) fn from_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SledStorageError> {
+    serde_json::from_slice(bytes).map_err(SledStorageError::decode)
+}
+
+/// Serializes a secret value into a buffer of exactly the right size.
+///
+/// `serde_json::to_vec` grows its buffer while writing, which leaves partial
+/// copies of the value behind in freed memory. Measuring the value first and
+/// allocating the buffer up front means the returned buffer is the only copy,
+/// and wiping it is enough.
+///
+/// # Arguments
+///
+/// * `value` - The value to serialize.
+///
+/// # Returns
+///
+/// A Result containing the JSON encoding of the value or a
+/// `SledStorageError::EncodeError`.
+pub fn serialize_secret<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SledStorageError> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value).map_err(SledStorageError::encode)?;
+    let mut out = Vec::with_capacity(counter.0);
+    serde_json::to_writer(&mut out, value).map_err(SledStorageError::encode)?;
+    Ok(out)
+}
+
+/// A writer that only counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the prefix shared by all items of the list stored at a key.
+///
+/// The key is prefixed with its length, so the items of one list can never be
+/// mistaken for those of a list whose key merely starts with the same bytes.
+///
+/// # Arguments
+///
+/// * `key` - The key of the list.
+///
+/// # Returns
+///
+/// A Vec<u8> containing the big-endian `u32` length of the key, followed by the key.
+pub fn list_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + key.len() + 8);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+/// Builds the key of a single list item.
+///
+/// Items sort by their sequence number, so a prefix scan returns them in the
+/// order they were appended.
+///
+/// # Arguments
+///
+/// * `key` - The key of the list.
+/// * `seq` - The sequence number of the item.
+///
+/// # Returns
+///
+/// A Vec<u8> containing the list prefix followed by the big-endian sequence number.
+pub fn list_item_key(key: &[u8], seq: u64) -> Vec<u8> {
+    let mut item_key = list_prefix(key);
+    item_key.extend_from_slice(&seq.to_be_bytes());
+    item_key
+}
+
+/// Splits a list item key built by [`list_item_key`] into the key of the list
+/// and the sequence number of the item.
+///
+/// # Arguments
+///
+/// * `item_key` - The key of the list item.
+///
+/// # Returns
+///
+/// The key of the list and the sequence number, or `None` if `item_key` is
+/// not a list item key.
+pub fn split_list_item_key(item_key: &[u8]) -> Option<(&[u8], u64)> {
+    let (len, rest) = item_key.split_first_chunk::<4>()?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() != len + 8 {
+        return None;
+    }
+    let (key, seq) = rest.split_at(len);
+    Some((key, u64::from_be_bytes(seq.try_into().ok()?)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use serde::Serialize;
 
     #[test]
+    #[allow(deprecated)]
     fn test_build_key_from_vec() {
         let label = b"test_label";
         let key = vec![1, 2, 3, 4];
@@ -79,6 +238,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_build_key() {
         #[derive(Serialize)]
         struct TestKey {
@@ -86,7 +246,7 @@ mod tests {
         }
         let label = b"test_label";
         let key = TestKey { id: 42 };
-        let result = build_key::<1, _>(label, key);
+        let result = build_key::<1, _>(label, key).unwrap();
         assert_eq!(
             result,
             vec![
@@ -96,6 +256,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_versioned_tree() {
+        assert_eq!(versioned_tree::<1>(b"GroupState"), b"GroupState\x00\x01");
+        assert_ne!(
+            versioned_tree::<1>(b"GroupState"),
+            versioned_tree::<2>(b"GroupState")
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_build_key_unserializable() {
+        // JSON objects need string keys.
+        let key = std::collections::BTreeMap::from([(vec![1u8], 1u8)]);
+        assert!(matches!(
+            build_key::<1, _>(b"test_label", key),
+            Err(SledStorageError::EncodeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_serialize_secret() {
+        let value = ("secret".to_string(), vec![1u8; 100]);
+        let serialized = serialize_secret(&value).unwrap();
+        assert_eq!(serialized, serde_json::to_vec(&value).unwrap());
+        assert_eq!(serialized.capacity(), serialized.len());
+    }
+
     #[derive(Serialize)]
     struct MockGroupId {
         id: String,
@@ -139,6 +327,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_build_key_from_vec_empty() {
         let label = b"";
         let key = Vec::<u8>::new();
@@ -147,6 +336,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_build_key_different_versions() {
         let label = b"test";
         let key = vec![1, 2, 3];
@@ -159,6 +349,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_build_key_with_special_characters() {
         #[derive(Serialize)]
         struct TestKey {
@@ -168,7 +359,7 @@ mod tests {
         let key = TestKey {
             value: "special@#$%^&*".to_string(),
         };
-        let result = build_key::<1, _>(label, key);
+        let result = build_key::<1, _>(label, key).unwrap();
         // We don't assert exact bytes since JSON serialization might vary,
         // but we ensure the key is properly constructed
         assert!(result.starts_with(label));
@@ -200,6 +391,29 @@ mod tests {
     }
 
     #[test]
+    fn test_list_item_key() {
+        let item_key = list_item_key(b"group", 258);
+        assert_eq!(
+            item_key,
+            vec![0, 0, 0, 5, b'g', b'r', b'o', b'u', b'p', 0, 0, 0, 0, 0, 0, 1, 2]
+        );
+        assert!(item_key.starts_with(&list_prefix(b"group")));
+
+        // Items of a list whose key extends another key do not share its prefix
+        assert!(!list_item_key(b"group1", 0).starts_with(&list_prefix(b"group")));
+
+        // Items sort by sequence number
+        assert!(list_item_key(b"group", 255) < list_item_key(b"group", 256));
+
+        assert_eq!(
+            split_list_item_key(&item_key),
+            Some((b"group".as_slice(), 258))
+        );
+        assert_eq!(split_list_item_key(b"group"), None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn test_build_key_with_unicode() {
         #[derive(Serialize)]
         struct TestKey {
@@ -209,7 +423,7 @@ mod tests {
         let key = TestKey {
             value: "Hello 世界".to_string(),
         };
-        let result = build_key::<1, _>(label, key);
+        let result = build_key::<1, _>(label, key).unwrap();
         assert!(result.starts_with(label));
         assert_eq!(result[result.len() - 2..], vec![0, 1]);
     }
@@ -0,0 +1,404 @@
+//! Inventory of stored key packages.
+//!
+//! OpenMLS only reads and deletes key packages by their hash reference. To
+//! tell how many unused key packages are left and which of them have expired,
+//! the time a key package was written and its lifetime are recorded next to
+//! it, in a tree of their own under the same lookup key.
+//!
+//! Key packages written through [`StorageProvider::write_key_package`] get the
+//! lifetime from [`SledStorageConfig::key_package_lifetime`]. Key packages
+//! written before this was recorded have no creation time and never expire.
+//!
+//! A key package can be marked as last resort, see
+//! [`SledStorage::mark_last_resort_key_package`]. OpenMLS deletes a key package
+//! once it has been used to join a group; for a last-resort key package the
+//! delete only counts the use, so that it can be used for any number of joins.
+//! It stays stored until it is replaced through
+//! [`SledStorage::rotate_last_resort_key_package`] or has expired and is purged.
+//!
+//! [`StorageProvider::write_key_package`]: openmls_traits::storage::StorageProvider::write_key_package
+//! [`SledStorageConfig::key_package_lifetime`]: crate::SledStorageConfig::key_package_lifetime
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openmls_traits::storage::{traits, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+
+use crate::traits::{KEY_PACKAGE_INFO_TREE, KEY_PACKAGE_TREE};
+use crate::{helpers, SledStorage, SledStorageError};
+
+/// A stored key package and when it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPackageInfo {
+    /// The lookup key the key package is stored under: the JSON encoding of
+    /// its hash reference, or its keyed hash if lookup keys are hashed.
+    pub key: Vec<u8>,
+    /// When the key package was written, if known.
+    pub created_at: Option<SystemTime>,
+    /// How long the key package stays valid after it was written. `None`
+    /// means it never expires.
+    pub lifetime: Option<Duration>,
+    /// Whether the key package is a last-resort key package.
+    pub last_resort: bool,
+    /// How often a last-resort key package has been used.
+    pub uses: u64,
+}
+
+impl KeyPackageInfo {
+    /// Returns the time the key package expires at, if it expires.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.created_at?.checked_add(self.lifetime?)
+    }
+
+    /// Returns `true` if the key package has expired at the given time.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// What is recorded next to a key package. Times are in seconds since the
+/// Unix epoch.
+#[derive(Serialize, Deserialize, Default)]
+struct KeyPackageRecord {
+    created_at: Option<u64>,
+    lifetime: Option<u64>,
+    #[serde(default)]
+    last_resort: bool,
+    #[serde(default)]
+    uses: u64,
+}
+
+impl SledStorage {
+    /// Writes a key package with the given lifetime, overriding
+    /// [`SledStorageConfig::key_package_lifetime`].
+    ///
+    /// # Arguments
+    ///
+    /// * `hash_ref` - The hash reference of the key package.
+    /// * `key_package` - The key package to store.
+    /// * `lifetime` - How long the key package stays valid, or `None` if it
+    ///   never expires.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// [`SledStorageConfig::key_package_lifetime`]: crate::SledStorageConfig::key_package_lifetime
+    pub fn write_key_package_with_lifetime<HashReference, KeyPackage>(
+        &self,
+        hash_ref: &HashReference,
+        key_package: &KeyPackage,
+        lifetime: Option<Duration>,
+    ) -> Result<(), SledStorageError>
+    where
+        HashReference: traits::HashReference<CURRENT_VERSION>,
+        KeyPackage: traits::KeyPackage<CURRENT_VERSION>,
+    {
+        let key = self.lookup_key(hash_ref)?;
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let record = KeyPackageRecord {
+            created_at: Some(created_at.as_secs()),
+            lifetime: lifetime.map(|lifetime| lifetime.as_secs()),
+            ..Default::default()
+        };
+        self.atomically(|storage| {
+            storage.write::<CURRENT_VERSION>(
+                KEY_PACKAGE_TREE,
+                &key,
+                helpers::to_json(key_package)?,
+            )?;
+            storage.write_key_package_record(&key, &record)
+        })
+    }
+
+    /// Marks a stored key package as last resort, so that deleting it through
+    /// [`StorageProvider::delete_key_package`] only counts a use.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash_ref` - The hash reference of the key package.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::NotFound` if no key package is stored under the
+    /// hash reference.
+    ///
+    /// [`StorageProvider::delete_key_package`]: openmls_traits::storage::StorageProvider::delete_key_package
+    pub fn mark_last_resort_key_package<HashReference>(
+        &self,
+        hash_ref: &HashReference,
+    ) -> Result<(), SledStorageError>
+    where
+        HashReference: traits::HashReference<CURRENT_VERSION>,
+    {
+        let key = self.lookup_key(hash_ref)?;
+        self.atomically(|storage| {
+            let active_tree = storage.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_TREE)?;
+            if storage.get(&active_tree, &key)?.is_none() {
+                return Err(SledStorageError::not_found());
+            }
+            let mut record = storage.key_package_record(&key)?.unwrap_or_default();
+            record.last_resort = true;
+            storage.write_key_package_record(&key, &record)
+        })
+    }
+
+    /// Replaces a last-resort key package with a new one, in a single
+    /// transaction.
+    ///
+    /// The new key package is written with
+    /// [`SledStorageConfig::key_package_lifetime`] and marked as last resort.
+    /// The old one is deleted even though it is a last-resort key package.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_hash_ref` - The hash reference of the key package to replace.
+    /// * `new_hash_ref` - The hash reference of the new key package.
+    /// * `key_package` - The new key package.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::NotFound` if no key package is stored under
+    /// `old_hash_ref`, or if it is not a last-resort key package.
+    ///
+    /// [`SledStorageConfig::key_package_lifetime`]: crate::SledStorageConfig::key_package_lifetime
+    pub fn rotate_last_resort_key_package<HashReference, KeyPackage>(
+        &self,
+        old_hash_ref: &HashReference,
+        new_hash_ref: &HashReference,
+        key_package: &KeyPackage,
+    ) -> Result<(), SledStorageError>
+    where
+        HashReference: traits::HashReference<CURRENT_VERSION>,
+        KeyPackage: traits::KeyPackage<CURRENT_VERSION>,
+    {
+        tracing::debug!(target: "openmls_sled_storage::key_packages", "Rotating last-resort key package");
+
+        let old_key = self.lookup_key(old_hash_ref)?;
+        self.atomically(|storage| {
+            let active_tree = storage.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_TREE)?;
+            let is_last_resort = storage
+                .key_package_record(&old_key)?
+                .is_some_and(|record| record.last_resort);
+            if storage.get(&active_tree, &old_key)?.is_none() || !is_last_resort {
+                return Err(SledStorageError::not_found());
+            }
+            storage.remove_key_package(&old_key)?;
+            storage.write_key_package_with_lifetime(
+                new_hash_ref,
+                key_package,
+                storage.key_package_lifetime,
+            )?;
+            storage.mark_last_resort_key_package(new_hash_ref)
+        })
+    }
+
+    /// Lists all stored key packages.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the key packages, ordered by lookup key, or a
+    /// `SledStorageError`.
+    pub fn key_packages(&self) -> Result<Vec<KeyPackageInfo>, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_TREE)?;
+
+        let mut key_packages = Vec::new();
+        for key in self.scan_keys(&active_tree, &[])? {
+            let record = self.key_package_record(&key)?.unwrap_or_default();
+            key_packages.push(KeyPackageInfo {
+                key: key.to_vec(),
+                created_at: record
+                    .created_at
+                    .map(|created_at| UNIX_EPOCH + Duration::from_secs(created_at)),
+                lifetime: record.lifetime.map(Duration::from_secs),
+                last_resort: record.last_resort,
+                uses: record.uses,
+            });
+        }
+        Ok(key_packages)
+    }
+
+    /// Lists the stored last-resort key packages.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the last-resort key packages or a
+    /// `SledStorageError`.
+    pub fn last_resort_key_packages(&self) -> Result<Vec<KeyPackageInfo>, SledStorageError> {
+        let mut key_packages = self.key_packages()?;
+        key_packages.retain(|key_package| key_package.last_resort);
+        Ok(key_packages)
+    }
+
+    /// Counts the stored key packages.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of key packages or a
+    /// `SledStorageError`.
+    pub fn key_package_count(&self) -> Result<usize, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_TREE)?;
+        Ok(self.scan_keys(&active_tree, &[])?.len())
+    }
+
+    /// Lists the key packages that have expired at the given time, including
+    /// last-resort ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time to check expiry against.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the expired key packages or a `SledStorageError`.
+    pub fn expired_key_packages(
+        &self,
+        now: SystemTime,
+    ) -> Result<Vec<KeyPackageInfo>, SledStorageError> {
+        let mut key_packages = self.key_packages()?;
+        key_packages.retain(|key_package| key_package.is_expired(now));
+        Ok(key_packages)
+    }
+
+    /// Deletes the key packages that have expired at the given time, in a
+    /// single transaction.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time to check expiry against.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the deleted key packages or a `SledStorageError`.
+    /// On error nothing has been deleted.
+    pub fn purge_expired_key_packages(
+        &self,
+        now: SystemTime,
+    ) -> Result<Vec<KeyPackageInfo>, SledStorageError> {
+        tracing::debug!(target: "openmls_sled_storage::key_packages", "Purging expired key packages");
+
+        self.atomically(|storage| {
+            let expired = storage.expired_key_packages(now)?;
+            for key_package in &expired {
+                storage.remove_key_package(&key_package.key)?;
+            }
+            Ok(expired)
+        })
+    }
+
+    /// Deletes a key package that has been used, unless it is a last-resort
+    /// key package, in which case the use is counted instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The lookup key of the key package.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    pub(crate) fn use_key_package(&self, key: &[u8]) -> Result<(), SledStorageError> {
+        self.atomically(|storage| match storage.key_package_record(key)? {
+            Some(mut record) if record.last_resort => {
+                record.uses += 1;
+                storage.write_key_package_record(key, &record)
+            }
+            _ => storage.remove_key_package(key),
+        })
+    }
+
+    /// Deletes a key package together with what is recorded about it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The lookup key of the key package.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    pub(crate) fn remove_key_package(&self, key: &[u8]) -> Result<(), SledStorageError> {
+        self.atomically(|storage| {
+            storage.delete::<CURRENT_VERSION>(KEY_PACKAGE_TREE, key)?;
+            storage.delete::<CURRENT_VERSION>(KEY_PACKAGE_INFO_TREE, key)
+        })
+    }
+
+    /// Reads what is recorded about a key package.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The lookup key of the key package.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the record, if there is one, or a
+    /// `SledStorageError`.
+    fn key_package_record(&self, key: &[u8]) -> Result<Option<KeyPackageRecord>, SledStorageError> {
+        let active_tree = self.open_tree::<CURRENT_VERSION>(KEY_PACKAGE_INFO_TREE)?;
+        match self.get(&active_tree, key)? {
+            Some(stored) => {
+                let record = self.decode_value(KEY_PACKAGE_INFO_TREE, key, &stored)?;
+                Ok(Some(helpers::from_json(&record)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes what is recorded about a key package.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The lookup key of the key package.
+    /// * `record` - The record to write.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    fn write_key_package_record(
+        &self,
+        key: &[u8],
+        record: &KeyPackageRecord,
+    ) -> Result<(), SledStorageError> {
+        self.write::<CURRENT_VERSION>(KEY_PACKAGE_INFO_TREE, key, helpers::to_json(record)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let created_at = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut info = KeyPackageInfo {
+            key: Vec::new(),
+            created_at: Some(created_at),
+            lifetime: Some(Duration::from_secs(10)),
+            last_resort: false,
+            uses: 0,
+        };
+        assert_eq!(
+            info.expires_at(),
+            Some(created_at + Duration::from_secs(10))
+        );
+        assert!(!info.is_expired(created_at + Duration::from_secs(9)));
+        assert!(info.is_expired(created_at + Duration::from_secs(10)));
+
+        info.lifetime = None;
+        assert!(!info.is_expired(created_at + Duration::from_secs(u32::MAX as u64)));
+
+        info.lifetime = Some(Duration::from_secs(10));
+        info.created_at = None;
+        assert_eq!(info.expires_at(), None);
+    }
+}
@@ -0,0 +1,458 @@
+//! Lookup keys.
+//!
+//! By default the keys OpenMLS looks entities up by, such as group ids, public
+//! keys and key package references, are serialized to JSON and used as sled
+//! keys as they are. Anyone who can read the database files can therefore see
+//! which groups exist and which keys they use, even when values are encrypted.
+//!
+//! With [`SledStorageConfig::hash_lookup_keys`] each part of a lookup key is
+//! instead replaced by its HMAC-SHA256 under a random secret. The secret is
+//! generated when the database is created and kept in the meta tree, encrypted
+//! with the storage key. Composite keys are built from the hashes of their
+//! parts, so every entry belonging to a group still starts with the hash of the
+//! group id and can be found with a prefix scan.
+//!
+//! Every [`StorageProvider`] method builds its keys through this module, so the
+//! key an entry is deleted under is always the key it was written under.
+//!
+//! [`SledStorageConfig::hash_lookup_keys`]: crate::SledStorageConfig::hash_lookup_keys
+
+use std::fmt;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use hmac::{Hmac, Mac};
+use openmls_traits::storage::{traits, StorageProvider, CURRENT_VERSION};
+use serde::Serialize;
+use sha2::Sha256;
+use sled::Db;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::encryption::{self, StorageKey};
+use crate::migrations::{self, META_TREE};
+use crate::{codec, helpers, SledStorage, SledStorageError};
+
+pub(crate) const LOOKUP_SECRET_KEY: &[u8] = b"lookup_key_secret";
+
+/// Length of the lookup key secret in bytes.
+const SECRET_LEN: usize = 32;
+
+/// Length of a hashed key part in bytes.
+pub const HASH_LEN: usize = 32;
+
+/// Computes keyed hashes of lookup key parts.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct KeyHasher {
+    secret: [u8; SECRET_LEN],
+}
+
+impl KeyHasher {
+    fn hash(&self, part: &[u8]) -> Result<[u8; HASH_LEN], SledStorageError> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret)
+            .map_err(|_| SledStorageError::encryption())?;
+        mac.update(part);
+        Ok(mac.finalize().into_bytes().into())
+    }
+}
+
+impl fmt::Debug for KeyHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyHasher(..)")
+    }
+}
+
+fn encrypt_secret(
+    storage_key: &StorageKey,
+    hasher: &KeyHasher,
+) -> Result<Vec<u8>, SledStorageError> {
+    encryption::encrypt(
+        storage_key,
+        META_TREE,
+        LOOKUP_SECRET_KEY,
+        &Zeroizing::new(codec::encode_entity(&hasher.secret)),
+    )
+}
+
+fn decrypt_secret(storage_key: &StorageKey, stored: &[u8]) -> Result<KeyHasher, SledStorageError> {
+    let value = encryption::decrypt(storage_key, META_TREE, LOOKUP_SECRET_KEY, stored)?;
+    let secret = codec::decode_entity(&value)?
+        .try_into()
+        .map_err(|_| SledStorageError::malformed())?;
+    Ok(KeyHasher { secret })
+}
+
+/// Loads the lookup key secret, generating it if hashed lookup keys are
+/// turned on for a new database.
+///
+/// # Arguments
+///
+/// * `db` - The Sled database.
+/// * `storage_key` - The storage key, if encryption is enabled.
+/// * `enable` - Whether hashed lookup keys were requested.
+///
+/// # Returns
+///
+/// A Result containing the `KeyHasher` if lookup keys are hashed, or a SledStorageError.
+///
+/// # Errors
+///
+/// Returns `SledStorageError::InvalidConfig` if hashed lookup keys are
+/// requested without encryption or for a database that already holds data, and
+/// `SledStorageError::DecryptionError` if the database uses hashed lookup keys
+/// but is opened without its storage key.
+pub(crate) fn load(
+    db: &Db,
+    storage_key: Option<&StorageKey>,
+    enable: bool,
+) -> Result<Option<KeyHasher>, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    if let Some(stored) = meta.get(LOOKUP_SECRET_KEY)? {
+        let storage_key = storage_key.ok_or_else(SledStorageError::decryption)?;
+        return decrypt_secret(storage_key, &stored).map(Some);
+    }
+    if !enable {
+        return Ok(None);
+    }
+
+    let Some(storage_key) = storage_key else {
+        return Err(SledStorageError::InvalidConfig(
+            "hashed lookup keys require encryption".to_string(),
+        ));
+    };
+    if migrations::has_mls_state(db)? {
+        return Err(SledStorageError::InvalidConfig(
+            "hashed lookup keys can only be turned on for a new database".to_string(),
+        ));
+    }
+
+    tracing::debug!(target: "openmls_sled_storage::keys", "Generating lookup key secret");
+    let mut hasher = KeyHasher {
+        secret: [0u8; SECRET_LEN],
+    };
+    OsRng.fill_bytes(&mut hasher.secret);
+    meta.insert(LOOKUP_SECRET_KEY, encrypt_secret(storage_key, &hasher)?)?;
+    Ok(Some(hasher))
+}
+
+/// Re-encrypts the stored lookup key secret with a new storage key.
+///
+/// # Returns
+///
+/// A Result containing the re-encrypted secret, or `None` if lookup keys are
+/// not hashed, or a SledStorageError.
+pub(crate) fn rewrap_secret(
+    db: &Db,
+    old: &StorageKey,
+    new: &StorageKey,
+) -> Result<Option<Vec<u8>>, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(LOOKUP_SECRET_KEY)? {
+        Some(stored) => {
+            // The secret may already have been re-encrypted by an interrupted rekey.
+            let hasher = decrypt_secret(old, &stored).or_else(|_| decrypt_secret(new, &stored))?;
+            Ok(Some(encrypt_secret(new, &hasher)?))
+        }
+        None => Ok(None),
+    }
+}
+
+impl SledStorage {
+    /// Returns `true` if lookup keys are stored as keyed hashes.
+    pub fn hashes_lookup_keys(&self) -> bool {
+        self.key_hasher.is_some()
+    }
+
+    /// Builds the sled key for an entity looked up by a single key, such as a
+    /// group id or a public key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The OpenMLS key.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the sled key or a SledStorageError.
+    pub(crate) fn lookup_key(&self, key: &impl Serialize) -> Result<Vec<u8>, SledStorageError> {
+        let key = helpers::to_json(key)?;
+        match &self.key_hasher {
+            Some(key_hasher) => Ok(key_hasher.hash(&key)?.to_vec()),
+            None => Ok(key),
+        }
+    }
+
+    /// Builds the sled key for a queued proposal.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The group the proposal is queued in.
+    /// * `proposal_ref` - The reference of the proposal.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the sled key, which starts with the lookup key of
+    /// the group when lookup keys are hashed, or a SledStorageError.
+    pub(crate) fn proposal_key(
+        &self,
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        proposal_ref: &impl traits::ProposalRef<CURRENT_VERSION>,
+    ) -> Result<Vec<u8>, SledStorageError> {
+        self.proposal_key_in(
+            &self.lookup_key(group_id)?,
+            &helpers::to_json(proposal_ref)?,
+        )
+    }
+
+    /// Builds the sled key for a queued proposal from the lookup key of the
+    /// group and the JSON encoding of the proposal reference.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group the proposal is queued in.
+    /// * `proposal_ref` - The JSON encoding of the reference of the proposal.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the sled key or a SledStorageError.
+    pub(crate) fn proposal_key_in(
+        &self,
+        group_key: &[u8],
+        proposal_ref: &[u8],
+    ) -> Result<Vec<u8>, SledStorageError> {
+        let mut key = self.proposal_prefix(group_key);
+        match &self.key_hasher {
+            Some(key_hasher) => key.extend_from_slice(&key_hasher.hash(proposal_ref)?),
+            None => {
+                key.extend_from_slice(proposal_ref);
+                key.push(b']');
+            }
+        }
+        Ok(key)
+    }
+
+    /// Builds the prefix shared by the sled keys of all proposals queued in a
+    /// group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group the proposals are queued in.
+    ///
+    /// # Returns
+    ///
+    /// The prefix.
+    pub(crate) fn proposal_prefix(&self, group_key: &[u8]) -> Vec<u8> {
+        match &self.key_hasher {
+            Some(_) => group_key.to_vec(),
+            None => {
+                // The JSON array `[group_id,proposal_ref]`, up to the proposal ref.
+                let mut prefix = b"[".to_vec();
+                prefix.extend_from_slice(group_key);
+                prefix.push(b',');
+                prefix
+            }
+        }
+    }
+
+    /// Builds the sled key for the encryption key pairs of an epoch.
+    ///
+    /// The key of every epoch of a group starts with the lookup key of the
+    /// group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_id` - The group the key pairs belong to.
+    /// * `epoch` - The epoch the key pairs belong to.
+    /// * `leaf_index` - The leaf index of the key pairs' owner.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the sled key, which starts with the lookup key of
+    /// the group when lookup keys are hashed, or a SledStorageError.
+    pub(crate) fn epoch_key_pairs_key(
+        &self,
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        epoch: &impl traits::EpochKey<CURRENT_VERSION>,
+        leaf_index: u32,
+    ) -> Result<Vec<u8>, <SledStorage as StorageProvider<CURRENT_VERSION>>::Error> {
+        self.epoch_key_pairs_key_in(&self.lookup_key(group_id)?, epoch, leaf_index)
+    }
+
+    /// Builds the sled key for the encryption key pairs of an epoch from the
+    /// lookup key of the group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_key` - The lookup key of the group the key pairs belong to.
+    /// * `epoch` - The epoch the key pairs belong to.
+    /// * `leaf_index` - The leaf index of the key pairs' owner.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the sled key or a SledStorageError.
+    pub(crate) fn epoch_key_pairs_key_in(
+        &self,
+        group_key: &[u8],
+        epoch: &impl Serialize,
+        leaf_index: u32,
+    ) -> Result<Vec<u8>, SledStorageError> {
+        let mut epoch_and_leaf = helpers::to_json(epoch)?;
+        epoch_and_leaf.extend_from_slice(&helpers::to_json(&leaf_index)?);
+        let mut key = group_key.to_vec();
+        match &self.key_hasher {
+            Some(key_hasher) => key.extend_from_slice(&key_hasher.hash(&epoch_and_leaf)?),
+            None => key.extend_from_slice(&epoch_and_leaf),
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{helpers, Encryption, SledStorageConfig};
+    use openmls_traits::storage::{Entity, Key};
+    use serde::Deserialize;
+    use tempfile::tempdir;
+
+    #[derive(Serialize)]
+    struct TestGroupId(Vec<u8>);
+
+    impl Key<CURRENT_VERSION> for TestGroupId {}
+    impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+
+    #[derive(Serialize, Deserialize)]
+    struct TestProposalRef(u32);
+
+    impl Key<CURRENT_VERSION> for TestProposalRef {}
+    impl Entity<CURRENT_VERSION> for TestProposalRef {}
+    impl traits::ProposalRef<CURRENT_VERSION> for TestProposalRef {}
+
+    #[derive(Serialize)]
+    struct TestEpoch(u64);
+
+    impl Key<CURRENT_VERSION> for TestEpoch {}
+    impl traits::EpochKey<CURRENT_VERSION> for TestEpoch {}
+
+    fn hashed_config(storage_key: &StorageKey) -> SledStorageConfig {
+        SledStorageConfig {
+            encryption: Encryption::Key(storage_key.clone()),
+            hash_lookup_keys: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plain_keys_are_unchanged() {
+        let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+        assert!(!storage.hashes_lookup_keys());
+        let group_id = TestGroupId(b"group".to_vec());
+
+        assert_eq!(
+            storage.lookup_key(&group_id).unwrap(),
+            serde_json::to_vec(&group_id).unwrap()
+        );
+        let proposal_key = storage
+            .proposal_key(&group_id, &TestProposalRef(1))
+            .unwrap();
+        assert_eq!(
+            proposal_key,
+            serde_json::to_vec(&(&group_id, &TestProposalRef(1))).unwrap()
+        );
+        assert!(proposal_key
+            .starts_with(&storage.proposal_prefix(&storage.lookup_key(&group_id).unwrap())));
+        assert_eq!(
+            storage
+                .epoch_key_pairs_key(&group_id, &TestEpoch(3), 1)
+                .unwrap(),
+            helpers::epoch_key_pairs_id(&group_id, &TestEpoch(3), 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hashed_keys_keep_group_prefix() {
+        let storage_key = StorageKey::generate();
+        let storage = SledStorage::new_from_db_with_config(
+            sled::open(tempdir().unwrap().path()).unwrap(),
+            hashed_config(&storage_key),
+        )
+        .unwrap();
+        assert!(storage.hashes_lookup_keys());
+        let group_id = TestGroupId(b"group".to_vec());
+
+        let group_key = storage.lookup_key(&group_id).unwrap();
+        assert_eq!(group_key.len(), HASH_LEN);
+        assert!(!group_key
+            .windows(b"group".len())
+            .any(|window| window == b"group"));
+
+        let proposal_key = storage
+            .proposal_key(&group_id, &TestProposalRef(1))
+            .unwrap();
+        assert_eq!(proposal_key.len(), 2 * HASH_LEN);
+        assert!(proposal_key.starts_with(&group_key));
+        assert_eq!(storage.proposal_prefix(&group_key), group_key);
+        assert_ne!(
+            proposal_key,
+            storage
+                .proposal_key(&group_id, &TestProposalRef(2))
+                .unwrap()
+        );
+
+        let epoch_key = storage
+            .epoch_key_pairs_key(&group_id, &TestEpoch(3), 1)
+            .unwrap();
+        assert_eq!(epoch_key.len(), 2 * HASH_LEN);
+        assert_eq!(
+            storage
+                .epoch_key_pairs_key_in(&group_key, &3u64, 1)
+                .unwrap(),
+            epoch_key
+        );
+    }
+
+    #[test]
+    fn test_secret_is_persisted() {
+        let dir = tempdir().unwrap();
+        let storage_key = StorageKey::generate();
+        let group_id = TestGroupId(b"group".to_vec());
+
+        let storage =
+            SledStorage::new_from_path_with_config(dir.path(), hashed_config(&storage_key))
+                .unwrap();
+        let group_key = storage.lookup_key(&group_id).unwrap();
+        drop(storage);
+
+        // Hashing stays on even if it is not requested again.
+        let storage = SledStorage::new_from_path_with_key(dir.path(), storage_key.clone()).unwrap();
+        assert_eq!(storage.lookup_key(&group_id).unwrap(), group_key);
+        drop(storage);
+
+        // The secret cannot be read without the storage key.
+        assert_eq!(
+            SledStorage::new_from_path(dir.path()).err(),
+            Some(SledStorageError::decryption())
+        );
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let config = SledStorageConfig {
+            hash_lookup_keys: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            SledStorage::new_from_path_with_config(tempdir().unwrap().path(), config),
+            Err(SledStorageError::InvalidConfig(_))
+        ));
+
+        let storage_key = StorageKey::generate();
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let storage = SledStorage::new_from_db_with_key(db.clone(), storage_key.clone()).unwrap();
+        storage
+            .write::<CURRENT_VERSION>(crate::traits::GROUP_STATE_TREE, b"group", b"{}".to_vec())
+            .unwrap();
+        assert!(matches!(
+            SledStorage::new_from_db_with_config(db, hashed_config(&storage_key)),
+            Err(SledStorageError::InvalidConfig(_))
+        ));
+    }
+}
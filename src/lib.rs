@@ -1,34 +1,70 @@
+pub mod batch;
+pub mod codec;
+pub mod config;
+pub mod encryption;
+pub mod error;
+pub mod gc;
+pub mod groups;
 pub mod helpers;
+pub mod key_packages;
+pub mod keys;
+pub mod migrations;
+pub mod passphrase;
+pub mod rekey;
+pub mod secure_delete;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 pub mod traits;
-
+pub mod verify;
+
+pub use batch::SledStorageBatch;
+pub use config::{DanglingProposalRefs, Encryption, SledStorageConfig};
+pub use encryption::StorageKey;
+pub use error::{ErrorContext, JsonError, KeyFingerprint, Operation, SledStorageError};
+pub use gc::{CleanupOptions, Orphan, OrphanReason, OrphanReport};
+pub use groups::{GroupSummary, TreeSummary};
+pub use key_packages::KeyPackageInfo;
+pub use verify::{Issue, IssueKind, VerifyReport};
+
+use batch::PendingWrites;
+use keys::KeyHasher;
+use migrations::META_TREE;
 use openmls_traits::storage::*;
-use sled::Db;
-use std::path::Path;
-use std::time::Instant;
+use sled::transaction::{ConflictableTransactionResult, Transactional, TransactionalTree};
+use sled::{Db, IVec};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
 
 pub struct SledStorage {
     db: Db,
-}
-/// Errors thrown by the key store.
-#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
-pub enum SledStorageError {
-    #[error("Sled error: {0}")]
-    SledError(#[from] sled::Error),
-    #[error("Serialization error")]
-    SerializationError,
-    #[error("Value does not exist.")]
-    None,
-}
-
-impl From<serde_json::Error> for SledStorageError {
-    fn from(_: serde_json::Error) -> Self {
-        Self::SerializationError
-    }
+    /// Writes buffered by a `SledStorageBatch`, if this instance belongs to one.
+    batch: Option<Mutex<PendingWrites>>,
+    /// Key used to encrypt values at rest, if encryption is enabled.
+    storage_key: Option<StorageKey>,
+    /// Hasher for lookup keys, if lookup keys are hashed.
+    key_hasher: Option<KeyHasher>,
+    /// Location of the database files, if the instance was opened from a path.
+    path: Option<PathBuf>,
+    /// Whether deleted secrets are scrubbed from disk.
+    secure_delete: bool,
+    /// Lifetime recorded for key packages written without an explicit one.
+    key_package_lifetime: Option<Duration>,
+    /// What to do about proposal references without a queued proposal.
+    dangling_proposal_refs: DanglingProposalRefs,
+    /// Shared by the instance and its batches, so a scrub can tell whether
+    /// another handle to the database is alive.
+    handles: Arc<()>,
 }
 
 impl SledStorage {
     /// Creates a new SledStorage instance from a given path.
     ///
+    /// Databases written by older versions of this crate are migrated to the
+    /// current schema before the instance is returned.
+    ///
     /// # Arguments
     ///
     /// * `path` - A path-like object representing the location to store the database.
@@ -36,22 +72,266 @@ impl SledStorage {
     /// # Returns
     ///
     /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::UnsupportedSchemaVersion` if the database was
+    /// written by a newer version of this crate.
     pub fn new_from_path<P: AsRef<Path>>(path: P) -> Result<Self, SledStorageError> {
-        let db = sled::open(path)?;
-        Ok(Self { db })
+        Self::new_from_path_with_config(path, SledStorageConfig::default())
     }
 
     /// Creates a new SledStorage instance from an existing Sled database.
     ///
+    /// Databases written by older versions of this crate are migrated to the
+    /// current schema before the instance is returned.
+    ///
     /// # Arguments
     ///
     /// * `db` - An existing Sled database instance.
     ///
     /// # Returns
     ///
-    /// A new SledStorage instance.
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::UnsupportedSchemaVersion` if the database was
+    /// written by a newer version of this crate.
     pub fn new_from_db(db: Db) -> Result<Self, SledStorageError> {
-        Ok(Self { db })
+        Self::new_from_db_with_config(db, SledStorageConfig::default())
+    }
+
+    /// Creates a new SledStorage instance from a given path, encrypting all
+    /// values at rest with the given key.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A path-like object representing the location to store the database.
+    /// * `storage_key` - The key used to encrypt and decrypt values.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::UnsupportedSchemaVersion` if the database was
+    /// written by a newer version of this crate.
+    pub fn new_from_path_with_key<P: AsRef<Path>>(
+        path: P,
+        storage_key: StorageKey,
+    ) -> Result<Self, SledStorageError> {
+        Self::new_from_path_with_config(
+            path,
+            SledStorageConfig {
+                encryption: Encryption::Key(storage_key),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Creates a new SledStorage instance from an existing Sled database,
+    /// encrypting all values at rest with the given key.
+    ///
+    /// Values are only readable with the key they were written with. Reading
+    /// a value that was written without encryption, or with another key,
+    /// fails with `SledStorageError::DecryptionError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - An existing Sled database instance.
+    /// * `storage_key` - The key used to encrypt and decrypt values.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::UnsupportedSchemaVersion` if the database was
+    /// written by a newer version of this crate.
+    pub fn new_from_db_with_key(db: Db, storage_key: StorageKey) -> Result<Self, SledStorageError> {
+        Self::new_from_db_with_config(
+            db,
+            SledStorageConfig {
+                encryption: Encryption::Key(storage_key),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Opens the database at the given path, encrypting all values at rest with
+    /// a key derived from a passphrase.
+    ///
+    /// The first time a database is opened with a passphrase, a random salt and
+    /// the key derivation parameters are stored alongside the data. The
+    /// passphrase should be set up on a new database; values already written
+    /// without it cannot be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A path-like object representing the location to store the database.
+    /// * `passphrase` - The passphrase the storage key is derived from.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::WrongPassphrase` if the database was set up
+    /// with a different passphrase, `SledStorageError::InvalidConfig` if the
+    /// database already holds data but was not set up with a passphrase, or
+    /// `SledStorageError::UnsupportedSchemaVersion` if the database was written
+    /// by a newer version of this crate.
+    pub fn open_with_passphrase<P: AsRef<Path>>(
+        path: P,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, SledStorageError> {
+        Self::new_from_path_with_config(
+            path,
+            SledStorageConfig {
+                encryption: Encryption::Passphrase(Zeroizing::new(passphrase.as_ref().to_vec())),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Creates a new SledStorage instance from an existing Sled database,
+    /// encrypting all values at rest with a key derived from a passphrase.
+    ///
+    /// See [`Self::open_with_passphrase`].
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - An existing Sled database instance.
+    /// * `passphrase` - The passphrase the storage key is derived from.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::WrongPassphrase` if the database was set up
+    /// with a different passphrase, `SledStorageError::InvalidConfig` if the
+    /// database already holds data but was not set up with a passphrase, or
+    /// `SledStorageError::UnsupportedSchemaVersion` if the database was written
+    /// by a newer version of this crate.
+    pub fn new_from_db_with_passphrase(
+        db: Db,
+        passphrase: impl AsRef<[u8]>,
+    ) -> Result<Self, SledStorageError> {
+        Self::new_from_db_with_config(
+            db,
+            SledStorageConfig {
+                encryption: Encryption::Passphrase(Zeroizing::new(passphrase.as_ref().to_vec())),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Opens the database at the given path with the given options.
+    ///
+    /// With [`SledStorageConfig::secure_delete`] an interrupted scrub is
+    /// finished or discarded first, and a pending scrub is carried out before
+    /// the instance is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A path-like object representing the location to store the database.
+    /// * `config` - The options to open the database with.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::new_from_db_with_config`].
+    pub fn new_from_path_with_config<P: AsRef<Path>>(
+        path: P,
+        config: SledStorageConfig,
+    ) -> Result<Self, SledStorageError> {
+        let path = path.as_ref();
+        if config.secure_delete {
+            secure_delete::recover(path)?;
+        }
+        let db = sled::open(path)?;
+        let mut storage = Self::new_from_db_with_config(db, config)?;
+        storage.path = Some(path.to_path_buf());
+        if storage.secure_delete && storage.scrub_pending()? {
+            storage.scrub()?;
+        }
+        Ok(storage)
+    }
+
+    /// Creates a new SledStorage instance from an existing Sled database with
+    /// the given options.
+    ///
+    /// Databases written by older versions of this crate are migrated to the
+    /// current schema before the instance is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `db` - An existing Sled database instance.
+    /// * `config` - The options to open the database with.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the new SledStorage instance or a SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::WrongPassphrase` if the database was set up
+    /// with a different passphrase, `SledStorageError::RekeyInProgress` if a
+    /// rekey was interrupted and `resume_rekey` is not set,
+    /// `SledStorageError::InvalidConfig` if hashed lookup keys or a passphrase
+    /// are requested for a database that already holds data, or hashed lookup
+    /// keys without encryption, `SledStorageError::DecryptionError` if the
+    /// database uses hashed lookup keys but is opened without its storage key,
+    /// or `SledStorageError::UnsupportedSchemaVersion` if the database was
+    /// written by a newer version of this crate.
+    pub fn new_from_db_with_config(
+        db: Db,
+        config: SledStorageConfig,
+    ) -> Result<Self, SledStorageError> {
+        if !config.resume_rekey && rekey::in_progress(&db)? {
+            return Err(SledStorageError::RekeyInProgress);
+        }
+        let storage_key = match config.encryption {
+            Encryption::None => None,
+            Encryption::Key(storage_key) => Some(storage_key),
+            Encryption::Passphrase(passphrase) => Some(passphrase::unlock(&db, &passphrase)?),
+        };
+        migrations::migrate(&db)?;
+        let key_hasher = keys::load(&db, storage_key.as_ref(), config.hash_lookup_keys)?;
+        Ok(Self {
+            db,
+            batch: None,
+            storage_key,
+            key_hasher,
+            path: None,
+            secure_delete: config.secure_delete,
+            key_package_lifetime: config.key_package_lifetime,
+            dangling_proposal_refs: config.dangling_proposal_refs,
+            handles: Arc::new(()),
+        })
+    }
+
+    /// Returns `true` if values are encrypted at rest.
+    pub fn is_encrypted(&self) -> bool {
+        self.storage_key.is_some()
+    }
+
+    /// Returns the schema version recorded in the database.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the schema version or a `SledStorageError`.
+    pub fn schema_version(&self) -> Result<u32, SledStorageError> {
+        migrations::schema_version(&self.db)?.ok_or_else(SledStorageError::not_found)
     }
 
     /// Flushes the database, ensuring all pending writes are persisted to disk.
@@ -75,7 +355,8 @@ impl SledStorage {
     /// Deletes all data from the storage.
     ///
     /// This method clears all trees defined in the `TREES` constant,
-    /// as well as the main database.
+    /// as well as the main database. Storage metadata such as the schema
+    /// version is kept.
     ///
     /// # Returns
     ///
@@ -90,8 +371,17 @@ impl SledStorage {
         let start = Instant::now();
         tracing::debug!(target: "openmls_sled_storage::delete_all_data", "Deleting all data");
 
+        if self.secure_delete {
+            self.db
+                .open_tree(META_TREE)?
+                .insert(secure_delete::SCRUB_PENDING_KEY, Vec::new())?;
+        }
+
         let trees = self.db.tree_names();
         for tree in trees {
+            if tree == META_TREE {
+                continue;
+            }
             let tree_ref = self.db.open_tree(&tree)?;
             tree_ref.clear()?;
             drop(tree_ref); // Explicitly drop the reference
@@ -127,22 +417,63 @@ impl SledStorage {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
+        error::in_context(Operation::Write, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
 
-        tracing::debug!(target: "openmls_sled_storage", "Writing to key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+            tracing::debug!(target: "openmls_sled_storage", "Writing to key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
 
-        // Serialize the value before storing
-        let serialized_value =
-            serde_json::to_vec(&value).map_err(|_| SledStorageError::SerializationError)?;
+            let value = Zeroizing::new(value);
+            let value = self.encode_value(tree, key, &value)?;
+            if let Some(mut pending) = self.pending() {
+                pending.put(&active_tree.name(), key, value);
+                return Ok(());
+            }
 
-        match active_tree.insert(key, serialized_value) {
-            Ok(_res) => Ok(()),
-            Err(e) => Err(SledStorageError::SledError(e)),
-        }
+            active_tree.insert(key, value)?;
+            Ok(())
+        })
+    }
+
+    /// Writes a complete list of values to the storage with the given tree and key,
+    /// replacing any list stored there.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The tree for the storage entry. A Tree in Sled represents a single logical keyspace / namespace / bucket.
+    /// * `key` - The key for the storage entry.
+    /// * `values` - The values to be stored, in order. They are wiped once written.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `VERSION` - The version of the storage format.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or a SledStorageError.
+    fn write_list<const VERSION: u16>(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+        values: Vec<Vec<u8>>,
+    ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
+        error::in_context(Operation::WriteList, tree, key, || {
+            tracing::debug!(target: "openmls_sled_storage", "Writing list to key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+
+            self.atomically(|storage| {
+                storage.delete_list::<VERSION>(tree, key)?;
+                for value in values {
+                    storage.append::<VERSION>(tree, key, value)?;
+                }
+                Ok(())
+            })
+        })
     }
 
     /// Appends a value to a list stored at the given label and key.
     ///
+    /// Every list item is stored under a key of its own, so appending never
+    /// touches the items already in the list.
+    ///
     /// # Arguments
     ///
     /// * `tree` - The tree for the storage entry. A Tree in Sled represents a single logical keyspace / namespace / bucket.
@@ -162,26 +493,24 @@ impl SledStorage {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
-
-        tracing::debug!(target: "openmls_sled_storage", "Appending to key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
-
-        let list_bytes = active_tree.get(key)?;
-        let mut list: Vec<Vec<u8>> = Vec::new();
-        if let Some(list_bytes) = list_bytes {
-            list = serde_json::from_slice(&list_bytes)
-                .map_err(|_e| SledStorageError::SerializationError)?;
-        }
-
-        list.push(value);
-
-        let updated_list_bytes =
-            serde_json::to_vec(&list).map_err(|_e| SledStorageError::SerializationError)?;
+        error::in_context(Operation::Append, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
+
+            tracing::debug!(target: "openmls_sled_storage", "Appending to key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+
+            // Ids are handed out in increasing order, which keeps the items in the
+            // order they were appended.
+            let value = Zeroizing::new(value);
+            let item_key = helpers::list_item_key(key, self.db.generate_id()?);
+            let value = self.encode_value(tree, &item_key, &value)?;
+            if let Some(mut pending) = self.pending() {
+                pending.put(&active_tree.name(), &item_key, value);
+                return Ok(());
+            }
 
-        match active_tree.insert(key, updated_list_bytes) {
-            Ok(_res) => Ok(()),
-            Err(e) => Err(SledStorageError::SledError(e)),
-        }
+            active_tree.insert(item_key, value)?;
+            Ok(())
+        })
     }
 
     /// Reads a value from the storage with the given label and key.
@@ -205,18 +534,19 @@ impl SledStorage {
         tree: &[u8],
         key: &[u8],
     ) -> Result<Option<V>, <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
+        error::in_context(Operation::Read, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
 
-        tracing::debug!(target: "openmls_sled_storage", "Reading key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+            tracing::debug!(target: "openmls_sled_storage", "Reading key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
 
-        match active_tree.get(key) {
-            Ok(None) => Ok(None),
-            Ok(Some(value)) => {
-                let deserialized: Vec<u8> = serde_json::from_slice(&value)?;
-                Ok(Some(serde_json::from_slice(&deserialized)?))
+            match self.get(&active_tree, key)? {
+                None => Ok(None),
+                Some(value) => {
+                    let entity = self.decode_value(tree, key, &value)?;
+                    Ok(Some(helpers::from_json(&entity)?))
+                }
             }
-            Err(e) => Err(SledStorageError::SledError(e)),
-        }
+        })
     }
 
     /// Reads a list of entities from the storage with the given label and key.
@@ -240,21 +570,19 @@ impl SledStorage {
         tree: &[u8],
         key: &[u8],
     ) -> Result<Vec<V>, <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
-
-        tracing::debug!(target: "openmls_sled_storage", "Reading list from key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
-
-        let value: Vec<Vec<u8>> = match active_tree.get(key) {
-            Ok(Some(list_bytes)) => serde_json::from_slice(&list_bytes)?,
-            Ok(None) => vec![],
-            Err(e) => return Err(SledStorageError::SledError(e)),
-        };
-
-        value
-            .iter()
-            .map(|value_bytes| serde_json::from_slice(value_bytes))
-            .collect::<Result<Vec<V>, _>>()
-            .map_err(|_| SledStorageError::SerializationError)
+        error::in_context(Operation::ReadList, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
+
+            tracing::debug!(target: "openmls_sled_storage", "Reading list from key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+
+            self.scan_list(&active_tree, key)?
+                .iter()
+                .map(|(item_key, value)| {
+                    let entity = self.decode_value(tree, item_key, value)?;
+                    helpers::from_json(&entity)
+                })
+                .collect()
+        })
     }
 
     /// Removes a specific item from a list stored at the given label and key.
@@ -278,37 +606,85 @@ impl SledStorage {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
+        error::in_context(Operation::RemoveItem, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
+
+            tracing::debug!(target: "openmls_sled_storage", "Removing item from key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+
+            loop {
+                // find the first occurrence of the value
+                let mut found = None;
+                for (item_key, item) in self.scan_list(&active_tree, key)? {
+                    if *self.decode_value(tree, &item_key, &item)? == value {
+                        found = Some((item_key, item));
+                        break;
+                    }
+                }
+                let Some((item_key, item)) = found else {
+                    return Ok(());
+                };
+
+                if let Some(mut pending) = self.pending() {
+                    pending.remove(&active_tree.name(), &item_key);
+                    return Ok(());
+                }
+
+                // Only remove the item if nobody else removed it in the meantime,
+                // otherwise look for the next occurrence.
+                if active_tree
+                    .compare_and_swap(&item_key, Some(item), None::<&[u8]>)?
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+        })
+    }
 
-        tracing::debug!(target: "openmls_sled_storage", "Removing item from key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+    /// Deletes an entry from the storage with the given label and key.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The tree for the storage entry. A Tree in Sled represents a single logical keyspace / namespace / bucket.
+    /// * `key` - The key for the storage entry.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `VERSION` - The version of the storage format.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success or a SledStorageError.
+    #[inline(always)]
+    fn delete<const VERSION: u16>(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+    ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
+        error::in_context(Operation::Delete, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
 
-        // fetch value from db, if we don't have a list, we're done
-        let list = match active_tree.get(key) {
-            Ok(Some(list)) => list,
-            Ok(None) => return Ok(()),
-            Err(e) => return Err(SledStorageError::SledError(e)),
-        };
+            tracing::debug!(target: "openmls_sled_storage", "Deleting key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
 
-        // parse old value, find value to delete and remove it from list
-        let mut parsed_list: Vec<Vec<u8>> = serde_json::from_slice(&list)?;
-        if let Some(pos) = parsed_list
-            .iter()
-            .position(|stored_item| stored_item == &value)
-        {
-            parsed_list.remove(pos);
-        }
+            let secure = self.deletes_securely(tree);
+            if secure {
+                self.prepare_secure_delete(&active_tree, key)?;
+            }
 
-        // write back, reusing the old buffer
-        let updated_list_bytes =
-            serde_json::to_vec(&parsed_list).map_err(|_e| SledStorageError::SerializationError)?;
+            if let Some(mut pending) = self.pending() {
+                pending.remove(&active_tree.name(), key);
+                return Ok(());
+            }
 
-        match active_tree.insert(key, updated_list_bytes) {
-            Ok(_res) => Ok(()),
-            Err(e) => Err(SledStorageError::SledError(e)),
-        }
+            active_tree.remove(key)?;
+            if secure {
+                self.db.flush()?;
+            }
+            Ok(())
+        })
     }
 
-    /// Deletes an entry from the storage with the given label and key.
+    /// Deletes a list and all of its items from the storage.
     ///
     /// # Arguments
     ///
@@ -322,20 +698,284 @@ impl SledStorage {
     /// # Returns
     ///
     /// A Result indicating success or a SledStorageError.
-    #[inline(always)]
-    fn delete<const VERSION: u16>(
+    fn delete_list<const VERSION: u16>(
         &self,
         tree: &[u8],
         key: &[u8],
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let active_tree = self.db.open_tree(tree)?;
+        error::in_context(Operation::DeleteList, tree, key, || {
+            let active_tree = self.open_tree::<VERSION>(tree)?;
+
+            tracing::debug!(target: "openmls_sled_storage", "Deleting list at key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+
+            self.atomically(|storage| {
+                for (item_key, _) in storage.scan_list(&active_tree, key)? {
+                    storage.delete::<VERSION>(tree, &item_key)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Gets the keys and raw values of all items of a list, in order, taking
+    /// pending batch writes into account.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree for the storage entry.
+    /// * `key` - The key of the list.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the item keys and encoded items, or a SledStorageError.
+    fn scan_list(
+        &self,
+        active_tree: &sled::Tree,
+        key: &[u8],
+    ) -> Result<Vec<(IVec, IVec)>, SledStorageError> {
+        self.scan_prefix(active_tree, &helpers::list_prefix(key))
+    }
+
+    /// Gets all keys starting with a prefix and their raw values, in order,
+    /// taking pending batch writes into account.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree for the storage entry.
+    /// * `prefix` - The prefix of the keys.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the keys and encoded values, or a SledStorageError.
+    fn scan_prefix(
+        &self,
+        active_tree: &sled::Tree,
+        prefix: &[u8],
+    ) -> Result<Vec<(IVec, IVec)>, SledStorageError> {
+        let mut entries = active_tree
+            .scan_prefix(prefix)
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        if let Some(pending) = self.pending() {
+            pending.resolve_prefix(&active_tree.name(), prefix, &mut entries);
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Gets all keys starting with a prefix, in order, taking pending batch
+    /// writes into account.
+    ///
+    /// Unlike [`Self::scan_prefix`] the values are neither copied nor
+    /// decoded.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree for the storage entry.
+    /// * `prefix` - The prefix of the keys.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the keys, or a SledStorageError.
+    fn scan_keys(
+        &self,
+        active_tree: &sled::Tree,
+        prefix: &[u8],
+    ) -> Result<Vec<IVec>, SledStorageError> {
+        let mut keys = active_tree
+            .scan_prefix(prefix)
+            .keys()
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        if let Some(pending) = self.pending() {
+            pending.resolve_prefix_keys(&active_tree.name(), prefix, &mut keys);
+        }
+        Ok(keys.into_iter().collect())
+    }
 
-        tracing::debug!(target: "openmls_sled_storage", "Deleting key: {:#?} in tree: {:#?}", hex::encode(key), hex::encode(tree));
+    /// Calls `f` with every key starting with `prefix` and its raw value,
+    /// taking pending batch writes into account.
+    ///
+    /// Unlike [`Self::scan_prefix`] the entries are not collected, so memory
+    /// use does not grow with the size of the tree. Stored entries are visited
+    /// in order, followed by the entries only written in the pending batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree for the storage entry.
+    /// * `prefix` - The prefix of the keys.
+    /// * `f` - Called with each key and encoded value.
+    ///
+    /// # Returns
+    ///
+    /// A Result indicating success, or the first SledStorageError returned by
+    /// sled or `f`.
+    fn for_each_prefixed(
+        &self,
+        active_tree: &sled::Tree,
+        prefix: &[u8],
+        mut f: impl FnMut(&[u8], &[u8]) -> Result<(), SledStorageError>,
+    ) -> Result<(), SledStorageError> {
+        let writes = self
+            .pending()
+            .map(|pending| pending.writes_with_prefix(&active_tree.name(), prefix))
+            .unwrap_or_default();
+        let written = writes
+            .iter()
+            .map(|(key, _)| key.as_slice())
+            .collect::<BTreeSet<_>>();
 
-        match active_tree.remove(key) {
-            Ok(_res) => Ok(()),
-            Err(e) => Err(SledStorageError::SledError(e)),
+        for entry in active_tree.scan_prefix(prefix) {
+            let (key, value) = entry?;
+            if !written.contains(&key[..]) {
+                f(&key, &value)?;
+            }
+        }
+        for (key, value) in &writes {
+            if let Some(value) = value {
+                f(key, value)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Encodes an entity for storage, encrypting it if encryption is enabled.
+    ///
+    /// The plaintext envelope is wiped after encryption.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The name of the tree the value will be stored in.
+    /// * `key` - The key the value will be stored under.
+    /// * `entity` - The serialized entity.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the bytes to be stored or a SledStorageError.
+    fn encode_value(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+        entity: &[u8],
+    ) -> Result<Vec<u8>, SledStorageError> {
+        match &self.storage_key {
+            Some(storage_key) => {
+                let value = Zeroizing::new(codec::encode_entity(entity));
+                encryption::encrypt(storage_key, tree, key, &value)
+                    .map_err(|error| error.in_entry(tree, key))
+            }
+            None => Ok(codec::encode_entity(entity)),
+        }
+    }
+
+    /// Decodes a stored value written with [`Self::encode_value`].
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The name of the tree the value was read from.
+    /// * `key` - The key the value was read from.
+    /// * `stored` - The bytes read from sled.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the serialized entity, wiped when dropped, or a
+    /// SledStorageError.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::Corrupted` if the value is not an envelope
+    /// of the current format version or its checksum does not match.
+    fn decode_value(
+        &self,
+        tree: &[u8],
+        key: &[u8],
+        stored: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, SledStorageError> {
+        let in_entry = |error: SledStorageError| error.in_entry(tree, key);
+        if !codec::checksum_matches(stored) {
+            return Err(in_entry(SledStorageError::corrupted()));
+        }
+        let entity = match &self.storage_key {
+            Some(storage_key) => {
+                let value =
+                    encryption::decrypt(storage_key, tree, key, stored).map_err(in_entry)?;
+                codec::decode_entity(&value).map_err(in_entry)?.to_vec()
+            }
+            None if codec::is_encrypted(stored) => {
+                return Err(in_entry(SledStorageError::decryption()))
+            }
+            // Every value is an entity envelope, so anything else has been
+            // damaged.
+            None => codec::decode_entity(stored)
+                .map_err(|_| in_entry(SledStorageError::corrupted()))?
+                .to_vec(),
+        };
+        Ok(Zeroizing::new(entity))
+    }
+
+    /// Gets the raw value of a key, taking pending batch writes into account.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree for the storage entry.
+    /// * `key` - The key for the storage entry.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the encoded value, if any, or a SledStorageError.
+    fn get(&self, active_tree: &sled::Tree, key: &[u8]) -> Result<Option<IVec>, SledStorageError> {
+        let stored = active_tree.get(key)?;
+        match self.pending() {
+            Some(pending) => pending.resolve(&active_tree.name(), key, stored),
+            None => Ok(stored),
+        }
+    }
+
+    /// Opens the sled tree holding the entries of `tree` for version
+    /// `VERSION` of the storage traits.
+    ///
+    /// Every version has trees of its own, see
+    /// [`helpers::versioned_tree`].
+    ///
+    /// # Arguments
+    ///
+    /// * `tree` - The name of the tree.
+    ///
+    /// # Type Parameters
+    ///
+    /// * `VERSION` - The version of the storage format.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the opened tree or a sled error.
+    pub(crate) fn open_tree<const VERSION: u16>(
+        &self,
+        tree: &[u8],
+    ) -> Result<sled::Tree, sled::Error> {
+        self.db.open_tree(helpers::versioned_tree::<VERSION>(tree))
+    }
+
+    /// Runs a transaction across the given trees.
+    ///
+    /// All writes made by `f` are applied atomically: either every tree sees
+    /// them or none does. `f` may be called more than once if the transaction
+    /// conflicts with a concurrent one.
+    ///
+    /// # Arguments
+    ///
+    /// * `trees` - The trees taking part in the transaction. `f` receives them in the same order.
+    /// * `f` - The body of the transaction.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the value returned by `f` or a SledStorageError.
+    fn transaction<A, F>(&self, trees: &[&[u8]], f: F) -> Result<A, SledStorageError>
+    where
+        F: Fn(&[TransactionalTree]) -> ConflictableTransactionResult<A, SledStorageError>,
+    {
+        let trees = trees
+            .iter()
+            .map(|tree| self.db.open_tree(tree))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(trees.as_slice().transaction(|trees| f(trees))?)
     }
 }
 
@@ -372,7 +1012,13 @@ mod tests {
         let dir = tempdir().unwrap();
         let storage = SledStorage::new_from_path(dir.path());
         assert!(storage.is_ok());
-        assert!(storage.unwrap().db.tree_names().len() == 1);
+        let storage = storage.unwrap();
+        // The default tree and the metadata tree
+        assert!(storage.db.tree_names().len() == 2);
+        assert_eq!(
+            storage.schema_version().unwrap(),
+            migrations::SCHEMA_VERSION
+        );
     }
 
     #[test]
@@ -380,7 +1026,13 @@ mod tests {
         let db = sled::open(tempdir().unwrap()).unwrap();
         let storage = SledStorage::new_from_db(db);
         assert!(storage.is_ok());
-        assert!(storage.unwrap().db.tree_names().len() == 1);
+        let storage = storage.unwrap();
+        // The default tree and the metadata tree
+        assert!(storage.db.tree_names().len() == 2);
+        assert_eq!(
+            storage.schema_version().unwrap(),
+            migrations::SCHEMA_VERSION
+        );
     }
 
     #[test]
@@ -402,6 +1054,134 @@ mod tests {
         assert_eq!(read_result.unwrap(), Some(value));
     }
 
+    #[test]
+    fn test_write_stores_envelope() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let value = TestEntity {
+            data: "test_data".to_string(),
+        };
+        let entity_bytes = serde_json::to_vec(&value).unwrap();
+
+        storage
+            .write::<CURRENT_VERSION>(tree, key, entity_bytes.clone())
+            .unwrap();
+
+        // The raw entity bytes follow the header without any re-encoding.
+        let stored = storage
+            .open_tree::<CURRENT_VERSION>(tree)
+            .unwrap()
+            .get(key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&stored[..2], &codec::MAGIC);
+        assert_eq!(&stored[codec::HEADER_LEN..], entity_bytes.as_slice());
+    }
+
+    #[test]
+    fn test_corrupted_value_is_detected() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let value = TestEntity {
+            data: "test_data".to_string(),
+        };
+        storage
+            .write::<CURRENT_VERSION>(tree, key, serde_json::to_vec(&value).unwrap())
+            .unwrap();
+
+        // Flip a bit in the payload, leaving valid JSON behind.
+        let active_tree = storage.open_tree::<CURRENT_VERSION>(tree).unwrap();
+        let mut stored = active_tree.get(key).unwrap().unwrap().to_vec();
+        let position = stored.iter().rposition(|&b| b == b'a').unwrap();
+        stored[position] ^= 0x02;
+        active_tree.insert(key, stored).unwrap();
+
+        assert_eq!(
+            storage.read::<CURRENT_VERSION, TestEntity>(tree, key),
+            Err(SledStorageError::Corrupted {
+                context: ErrorContext {
+                    operation: Some(Operation::Read),
+                    tree: Some("test_tree".to_string()),
+                    key: Some(KeyFingerprint::of(key)),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_unchecked_envelope_is_corrupted() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let value = TestEntity {
+            data: "test_data".to_string(),
+        };
+        storage
+            .write::<CURRENT_VERSION>(tree, key, serde_json::to_vec(&value).unwrap())
+            .unwrap();
+
+        // A flipped version byte turns the header into one without a checksum.
+        let active_tree = storage.open_tree::<CURRENT_VERSION>(tree).unwrap();
+        let mut stored = active_tree.get(key).unwrap().unwrap().to_vec();
+        stored[2] = codec::FORMAT_VERSION_UNCHECKED;
+        active_tree.insert(key, stored).unwrap();
+
+        assert!(matches!(
+            storage.read::<CURRENT_VERSION, TestEntity>(tree, key),
+            Err(SledStorageError::Corrupted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_undecodable_value_is_reported_with_context() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        storage
+            .write::<CURRENT_VERSION>(tree, key, serde_json::to_vec("not an entity").unwrap())
+            .unwrap();
+
+        let error = storage
+            .read::<CURRENT_VERSION, TestEntity>(tree, key)
+            .unwrap_err();
+        let SledStorageError::DecodeError { context, source } = &error else {
+            panic!("unexpected error: {error:?}");
+        };
+        assert_eq!(context.operation, Some(Operation::Read));
+        assert_eq!(context.tree.as_deref(), Some("test_tree"));
+        assert!(source.is_some());
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn test_write_list_and_read_list() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let values = vec![
+            TestEntity {
+                data: "data1".to_string(),
+            },
+            TestEntity {
+                data: "data2".to_string(),
+            },
+        ];
+        let serialized: Vec<Vec<u8>> = values
+            .iter()
+            .map(|value| serde_json::to_vec(value).unwrap())
+            .collect();
+
+        storage
+            .write_list::<CURRENT_VERSION>(tree, key, serialized)
+            .unwrap();
+
+        let read_result: Vec<TestEntity> =
+            storage.read_list::<CURRENT_VERSION, _>(tree, key).unwrap();
+        assert_eq!(read_result, values);
+    }
+
     #[test]
     fn test_append_and_read_list() {
         let storage = setup_storage();
@@ -428,6 +1208,52 @@ mod tests {
         assert_eq!(read_result.unwrap(), values);
     }
 
+    #[test]
+    fn test_append_stores_items_under_own_keys() {
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let item = |i: usize| {
+            serde_json::to_vec(&TestEntity {
+                data: format!("data{i}"),
+            })
+            .unwrap()
+        };
+
+        storage
+            .append::<CURRENT_VERSION>(tree, key, item(0))
+            .unwrap();
+        let active_tree = storage.open_tree::<CURRENT_VERSION>(tree).unwrap();
+        let (first_key, first_item) = active_tree.first().unwrap().unwrap();
+
+        for i in 1..10 {
+            storage
+                .append::<CURRENT_VERSION>(tree, key, item(i))
+                .unwrap();
+        }
+
+        // Every item has a key of its own, and appending left the first one untouched.
+        assert_eq!(active_tree.len(), 10);
+        assert!(active_tree
+            .iter()
+            .keys()
+            .all(|item_key| item_key.unwrap().starts_with(&helpers::list_prefix(key))));
+        assert_eq!(active_tree.get(&first_key).unwrap(), Some(first_item));
+
+        // Lists stored at other keys are not affected.
+        storage
+            .append::<CURRENT_VERSION>(tree, b"test_key2", item(10))
+            .unwrap();
+        storage.delete_list::<CURRENT_VERSION>(tree, key).unwrap();
+        let read_result: Vec<TestEntity> =
+            storage.read_list::<CURRENT_VERSION, _>(tree, key).unwrap();
+        assert!(read_result.is_empty());
+        let read_result: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(tree, b"test_key2")
+            .unwrap();
+        assert_eq!(read_result.len(), 1);
+    }
+
     #[test]
     fn test_remove_item() {
         let storage = setup_storage();
@@ -461,6 +1287,72 @@ mod tests {
         assert_eq!(read_result.unwrap(), vec![values[1].clone()]);
     }
 
+    #[test]
+    fn test_concurrent_append_and_remove_item() {
+        const THREADS: usize = 8;
+        const ITEMS: usize = 50;
+
+        let storage = setup_storage();
+        let tree = b"test_tree";
+        let key = b"test_key";
+        let item = |thread: usize, i: usize| {
+            serde_json::to_vec(&TestEntity {
+                data: format!("{thread}-{i}"),
+            })
+            .unwrap()
+        };
+
+        std::thread::scope(|s| {
+            for thread in 0..THREADS {
+                let storage = &storage;
+                s.spawn(move || {
+                    for i in 0..ITEMS {
+                        storage
+                            .append::<CURRENT_VERSION>(tree, key, item(thread, i))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let read_result: Vec<TestEntity> =
+            storage.read_list::<CURRENT_VERSION, _>(tree, key).unwrap();
+        assert_eq!(read_result.len(), THREADS * ITEMS);
+
+        // Every thread removes its even items while the others do the same.
+        std::thread::scope(|s| {
+            for thread in 0..THREADS {
+                let storage = &storage;
+                s.spawn(move || {
+                    for i in (0..ITEMS).step_by(2) {
+                        storage
+                            .remove_item::<CURRENT_VERSION>(tree, key, item(thread, i))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let read_result: Vec<TestEntity> =
+            storage.read_list::<CURRENT_VERSION, _>(tree, key).unwrap();
+        assert_eq!(read_result.len(), THREADS * ITEMS / 2);
+        for thread in 0..THREADS {
+            // Items of a single thread keep their relative order.
+            let expected: Vec<TestEntity> = (1..ITEMS)
+                .step_by(2)
+                .map(|i| TestEntity {
+                    data: format!("{thread}-{i}"),
+                })
+                .collect();
+            let actual: Vec<TestEntity> = read_result
+                .iter()
+                .filter(|entity| entity.data.starts_with(&format!("{thread}-")))
+                .cloned()
+                .collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
     #[test]
     fn test_delete() {
         let storage = setup_storage();
@@ -559,7 +1451,16 @@ mod tests {
 
         // Verify trees are gone
         for tree in &trees {
-            assert!(storage.db.open_tree(tree).unwrap().is_empty());
+            assert!(storage
+                .open_tree::<CURRENT_VERSION>(*tree)
+                .unwrap()
+                .is_empty());
         }
+
+        // Verify the schema version survives
+        assert_eq!(
+            storage.schema_version().unwrap(),
+            migrations::SCHEMA_VERSION
+        );
     }
 }
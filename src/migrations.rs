@@ -0,0 +1,658 @@
+//! Schema versioning and migrations.
+//!
+//! The schema version of a database is recorded in [`META_TREE`]. When a
+//! [`SledStorage`](crate::SledStorage) is opened, every registered migration
+//! newer than the recorded version is applied in order. Migrations rewrite the
+//! trees in [`TREES`] in batches, and the position reached is persisted in the
+//! same transaction as each batch of rewritten entries, so an interrupted
+//! migration picks up where it stopped the next time the database is opened.
+//!
+//! Up to schema version 3 the trees were not namespaced by the version of the
+//! storage traits. Migrations up to that version rewrite the trees under their
+//! plain names; version 4 then moves every entry to the tree of
+//! [`CURRENT_VERSION`], see [`helpers::versioned_tree`].
+
+use std::ops::Bound;
+
+use openmls_traits::storage::CURRENT_VERSION;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use sled::Db;
+
+use crate::traits::{EPOCH_KEY_PAIRS_TREE, OWN_LEAF_NODES_TREE, PROPOSAL_QUEUE_REFS_TREE, TREES};
+use crate::SledStorageError;
+use crate::{codec, helpers};
+
+/// Name of the tree holding storage metadata such as the schema version.
+pub const META_TREE: &[u8] = b"__openmls_sled_storage_meta";
+
+/// The schema version written by this version of the crate.
+pub const SCHEMA_VERSION: u32 = 4;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+const MIGRATION_PROGRESS_KEY: &[u8] = b"migration_progress";
+
+/// Number of entries rewritten per transaction.
+const BATCH_SIZE: usize = 512;
+
+/// Writes produced by a migration for a single entry. Each write replaces
+/// (`Some`) or removes (`None`) a key in the tree the entry was read from.
+type Rewrite = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// Rewrites a single entry of the given tree.
+type RewriteFn =
+    fn(db: &Db, tree: &[u8], key: &[u8], value: &[u8]) -> Result<Rewrite, SledStorageError>;
+
+/// Returns the tree an unversioned tree is moved to.
+type RenameFn = fn(tree: &[u8]) -> Vec<u8>;
+
+/// A single step in the schema history.
+struct Migration {
+    /// The schema version of the database once this migration has completed.
+    version: u32,
+    /// Short summary, used for logging.
+    description: &'static str,
+    /// What the migration does.
+    step: Step,
+}
+
+/// What a migration does to the trees in [`TREES`].
+enum Step {
+    /// Rewrites every entry in place.
+    ///
+    /// Because migrations can be interrupted and resumed, an entry may be
+    /// visited more than once. Entries that are already in the target layout
+    /// must produce no writes.
+    Rewrite(RewriteFn),
+    /// Moves every entry to another tree, and drops the emptied tree.
+    Move(RenameFn),
+}
+
+/// All migrations, ordered by version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "wrap values in the binary envelope",
+        step: Step::Rewrite(wrap_in_envelope),
+    },
+    Migration {
+        version: 2,
+        description: "store list items under individual keys",
+        step: Step::Rewrite(split_lists),
+    },
+    Migration {
+        version: 3,
+        description: "add checksums to values",
+        step: Step::Rewrite(add_checksums),
+    },
+    Migration {
+        version: 4,
+        description: "namespace trees by storage version",
+        step: Step::Move(helpers::versioned_tree::<CURRENT_VERSION>),
+    },
+];
+
+/// Position of an in-flight migration.
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    version: u32,
+    tree_index: usize,
+    last_key: Option<Vec<u8>>,
+}
+
+/// Reads the schema version recorded in the database, if any.
+///
+/// # Arguments
+///
+/// * `db` - The Sled database.
+///
+/// # Returns
+///
+/// A `Result` containing the schema version, or `None` if the database has never
+/// been opened by a version of this crate that records it.
+pub fn schema_version(db: &Db) -> Result<Option<u32>, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => {
+            let bytes: [u8; 4] = bytes
+                .as_ref()
+                .try_into()
+                .map_err(|_| SledStorageError::malformed())?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`].
+///
+/// A database without any MLS state is stamped with the current version. A
+/// database with state but no recorded version predates schema versioning and
+/// is treated as version 0.
+///
+/// # Errors
+///
+/// Returns [`SledStorageError::UnsupportedSchemaVersion`] if the database was
+/// written by a newer version of this crate.
+pub(crate) fn migrate(db: &Db) -> Result<(), SledStorageError> {
+    migrate_in_batches(db, BATCH_SIZE)
+}
+
+fn migrate_in_batches(db: &Db, batch_size: usize) -> Result<(), SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+
+    let version = match schema_version(db)? {
+        Some(version) => version,
+        None if !has_mls_state(db)? => {
+            meta.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_be_bytes())?;
+            return Ok(());
+        }
+        None => 0,
+    };
+
+    if version > SCHEMA_VERSION {
+        return Err(SledStorageError::UnsupportedSchemaVersion {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        tracing::info!(target: "openmls_sled_storage::migrations", "Migrating to schema version {}: {}", migration.version, migration.description);
+        match migration.step {
+            Step::Rewrite(rewrite) => run(db, &meta, migration.version, rewrite, batch_size)?,
+            Step::Move(rename) => move_trees(db, rename, batch_size)?,
+        }
+        meta.transaction(|meta| {
+            meta.insert(SCHEMA_VERSION_KEY, &migration.version.to_be_bytes())?;
+            meta.remove(MIGRATION_PROGRESS_KEY)?;
+            Ok::<_, ConflictableTransactionError<SledStorageError>>(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if any of the trees in `TREES` holds an entry, either under
+/// its plain name or in the namespace of the current version.
+pub(crate) fn has_mls_state(db: &Db) -> Result<bool, SledStorageError> {
+    for name in db.tree_names() {
+        let is_mls_tree = TREES
+            .iter()
+            .any(|tree| name == tree || name == helpers::versioned_tree::<CURRENT_VERSION>(tree));
+        if is_mls_tree && !db.open_tree(&name)?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn read_progress(meta: &sled::Tree) -> Result<Option<Progress>, SledStorageError> {
+    match meta.get(MIGRATION_PROGRESS_KEY)? {
+        Some(bytes) => Ok(Some(helpers::from_json(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Applies a rewriting migration, resuming from any persisted progress.
+fn run(
+    db: &Db,
+    meta: &sled::Tree,
+    version: u32,
+    rewrite: RewriteFn,
+    batch_size: usize,
+) -> Result<(), SledStorageError> {
+    let mut progress = match read_progress(meta)? {
+        Some(progress) if progress.version == version => progress,
+        _ => Progress {
+            version,
+            tree_index: 0,
+            last_key: None,
+        },
+    };
+
+    let tree_names = db.tree_names();
+    while progress.tree_index < TREES.len() {
+        let name = TREES[progress.tree_index];
+        if !tree_names.iter().any(|tree_name| tree_name == name) {
+            progress.tree_index += 1;
+            continue;
+        }
+        let tree = db.open_tree(name)?;
+
+        let entries = match &progress.last_key {
+            Some(last_key) => {
+                tree.range::<&[u8], _>((Bound::Excluded(last_key.as_slice()), Bound::Unbounded))
+            }
+            None => tree.iter(),
+        };
+
+        let mut writes = Vec::new();
+        let mut last_key = None;
+        for entry in entries.take(batch_size) {
+            let (key, value) = entry?;
+            writes.extend(rewrite(db, name, &key, &value)?);
+            last_key = Some(key.to_vec());
+        }
+
+        progress = match last_key {
+            Some(last_key) => Progress {
+                last_key: Some(last_key),
+                ..progress
+            },
+            None => Progress {
+                tree_index: progress.tree_index + 1,
+                last_key: None,
+                ..progress
+            },
+        };
+        let encoded_progress = helpers::to_json(&progress)?;
+
+        (&tree, meta).transaction(|(tree, meta)| {
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                    None => tree.remove(key.as_slice())?,
+                };
+            }
+            meta.insert(MIGRATION_PROGRESS_KEY, encoded_progress.as_slice())?;
+            Ok::<_, ConflictableTransactionError<SledStorageError>>(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Moves the entries of every tree in [`TREES`] to the tree returned by
+/// `rename`, a batch per transaction.
+///
+/// Moved entries are removed in the same transaction, so an interrupted move
+/// simply continues with the entries that are left. Values are moved as they
+/// are; encrypted values stay readable because their associated data names
+/// the tree without its version.
+fn move_trees(db: &Db, rename: RenameFn, batch_size: usize) -> Result<(), SledStorageError> {
+    let tree_names = db.tree_names();
+    for name in TREES {
+        if !tree_names.iter().any(|tree_name| tree_name == name) {
+            continue;
+        }
+        let source = db.open_tree(name)?;
+        let target = db.open_tree(rename(name))?;
+
+        loop {
+            let entries = source
+                .iter()
+                .take(batch_size)
+                .collect::<Result<Vec<_>, _>>()?;
+            if entries.is_empty() {
+                break;
+            }
+            (&source, &target).transaction(|(source, target)| {
+                for (key, value) in &entries {
+                    target.insert(key, value)?;
+                    source.remove(key)?;
+                }
+                Ok::<_, ConflictableTransactionError<SledStorageError>>(())
+            })?;
+        }
+
+        db.drop_tree(name)?;
+    }
+    Ok(())
+}
+
+/// Version 1: wraps values written as JSON arrays of bytes in the binary envelope.
+fn wrap_in_envelope(
+    _db: &Db,
+    tree: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Result<Rewrite, SledStorageError> {
+    if codec::is_envelope(value) {
+        return Ok(vec![]);
+    }
+
+    let encoded = if tree == EPOCH_KEY_PAIRS_TREE {
+        // Epoch key pairs used to be written as a single entity holding a
+        // JSON array of key pairs, rather than as a list.
+        let entity = codec::decode_legacy_entity(value)?;
+        let key_pairs: Vec<serde_json::Value> = helpers::from_json(&entity)?;
+        let items = key_pairs
+            .iter()
+            .map(helpers::to_json)
+            .collect::<Result<Vec<_>, _>>()?;
+        codec::encode_list(&items)
+    } else if tree == OWN_LEAF_NODES_TREE || tree == PROPOSAL_QUEUE_REFS_TREE {
+        codec::encode_list(&codec::decode_legacy_list(value)?)
+    } else {
+        codec::encode_entity(&codec::decode_legacy_entity(value)?)
+    };
+
+    Ok(vec![(key.to_vec(), Some(encoded))])
+}
+
+/// Version 2: replaces each list value with one entry per list item.
+fn split_lists(
+    db: &Db,
+    tree: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Result<Rewrite, SledStorageError> {
+    if tree != EPOCH_KEY_PAIRS_TREE
+        && tree != OWN_LEAF_NODES_TREE
+        && tree != PROPOSAL_QUEUE_REFS_TREE
+    {
+        return Ok(vec![]);
+    }
+
+    let items = match codec::decode_list(value) {
+        Ok(items) => items,
+        // Already a list item.
+        Err(_) if codec::decode_entity(value).is_ok() => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut writes = vec![(key.to_vec(), None)];
+    for item in items {
+        let item_key = helpers::list_item_key(key, db.generate_id()?);
+        writes.push((item_key, Some(codec::encode_entity(item))));
+    }
+    Ok(writes)
+}
+
+/// Version 3: adds a checksum to each envelope of format version 1.
+///
+/// Encrypted values get a checksum on the outer envelope only, the ciphertext
+/// is authenticated already.
+fn add_checksums(
+    _db: &Db,
+    _tree: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Result<Rewrite, SledStorageError> {
+    Ok(codec::add_checksum(value)
+        .map(|checked| (key.to_vec(), Some(checked)))
+        .into_iter()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::GROUP_STATE_TREE;
+    use crate::SledStorage;
+    use openmls_traits::storage::{Entity, CURRENT_VERSION};
+    use tempfile::tempdir;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestEntity(String);
+
+    impl Entity<CURRENT_VERSION> for TestEntity {}
+    impl Entity<2> for TestEntity {}
+
+    fn legacy_entity(value: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::to_vec(value).unwrap()).unwrap()
+    }
+
+    fn open_db() -> Db {
+        sled::open(tempdir().unwrap().path()).unwrap()
+    }
+
+    fn open_versioned(db: &Db, tree: &[u8]) -> sled::Tree {
+        db.open_tree(helpers::versioned_tree::<CURRENT_VERSION>(tree))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fresh_database_is_stamped() {
+        let db = open_db();
+        migrate(&db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn test_migrates_legacy_values() {
+        let db = open_db();
+        db.open_tree(GROUP_STATE_TREE)
+            .unwrap()
+            .insert(b"group", legacy_entity("state"))
+            .unwrap();
+        let leaf_nodes = vec![
+            serde_json::to_vec("leaf1").unwrap(),
+            serde_json::to_vec("leaf2").unwrap(),
+        ];
+        db.open_tree(OWN_LEAF_NODES_TREE)
+            .unwrap()
+            .insert(b"group", serde_json::to_vec(&leaf_nodes).unwrap())
+            .unwrap();
+        let key_pairs = serde_json::to_vec(&["pair1", "pair2"]).unwrap();
+        db.open_tree(EPOCH_KEY_PAIRS_TREE)
+            .unwrap()
+            .insert(b"group", serde_json::to_vec(&key_pairs).unwrap())
+            .unwrap();
+
+        let storage = SledStorage::new_from_db(db.clone()).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+
+        let state: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(GROUP_STATE_TREE, b"group")
+            .unwrap();
+        assert_eq!(state, Some(TestEntity("state".to_string())));
+
+        let leaf_nodes: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(OWN_LEAF_NODES_TREE, b"group")
+            .unwrap();
+        assert_eq!(
+            leaf_nodes,
+            vec![
+                TestEntity("leaf1".to_string()),
+                TestEntity("leaf2".to_string())
+            ]
+        );
+
+        let key_pairs: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(EPOCH_KEY_PAIRS_TREE, b"group")
+            .unwrap();
+        assert_eq!(
+            key_pairs,
+            vec![
+                TestEntity("pair1".to_string()),
+                TestEntity("pair2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_splits_lists_into_items() {
+        let db = open_db();
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes())
+            .unwrap();
+        let tree = db.open_tree(OWN_LEAF_NODES_TREE).unwrap();
+        let leaf_nodes = vec![
+            serde_json::to_vec("leaf1").unwrap(),
+            serde_json::to_vec("leaf2").unwrap(),
+        ];
+        tree.insert(b"group", codec::encode_list(&leaf_nodes))
+            .unwrap();
+
+        let storage = SledStorage::new_from_db(db.clone()).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        let tree = open_versioned(&db, OWN_LEAF_NODES_TREE);
+        assert!(tree.get(b"group").unwrap().is_none());
+        assert_eq!(tree.scan_prefix(helpers::list_prefix(b"group")).count(), 2);
+
+        // Items appended after the migration go to the end of the list.
+        storage
+            .append::<CURRENT_VERSION>(
+                OWN_LEAF_NODES_TREE,
+                b"group",
+                serde_json::to_vec("leaf3").unwrap(),
+            )
+            .unwrap();
+        let leaf_nodes: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(OWN_LEAF_NODES_TREE, b"group")
+            .unwrap();
+        assert_eq!(
+            leaf_nodes,
+            vec![
+                TestEntity("leaf1".to_string()),
+                TestEntity("leaf2".to_string()),
+                TestEntity("leaf3".to_string())
+            ]
+        );
+
+        // Running the migration over its own output changes nothing.
+        for (key, value) in tree.iter().map(Result::unwrap) {
+            assert!(split_lists(&db, OWN_LEAF_NODES_TREE, &key, &value)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_adds_checksums() {
+        let db = open_db();
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &2u32.to_be_bytes())
+            .unwrap();
+        let tree = db.open_tree(GROUP_STATE_TREE).unwrap();
+        let entity = serde_json::to_vec("state").unwrap();
+        let unchecked = [b"OS\x01\x00".as_slice(), &entity].concat();
+        tree.insert(b"group", unchecked).unwrap();
+
+        let storage = SledStorage::new_from_db(db.clone()).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        let tree = open_versioned(&db, GROUP_STATE_TREE);
+        let stored = tree.get(b"group").unwrap().unwrap();
+        assert_eq!(stored, codec::encode_entity(&entity));
+        let state: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(GROUP_STATE_TREE, b"group")
+            .unwrap();
+        assert_eq!(state, Some(TestEntity("state".to_string())));
+
+        assert!(add_checksums(&db, GROUP_STATE_TREE, b"group", &stored)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_moves_trees_into_version_namespace() {
+        let db = open_db();
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &3u32.to_be_bytes())
+            .unwrap();
+        let tree = db.open_tree(GROUP_STATE_TREE).unwrap();
+        let target = open_versioned(&db, GROUP_STATE_TREE);
+        // Simulate a crash after the first entry was moved.
+        for i in 0u8..5 {
+            let entity = serde_json::to_vec(&format!("state{i}")).unwrap();
+            let moved = if i == 0 { &target } else { &tree };
+            moved.insert([i], codec::encode_entity(&entity)).unwrap();
+        }
+
+        migrate_in_batches(&db, 2).unwrap();
+
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        assert!(!db.tree_names().iter().any(|name| name == GROUP_STATE_TREE));
+        assert!(has_mls_state(&db).unwrap());
+        let storage = SledStorage::new_from_db(db.clone()).unwrap();
+        for i in 0u8..5 {
+            let state: Option<TestEntity> = storage
+                .read::<CURRENT_VERSION, _>(GROUP_STATE_TREE, &[i])
+                .unwrap();
+            assert_eq!(state, Some(TestEntity(format!("state{i}"))));
+        }
+
+        // Other versions have trees of their own.
+        let other: Option<TestEntity> = storage.read::<2, _>(GROUP_STATE_TREE, &[0]).unwrap();
+        assert_eq!(other, None);
+        storage
+            .write::<2>(GROUP_STATE_TREE, &[0], serde_json::to_vec("other").unwrap())
+            .unwrap();
+        let state: Option<TestEntity> = storage
+            .read::<CURRENT_VERSION, _>(GROUP_STATE_TREE, &[0])
+            .unwrap();
+        assert_eq!(state, Some(TestEntity("state0".to_string())));
+    }
+
+    #[test]
+    fn test_resumes_interrupted_migration() {
+        let db = open_db();
+        let tree = db.open_tree(GROUP_STATE_TREE).unwrap();
+        let keys: Vec<Vec<u8>> = (0u8..10).map(|i| vec![i]).collect();
+
+        // Simulate a crash after the first four entries were rewritten.
+        for key in &keys[..4] {
+            let entity = serde_json::to_vec(&format!("state{}", key[0])).unwrap();
+            tree.insert(key, codec::encode_entity(&entity)).unwrap();
+        }
+        for key in &keys[4..] {
+            tree.insert(key, legacy_entity(&format!("state{}", key[0])))
+                .unwrap();
+        }
+        let progress = Progress {
+            version: 1,
+            tree_index: TREES.iter().position(|t| *t == GROUP_STATE_TREE).unwrap(),
+            last_key: Some(keys[3].clone()),
+        };
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(
+                MIGRATION_PROGRESS_KEY,
+                serde_json::to_vec(&progress).unwrap(),
+            )
+            .unwrap();
+
+        migrate_in_batches(&db, 3).unwrap();
+
+        assert_eq!(schema_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        let meta = db.open_tree(META_TREE).unwrap();
+        assert!(meta.get(MIGRATION_PROGRESS_KEY).unwrap().is_none());
+        let tree = open_versioned(&db, GROUP_STATE_TREE);
+        assert_eq!(tree.len(), keys.len());
+        for (key, value) in tree.iter().map(Result::unwrap) {
+            let entity = codec::decode_entity(&value).unwrap();
+            let state: String = serde_json::from_slice(entity).unwrap();
+            assert_eq!(state, format!("state{}", key[0]));
+        }
+    }
+
+    #[test]
+    fn test_migration_is_idempotent() {
+        let db = open_db();
+        let tree = db.open_tree(GROUP_STATE_TREE).unwrap();
+        let entity = serde_json::to_vec("state").unwrap();
+        tree.insert(b"migrated", codec::encode_entity(&entity))
+            .unwrap();
+
+        assert!(wrap_in_envelope(
+            &db,
+            GROUP_STATE_TREE,
+            b"migrated",
+            &tree.get(b"migrated").unwrap().unwrap()
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_rejects_newer_schema() {
+        let db = open_db();
+        db.open_tree(META_TREE)
+            .unwrap()
+            .insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+
+        assert_eq!(
+            SledStorage::new_from_db(db).err(),
+            Some(SledStorageError::UnsupportedSchemaVersion {
+                found: SCHEMA_VERSION + 1,
+                supported: SCHEMA_VERSION,
+            })
+        );
+    }
+}
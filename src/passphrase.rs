@@ -0,0 +1,237 @@
+//! Passphrase-derived storage keys.
+//!
+//! [`SledStorage::open_with_passphrase`](crate::SledStorage::open_with_passphrase)
+//! derives the [`StorageKey`] from a passphrase with Argon2id. The salt and
+//! the Argon2 parameters are generated when the passphrase is first set and
+//! kept in the meta tree, so the same key is derived every time the database
+//! is opened.
+//!
+//! Next to them the meta tree holds a key-check record: a known value
+//! encrypted with the derived key. A passphrase is accepted only if the
+//! key-check record decrypts with the key derived from it, which tells a wrong
+//! passphrase apart from a damaged database.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use sled::transaction::ConflictableTransactionError;
+use sled::Db;
+use zeroize::Zeroizing;
+
+use crate::encryption::{self, StorageKey, KEY_LEN};
+use crate::migrations::{self, META_TREE};
+use crate::{codec, helpers, SledStorageError};
+
+pub(crate) const KDF_PARAMS_KEY: &[u8] = b"kdf_params";
+pub(crate) const KEY_CHECK_KEY: &[u8] = b"key_check";
+
+/// The value encrypted in the key-check record.
+const KEY_CHECK_VALUE: &[u8] = b"openmls-sled-storage key check";
+
+/// Length of the random salt in bytes.
+const SALT_LEN: usize = 16;
+
+/// Key derivation parameters, persisted in the meta tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct KdfParams {
+    /// Memory cost in KiB.
+    m_cost: u32,
+    /// Number of iterations.
+    t_cost: u32,
+    /// Degree of parallelism.
+    p_cost: u32,
+    salt: Vec<u8>,
+}
+
+impl KdfParams {
+    /// Creates parameters with a fresh random salt and the default Argon2id costs.
+    pub(crate) fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            salt,
+        }
+    }
+
+    /// Derives the storage key for a passphrase.
+    pub(crate) fn derive_key(&self, passphrase: &[u8]) -> Result<StorageKey, SledStorageError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|_| SledStorageError::encryption())?;
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, &self.salt, key.as_mut())
+            .map_err(|_| SledStorageError::encryption())?;
+        Ok(StorageKey::from_bytes(*key))
+    }
+}
+
+/// Reads the key derivation parameters, if a passphrase has been set.
+pub(crate) fn read_params(db: &Db) -> Result<Option<KdfParams>, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(KDF_PARAMS_KEY)? {
+        Some(bytes) => Ok(Some(helpers::from_json(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Encrypts the key-check record.
+pub(crate) fn key_check(storage_key: &StorageKey) -> Result<Vec<u8>, SledStorageError> {
+    encryption::encrypt(
+        storage_key,
+        META_TREE,
+        KEY_CHECK_KEY,
+        &codec::encode_entity(KEY_CHECK_VALUE),
+    )
+}
+
+/// Returns `true` if a key-check record was encrypted with the given key.
+pub(crate) fn matches_key_check(
+    storage_key: &StorageKey,
+    key_check: &[u8],
+) -> Result<bool, SledStorageError> {
+    Ok(
+        match encryption::decrypt(storage_key, META_TREE, KEY_CHECK_KEY, key_check) {
+            Ok(value) => codec::decode_entity(&value)? == KEY_CHECK_VALUE,
+            Err(_) => false,
+        },
+    )
+}
+
+/// Returns `true` if the stored key-check record decrypts with the given key.
+pub(crate) fn verify_key(db: &Db, storage_key: &StorageKey) -> Result<bool, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    let stored = meta
+        .get(KEY_CHECK_KEY)?
+        .ok_or_else(SledStorageError::not_found)?;
+    matches_key_check(storage_key, &stored)
+}
+
+/// Persists the key derivation parameters together with the key-check record
+/// for the key derived from them.
+pub(crate) fn write_params(
+    db: &Db,
+    params: &KdfParams,
+    storage_key: &StorageKey,
+) -> Result<(), SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    let encoded_params = helpers::to_json(params)?;
+    let key_check = key_check(storage_key)?;
+    meta.transaction(|meta| {
+        meta.insert(KDF_PARAMS_KEY, encoded_params.as_slice())?;
+        meta.insert(KEY_CHECK_KEY, key_check.as_slice())?;
+        Ok::<_, ConflictableTransactionError<SledStorageError>>(())
+    })?;
+    Ok(())
+}
+
+/// Derives the storage key for a passphrase.
+///
+/// The first time a new database is unlocked, fresh key derivation parameters
+/// and a key-check record are stored in the meta tree.
+///
+/// # Arguments
+///
+/// * `db` - The Sled database.
+/// * `passphrase` - The passphrase.
+///
+/// # Returns
+///
+/// A Result containing the derived `StorageKey` or a SledStorageError.
+///
+/// # Errors
+///
+/// Returns `SledStorageError::WrongPassphrase` if the passphrase does not
+/// match the one the database was set up with, or
+/// `SledStorageError::InvalidConfig` if the database already holds data but
+/// was not set up with a passphrase.
+pub(crate) fn unlock(db: &Db, passphrase: &[u8]) -> Result<StorageKey, SledStorageError> {
+    match read_params(db)? {
+        Some(params) => {
+            let storage_key = params.derive_key(passphrase)?;
+            if !verify_key(db, &storage_key)? {
+                return Err(SledStorageError::WrongPassphrase);
+            }
+            Ok(storage_key)
+        }
+        None => {
+            if migrations::has_mls_state(db)? {
+                return Err(SledStorageError::InvalidConfig(
+                    "a passphrase can only be set up for a new database".to_string(),
+                ));
+            }
+            tracing::debug!(target: "openmls_sled_storage::passphrase", "Setting up passphrase");
+            let params = KdfParams::generate();
+            let storage_key = params.derive_key(passphrase)?;
+            write_params(db, &params, &storage_key)?;
+            Ok(storage_key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledStorage;
+    use openmls_traits::storage::CURRENT_VERSION;
+    use tempfile::tempdir;
+
+    /// Cheap parameters, so the tests do not spend their time in Argon2.
+    fn test_params() -> KdfParams {
+        KdfParams {
+            m_cost: Params::MIN_M_COST,
+            t_cost: 1,
+            ..KdfParams::generate()
+        }
+    }
+
+    #[test]
+    fn test_derive_key_is_deterministic() {
+        let params = test_params();
+        let first = params.derive_key(b"passphrase").unwrap();
+        let second = params.derive_key(b"passphrase").unwrap();
+        let stored = encryption::encrypt(&first, b"tree", b"key", b"value").unwrap();
+        assert!(encryption::decrypt(&second, b"tree", b"key", &stored).is_ok());
+
+        // A different salt gives a different key.
+        let other = KdfParams {
+            salt: vec![0; SALT_LEN],
+            ..params
+        };
+        let third = other.derive_key(b"passphrase").unwrap();
+        assert!(encryption::decrypt(&third, b"tree", b"key", &stored).is_err());
+    }
+
+    #[test]
+    fn test_unlock() {
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let params = test_params();
+        let storage_key = params.derive_key(b"passphrase").unwrap();
+        write_params(&db, &params, &storage_key).unwrap();
+        assert_eq!(read_params(&db).unwrap(), Some(params));
+
+        assert!(unlock(&db, b"passphrase").is_ok());
+        assert_eq!(
+            unlock(&db, b"wrong passphrase").err(),
+            Some(SledStorageError::WrongPassphrase)
+        );
+    }
+
+    #[test]
+    fn test_unlock_refuses_existing_state() {
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let storage = SledStorage::new_from_db(db.clone()).unwrap();
+        storage
+            .write::<CURRENT_VERSION>(crate::traits::GROUP_STATE_TREE, b"group", b"{}".to_vec())
+            .unwrap();
+
+        assert!(matches!(
+            unlock(&db, b"passphrase"),
+            Err(SledStorageError::InvalidConfig(_))
+        ));
+        assert_eq!(read_params(&db).unwrap(), None);
+    }
+}
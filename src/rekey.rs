@@ -0,0 +1,410 @@
+//! Storage key rotation.
+//!
+//! [`SledStorage::rekey`] re-encrypts every value in the trees listed in
+//! [`TREES`] with a new key. Values are re-encrypted in batches, and the
+//! position reached is persisted in the meta tree in the same transaction as
+//! each batch. At any point every value is encrypted with either the old or
+//! the new key, and the persisted position tells which, so an interrupted
+//! rekey never leaves a value behind that neither key can read. Calling
+//! `rekey` again with the same keys picks up where it stopped.
+//!
+//! A database with an interrupted rekey can only be opened with
+//! [`SledStorageConfig::resume_rekey`] set, and with the old key.
+//!
+//! [`SledStorageConfig::resume_rekey`]: crate::SledStorageConfig::resume_rekey
+
+use std::ops::Bound;
+
+use openmls_traits::storage::CURRENT_VERSION;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+
+use crate::encryption::{self, StorageKey};
+use crate::keys::{self, LOOKUP_SECRET_KEY};
+use crate::migrations::META_TREE;
+use crate::passphrase::{self, KdfParams, KDF_PARAMS_KEY, KEY_CHECK_KEY};
+use crate::traits::TREES;
+use crate::{helpers, SledStorage, SledStorageError};
+
+const REKEY_PROGRESS_KEY: &[u8] = b"rekey_progress";
+
+/// Number of values re-encrypted per transaction.
+const BATCH_SIZE: usize = 512;
+
+/// Position of an in-flight rekey.
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    tree_index: usize,
+    last_key: Option<Vec<u8>>,
+    /// Key-check record for the new key, used to make sure a resumed rekey
+    /// continues with the same key it started with.
+    new_key_check: Vec<u8>,
+    /// Key derivation parameters for the new key, if it is derived from a
+    /// passphrase.
+    kdf_params: Option<KdfParams>,
+}
+
+impl SledStorage {
+    /// Re-encrypts all values with a new storage key.
+    ///
+    /// Once this returns, this instance uses the new key. Other instances
+    /// using the same database must not write to it while the rekey runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The key the values are currently encrypted with.
+    /// * `new` - The key to re-encrypt the values with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::InvalidConfig` if this instance is not
+    /// encrypted or `old` is not its storage key,
+    /// `SledStorageError::DecryptionError` if a value cannot be
+    /// decrypted with either key, `SledStorageError::RekeyInProgress` if an
+    /// interrupted rekey to a different key has to be finished first, and
+    /// `SledStorageError::PassphraseProtected` if the storage key is derived
+    /// from a passphrase, in which case [`Self::change_passphrase`] must be
+    /// used instead.
+    pub fn rekey(&mut self, old: &StorageKey, new: &StorageKey) -> Result<(), SledStorageError> {
+        if passphrase::read_params(&self.db)?.is_some() {
+            return Err(SledStorageError::PassphraseProtected);
+        }
+        self.rekey_in_batches(old, new, None, BATCH_SIZE)
+    }
+
+    /// Changes the passphrase the storage key is derived from and re-encrypts
+    /// all values with the new key.
+    ///
+    /// An interrupted change is resumed by calling this again with the same
+    /// passphrases. Until it completes, the database can only be opened with
+    /// the old passphrase and [`SledStorageConfig::resume_rekey`] set.
+    ///
+    /// [`SledStorageConfig::resume_rekey`]: crate::SledStorageConfig::resume_rekey
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - The current passphrase.
+    /// * `new` - The new passphrase.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::InvalidConfig` if no passphrase is set,
+    /// `SledStorageError::WrongPassphrase` if `old` is not the current
+    /// passphrase, or if `new` is not the passphrase an interrupted change was
+    /// started with.
+    pub fn change_passphrase(
+        &mut self,
+        old: impl AsRef<[u8]>,
+        new: impl AsRef<[u8]>,
+    ) -> Result<(), SledStorageError> {
+        let Some(old_params) = passphrase::read_params(&self.db)? else {
+            return Err(SledStorageError::InvalidConfig(
+                "no passphrase is set".to_string(),
+            ));
+        };
+        let old_key = old_params.derive_key(old.as_ref())?;
+        if !passphrase::verify_key(&self.db, &old_key)? {
+            return Err(SledStorageError::WrongPassphrase);
+        }
+
+        let kdf_params = match read_progress(&self.db)? {
+            Some(Progress {
+                kdf_params: Some(kdf_params),
+                ..
+            }) => kdf_params,
+            _ => KdfParams::generate(),
+        };
+        let new_key = kdf_params.derive_key(new.as_ref())?;
+
+        match self.rekey_in_batches(&old_key, &new_key, Some(kdf_params), BATCH_SIZE) {
+            Err(SledStorageError::RekeyInProgress) => Err(SledStorageError::WrongPassphrase),
+            result => result,
+        }
+    }
+
+    fn rekey_in_batches(
+        &mut self,
+        old: &StorageKey,
+        new: &StorageKey,
+        kdf_params: Option<KdfParams>,
+        batch_size: usize,
+    ) -> Result<(), SledStorageError> {
+        match &self.storage_key {
+            None => {
+                return Err(SledStorageError::InvalidConfig(
+                    "rekeying requires encryption".to_string(),
+                ))
+            }
+            Some(storage_key) if storage_key != old => {
+                return Err(SledStorageError::InvalidConfig(
+                    "the old key is not the storage key of this instance".to_string(),
+                ))
+            }
+            Some(_) => {}
+        }
+        let meta = self.db.open_tree(META_TREE)?;
+
+        let mut progress = match read_progress(&self.db)? {
+            Some(progress) => {
+                if !passphrase::matches_key_check(new, &progress.new_key_check)? {
+                    return Err(SledStorageError::RekeyInProgress);
+                }
+                tracing::info!(target: "openmls_sled_storage::rekey", "Resuming interrupted rekey");
+                progress
+            }
+            None => Progress {
+                tree_index: 0,
+                last_key: None,
+                new_key_check: passphrase::key_check(new)?,
+                kdf_params,
+            },
+        };
+
+        let tree_names = self.db.tree_names();
+        while progress.tree_index < TREES.len() {
+            let name = TREES[progress.tree_index];
+            if !tree_names
+                .iter()
+                .any(|tree_name| *tree_name == helpers::versioned_tree::<CURRENT_VERSION>(name))
+            {
+                progress.tree_index += 1;
+                continue;
+            }
+            let tree = self.open_tree::<CURRENT_VERSION>(name)?;
+
+            let entries = match &progress.last_key {
+                Some(last_key) => {
+                    tree.range::<&[u8], _>((Bound::Excluded(last_key.as_slice()), Bound::Unbounded))
+                }
+                None => tree.iter(),
+            };
+
+            let mut keys = Vec::new();
+            for entry in entries.take(batch_size) {
+                let (key, _) = entry?;
+                keys.push(key);
+            }
+
+            progress = match keys.last() {
+                Some(last_key) => Progress {
+                    last_key: Some(last_key.to_vec()),
+                    ..progress
+                },
+                None => Progress {
+                    tree_index: progress.tree_index + 1,
+                    last_key: None,
+                    ..progress
+                },
+            };
+            let encoded_progress = helpers::to_json(&progress)?;
+
+            (&tree, &meta).transaction(|(tree, meta)| {
+                for key in &keys {
+                    let Some(stored) = tree.get(key)? else {
+                        continue;
+                    };
+                    let value = match encryption::decrypt(old, name, key, &stored) {
+                        Ok(value) => value,
+                        // Written with the new key after all.
+                        Err(_) if encryption::decrypt(new, name, key, &stored).is_ok() => continue,
+                        Err(e) => return Err(ConflictableTransactionError::Abort(e)),
+                    };
+                    let encrypted = encryption::encrypt(new, name, key, &value)
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    tree.insert(key, encrypted)?;
+                }
+                meta.insert(REKEY_PROGRESS_KEY, encoded_progress.as_slice())?;
+                Ok(())
+            })?;
+        }
+
+        let lookup_secret = keys::rewrap_secret(&self.db, old, new)?;
+        meta.transaction(|meta| {
+            if let Some(lookup_secret) = &lookup_secret {
+                meta.insert(LOOKUP_SECRET_KEY, lookup_secret.as_slice())?;
+            }
+            if let Some(kdf_params) = &progress.kdf_params {
+                meta.insert(
+                    KDF_PARAMS_KEY,
+                    helpers::to_json(kdf_params).map_err(ConflictableTransactionError::Abort)?,
+                )?;
+                meta.insert(KEY_CHECK_KEY, progress.new_key_check.as_slice())?;
+            }
+            meta.remove(REKEY_PROGRESS_KEY)?;
+            Ok::<_, ConflictableTransactionError<SledStorageError>>(())
+        })?;
+
+        tracing::info!(target: "openmls_sled_storage::rekey", "Rekey completed");
+        self.storage_key = Some(new.clone());
+        Ok(())
+    }
+}
+
+/// Returns `true` if a rekey was interrupted and has not been resumed yet.
+pub(crate) fn in_progress(db: &sled::Db) -> Result<bool, SledStorageError> {
+    Ok(db.open_tree(META_TREE)?.contains_key(REKEY_PROGRESS_KEY)?)
+}
+
+fn read_progress(db: &sled::Db) -> Result<Option<Progress>, SledStorageError> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(REKEY_PROGRESS_KEY)? {
+        Some(bytes) => Ok(Some(helpers::from_json(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::{GROUP_STATE_TREE, MESSAGE_SECRETS_TREE};
+    use crate::{Encryption, SledStorageConfig};
+    use openmls_traits::storage::{Entity, CURRENT_VERSION};
+    use tempfile::tempdir;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestEntity(String);
+
+    impl Entity<CURRENT_VERSION> for TestEntity {}
+
+    fn write_entries(storage: &SledStorage, tree: &[u8], count: u8) {
+        for i in 0..count {
+            storage
+                .write::<CURRENT_VERSION>(
+                    tree,
+                    &[i],
+                    serde_json::to_vec(&TestEntity(format!("value{i}"))).unwrap(),
+                )
+                .unwrap();
+        }
+    }
+
+    fn read_entries(storage: &SledStorage, tree: &[u8], count: u8) {
+        for i in 0..count {
+            let value: Option<TestEntity> = storage.read::<CURRENT_VERSION, _>(tree, &[i]).unwrap();
+            assert_eq!(value, Some(TestEntity(format!("value{i}"))));
+        }
+    }
+
+    fn resume(db: &sled::Db, storage_key: &StorageKey) -> SledStorage {
+        let config = SledStorageConfig {
+            encryption: Encryption::Key(storage_key.clone()),
+            resume_rekey: true,
+            ..Default::default()
+        };
+        SledStorage::new_from_db_with_config(db.clone(), config).unwrap()
+    }
+
+    #[test]
+    fn test_rekey() {
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let old = StorageKey::generate();
+        let new = StorageKey::generate();
+        let mut storage = SledStorage::new_from_db_with_key(db.clone(), old.clone()).unwrap();
+        write_entries(&storage, GROUP_STATE_TREE, 10);
+        storage
+            .append::<CURRENT_VERSION>(
+                MESSAGE_SECRETS_TREE,
+                b"list",
+                serde_json::to_vec(&TestEntity("item".to_string())).unwrap(),
+            )
+            .unwrap();
+
+        storage.rekey_in_batches(&old, &new, None, 3).unwrap();
+        read_entries(&storage, GROUP_STATE_TREE, 10);
+        let list: Vec<TestEntity> = storage
+            .read_list::<CURRENT_VERSION, _>(MESSAGE_SECRETS_TREE, b"list")
+            .unwrap();
+        assert_eq!(list, vec![TestEntity("item".to_string())]);
+        assert!(read_progress(&db).unwrap().is_none());
+
+        let storage = SledStorage::new_from_db_with_key(db, old).unwrap();
+        assert!(matches!(
+            storage.read::<CURRENT_VERSION, TestEntity>(GROUP_STATE_TREE, &[0]),
+            Err(SledStorageError::DecryptionError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resumes_interrupted_rekey() {
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let old = StorageKey::generate();
+        let new = StorageKey::generate();
+        let mut storage = SledStorage::new_from_db_with_key(db.clone(), old.clone()).unwrap();
+        write_entries(&storage, GROUP_STATE_TREE, 10);
+        write_entries(&storage, MESSAGE_SECRETS_TREE, 10);
+
+        // A value that cannot be decrypted makes the rekey fail halfway.
+        let broken = storage
+            .open_tree::<CURRENT_VERSION>(MESSAGE_SECRETS_TREE)
+            .unwrap();
+        broken.insert([5], b"garbage".to_vec()).unwrap();
+        assert!(storage.rekey_in_batches(&old, &new, None, 3).is_err());
+        assert!(read_progress(&db).unwrap().is_some());
+
+        // The database is only opened to resume the rekey.
+        assert_eq!(
+            SledStorage::new_from_db_with_key(db.clone(), old.clone()).err(),
+            Some(SledStorageError::RekeyInProgress)
+        );
+
+        // Every value is still readable with one of the keys.
+        let with_old = resume(&db, &old);
+        let with_new = resume(&db, &new);
+        for tree in [GROUP_STATE_TREE, MESSAGE_SECRETS_TREE] {
+            for i in 0..10u8 {
+                if tree == MESSAGE_SECRETS_TREE && i == 5 {
+                    continue;
+                }
+                let value = with_old
+                    .read::<CURRENT_VERSION, TestEntity>(tree, &[i])
+                    .or_else(|_| with_new.read::<CURRENT_VERSION, TestEntity>(tree, &[i]))
+                    .unwrap();
+                assert_eq!(value, Some(TestEntity(format!("value{i}"))));
+            }
+        }
+
+        // A different new key cannot take over the interrupted rekey.
+        assert_eq!(
+            storage.rekey_in_batches(&old, &StorageKey::generate(), None, 3),
+            Err(SledStorageError::RekeyInProgress)
+        );
+
+        broken.remove([5]).unwrap();
+        storage.rekey_in_batches(&old, &new, None, 3).unwrap();
+        read_entries(&with_new, GROUP_STATE_TREE, 10);
+        assert!(read_progress(&db).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rekey_needs_current_key() {
+        let db = sled::open(tempdir().unwrap().path()).unwrap();
+        let old = StorageKey::generate();
+        let new = StorageKey::generate();
+
+        let mut storage = SledStorage::new_from_db(db.clone()).unwrap();
+        assert!(matches!(
+            storage.rekey(&old, &new),
+            Err(SledStorageError::InvalidConfig(_))
+        ));
+
+        let mut storage = SledStorage::new_from_db_with_key(db.clone(), old.clone()).unwrap();
+        write_entries(&storage, GROUP_STATE_TREE, 3);
+        assert!(matches!(
+            storage.rekey(&StorageKey::generate(), &new),
+            Err(SledStorageError::InvalidConfig(_))
+        ));
+        assert!(read_progress(&db).unwrap().is_none());
+        read_entries(&storage, GROUP_STATE_TREE, 3);
+
+        storage.rekey(&old, &new).unwrap();
+        read_entries(&storage, GROUP_STATE_TREE, 3);
+    }
+}
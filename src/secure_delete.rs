@@ -0,0 +1,269 @@
+//! Secure deletion of forward-secrecy material.
+//!
+//! MLS forward secrecy depends on old epoch secrets and key pairs actually
+//! being gone once they are deleted. Sled never updates data in place: a
+//! removed value stays in the log segment it was written to until the segment
+//! happens to be reclaimed, which may be never.
+//!
+//! With [`SledStorageConfig::secure_delete`] a delete in one of the
+//! [`SECRET_TREES`] overwrites the value with zeros before removing it, which
+//! at least keeps the latest version from holding the secret, and marks the
+//! database as needing a scrub. [`SledStorage::scrub`] then rewrites the
+//! database into fresh files and wipes the old ones, so no segment holding a
+//! deleted value survives. A pending scrub is also carried out the next time
+//! the database is opened with secure deletion enabled.
+//!
+//! The rewrite goes through a staging directory next to the database.
+//! Opening a database finishes or discards a rewrite that was interrupted.
+//!
+//! [`SledStorageConfig::secure_delete`]: crate::SledStorageConfig::secure_delete
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::migrations::META_TREE;
+use crate::traits::SECRET_TREES;
+use crate::{SledStorage, SledStorageError};
+
+pub(crate) const SCRUB_PENDING_KEY: &[u8] = b"scrub_pending";
+
+/// Size of the chunks of zeros old database files are overwritten with.
+const WIPE_CHUNK_LEN: usize = 64 * 1024;
+
+impl SledStorage {
+    /// Rewrites the database into fresh files and wipes the old ones, so that
+    /// no trace of deleted values remains on disk.
+    ///
+    /// The whole database is copied, so this is expensive for large
+    /// databases. The id generator of the copy is advanced past the one of
+    /// the database, as list items are ordered by generated ids.
+    ///
+    /// The old files are wiped, so no other handle to the database may be
+    /// alive. Instances opened from a path own their database and never hand
+    /// out handles to it, so the only other handles are the ones of batches.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SledStorageError::InvalidConfig` if the instance was not opened
+    /// from a path, as only then are the database files known, or if a batch
+    /// of this instance is alive, as its handle to the database would keep
+    /// writing to the files that are wiped.
+    pub fn scrub(&mut self) -> Result<(), SledStorageError> {
+        let Some(path) = self.path.clone() else {
+            return Err(SledStorageError::InvalidConfig(
+                "scrubbing requires a database opened from a path".to_string(),
+            ));
+        };
+        if Arc::strong_count(&self.handles) > 1 {
+            return Err(SledStorageError::InvalidConfig(
+                "scrubbing requires that no batch is alive".to_string(),
+            ));
+        }
+        let path = database_path(&path)?;
+        let staged = sibling(&path, "scrub")?;
+        let old = sibling(&path, "scrub-old")?;
+        tracing::info!(target: "openmls_sled_storage::secure_delete", "Scrubbing database");
+
+        if staged.exists() {
+            fs::remove_dir_all(&staged).map_err(io_error)?;
+        }
+        self.db.flush()?;
+        let next_id = self.db.generate_id()?;
+        let copy = sled::open(&staged)?;
+        copy.import(self.db.export());
+        while copy.generate_id()? < next_id {}
+        copy.open_tree(META_TREE)?.remove(SCRUB_PENDING_KEY)?;
+        copy.flush()?;
+        drop(copy);
+
+        // Close the database before moving its files.
+        let placeholder = sled::Config::new().temporary(true).open()?;
+        drop(std::mem::replace(&mut self.db, placeholder));
+
+        fs::rename(&path, &old).map_err(io_error)?;
+        fs::rename(&staged, &path).map_err(io_error)?;
+        self.db = sled::open(&path)?;
+        wipe_dir(&old).map_err(io_error)?;
+
+        tracing::info!(target: "openmls_sled_storage::secure_delete", "Scrub completed");
+        Ok(())
+    }
+
+    /// Returns `true` if a deleted value has not been scrubbed from disk yet.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether a scrub is pending or a `SledStorageError`.
+    pub fn scrub_pending(&self) -> Result<bool, SledStorageError> {
+        Ok(self
+            .db
+            .open_tree(META_TREE)?
+            .contains_key(SCRUB_PENDING_KEY)?)
+    }
+
+    /// Returns `true` if deletes in the given tree have to be secure.
+    pub(crate) fn deletes_securely(&self, tree: &[u8]) -> bool {
+        self.secure_delete && SECRET_TREES.contains(&tree)
+    }
+
+    /// Overwrites a stored value with zeros and marks the database as needing
+    /// a scrub. Removing the value is left to the caller.
+    ///
+    /// # Arguments
+    ///
+    /// * `active_tree` - The opened tree holding the value.
+    /// * `key` - The key of the value about to be removed.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SledStorageError`.
+    pub(crate) fn prepare_secure_delete(
+        &self,
+        active_tree: &sled::Tree,
+        key: &[u8],
+    ) -> Result<(), SledStorageError> {
+        if let Some(mut pending) = self.pending() {
+            // The overwrite would be collapsed into the removal when the batch
+            // is committed, so only the scrub is recorded.
+            pending.put(META_TREE, SCRUB_PENDING_KEY, Vec::new());
+            return Ok(());
+        }
+
+        self.db
+            .open_tree(META_TREE)?
+            .insert(SCRUB_PENDING_KEY, Vec::new())?;
+        if let Some(stored) = active_tree.get(key)? {
+            active_tree.insert(key, vec![0u8; stored.len()])?;
+        }
+        Ok(())
+    }
+}
+
+/// Finishes or discards a scrub of the database at `path` that was
+/// interrupted.
+///
+/// A path without a file name, such as `..`, is resolved first. If it cannot
+/// be resolved it does not exist, and no scrub can have been started on it.
+///
+/// # Arguments
+///
+/// * `path` - The path of the database.
+///
+/// # Returns
+///
+/// A `Result` indicating success or a `SledStorageError`.
+pub(crate) fn recover(path: &Path) -> Result<(), SledStorageError> {
+    let Ok(path) = &database_path(path) else {
+        return Ok(());
+    };
+    let staged = sibling(path, "scrub")?;
+    let old = sibling(path, "scrub-old")?;
+
+    if !path.exists() && staged.exists() {
+        // Interrupted between moving the old files away and moving the
+        // complete copy into place.
+        fs::rename(&staged, path).map_err(io_error)?;
+    } else if staged.exists() {
+        // Interrupted while copying. The database still needs a scrub.
+        fs::remove_dir_all(&staged).map_err(io_error)?;
+    }
+    if old.exists() {
+        wipe_dir(&old).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+/// Returns a path to the database that ends in its name, resolving paths
+/// like `.` or `..`.
+///
+/// # Arguments
+///
+/// * `path` - The path the database was opened from.
+///
+/// # Returns
+///
+/// A `Result` containing the path, or a `SledStorageError` if `path` has to
+/// be resolved but does not exist.
+fn database_path(path: &Path) -> Result<PathBuf, SledStorageError> {
+    match path.file_name() {
+        Some(_) => Ok(path.to_path_buf()),
+        None => path.canonicalize().map_err(io_error),
+    }
+}
+
+/// Builds the path of a directory next to the database.
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf, SledStorageError> {
+    let name = path.file_name().ok_or_else(|| {
+        SledStorageError::InvalidConfig("database path has no file name".to_string())
+    })?;
+    let mut name = name.to_os_string();
+    name.push(".");
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+/// Overwrites every file in a directory with zeros and removes the directory.
+fn wipe_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            wipe_dir(&entry.path())?;
+            continue;
+        }
+        let mut file = OpenOptions::new().write(true).open(entry.path())?;
+        let mut remaining = file.metadata()?.len() as usize;
+        let zeros = vec![0u8; WIPE_CHUNK_LEN];
+        while remaining > 0 {
+            let len = remaining.min(WIPE_CHUNK_LEN);
+            file.write_all(&zeros[..len])?;
+            remaining -= len;
+        }
+        file.sync_all()?;
+    }
+    fs::remove_dir_all(dir)
+}
+
+fn io_error(error: io::Error) -> SledStorageError {
+    sled::Error::Io(error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recover_interrupted_scrub() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        let staged = sibling(&path, "scrub").unwrap();
+        let old = sibling(&path, "scrub-old").unwrap();
+
+        // Interrupted after moving the old files away.
+        sled::open(&staged)
+            .unwrap()
+            .insert(b"key", b"value".to_vec())
+            .unwrap();
+        fs::create_dir(&old).unwrap();
+        fs::write(old.join("db"), b"secret").unwrap();
+
+        recover(&path).unwrap();
+        assert!(!staged.exists());
+        assert!(!old.exists());
+        let db = sled::open(&path).unwrap();
+        assert_eq!(db.get(b"key").unwrap().unwrap(), b"value".as_slice());
+        drop(db);
+
+        // Interrupted while copying.
+        fs::create_dir(&staged).unwrap();
+        recover(&path).unwrap();
+        assert!(!staged.exists());
+        assert!(path.exists());
+    }
+}
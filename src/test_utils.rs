@@ -0,0 +1,473 @@
+//! A conformance suite for [`StorageProvider`] implementations.
+//!
+//! The suite only goes through the `StorageProvider` trait, so it can be run
+//! against [`SledStorage`](crate::SledStorage) in any configuration as well as
+//! against wrappers around it. It checks that every method round-trips its
+//! values, that writes overwrite, that deletes remove exactly what they are
+//! asked to, and that entries of different groups and keys don't interfere
+//! with each other.
+//!
+//! Every check uses its own ids, so all of them can share one fresh storage.
+//! Failures panic, as the suite is meant to be called from tests:
+//!
+//! ```ignore
+//! #[test]
+//! fn conformance() {
+//!     let storage = MyStorage::new();
+//!     openmls_sled_storage::test_utils::run_conformance_suite(&storage);
+//! }
+//! ```
+//!
+//! This module is only available with the `test-utils` feature.
+
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+
+/// A mock group id.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TestGroupId(pub Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+/// A mock for the keys that are not group ids: signature and encryption
+/// public keys, key package references and PSK ids.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TestKey(pub Vec<u8>);
+impl traits::SignaturePublicKey<CURRENT_VERSION> for TestKey {}
+impl traits::EncryptionKey<CURRENT_VERSION> for TestKey {}
+impl traits::HashReference<CURRENT_VERSION> for TestKey {}
+impl traits::PskId<CURRENT_VERSION> for TestKey {}
+impl Key<CURRENT_VERSION> for TestKey {}
+
+/// A mock epoch.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct TestEpochKey(pub u64);
+impl traits::EpochKey<CURRENT_VERSION> for TestEpochKey {}
+impl Key<CURRENT_VERSION> for TestEpochKey {}
+
+/// A mock proposal reference.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct TestProposalRef(pub usize);
+impl traits::ProposalRef<CURRENT_VERSION> for TestProposalRef {}
+impl Key<CURRENT_VERSION> for TestProposalRef {}
+impl Entity<CURRENT_VERSION> for TestProposalRef {}
+
+/// A mock entity, standing in for every kind of stored value.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TestEntity(pub String);
+impl Entity<CURRENT_VERSION> for TestEntity {}
+impl traits::TreeSync<CURRENT_VERSION> for TestEntity {}
+impl traits::GroupContext<CURRENT_VERSION> for TestEntity {}
+impl traits::InterimTranscriptHash<CURRENT_VERSION> for TestEntity {}
+impl traits::ConfirmationTag<CURRENT_VERSION> for TestEntity {}
+impl traits::GroupState<CURRENT_VERSION> for TestEntity {}
+impl traits::MessageSecrets<CURRENT_VERSION> for TestEntity {}
+impl traits::ResumptionPskStore<CURRENT_VERSION> for TestEntity {}
+impl traits::LeafNodeIndex<CURRENT_VERSION> for TestEntity {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for TestEntity {}
+impl traits::SignatureKeyPair<CURRENT_VERSION> for TestEntity {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for TestEntity {}
+impl traits::KeyPackage<CURRENT_VERSION> for TestEntity {}
+impl traits::PskBundle<CURRENT_VERSION> for TestEntity {}
+impl traits::MlsGroupJoinConfig<CURRENT_VERSION> for TestEntity {}
+impl traits::LeafNode<CURRENT_VERSION> for TestEntity {}
+impl traits::QueuedProposal<CURRENT_VERSION> for TestEntity {}
+
+impl TestEntity {
+    fn new(label: &str, n: usize) -> Self {
+        Self(format!("{label} {n}"))
+    }
+}
+
+/// A value stored under a single key, together with the methods that read,
+/// write and delete it.
+struct Entry<S, K> {
+    name: &'static str,
+    read: fn(&S, &K) -> Option<TestEntity>,
+    write: fn(&S, &K, &TestEntity),
+    delete: fn(&S, &K),
+}
+
+/// The values stored per group.
+fn group_entries<S: StorageProvider<CURRENT_VERSION>>() -> Vec<Entry<S, TestGroupId>> {
+    vec![
+        Entry {
+            name: "tree",
+            read: |s, g| s.tree(g).unwrap(),
+            write: |s, g, v| s.write_tree(g, v).unwrap(),
+            delete: |s, g| s.delete_tree(g).unwrap(),
+        },
+        Entry {
+            name: "interim transcript hash",
+            read: |s, g| s.interim_transcript_hash(g).unwrap(),
+            write: |s, g, v| s.write_interim_transcript_hash(g, v).unwrap(),
+            delete: |s, g| s.delete_interim_transcript_hash(g).unwrap(),
+        },
+        Entry {
+            name: "group context",
+            read: |s, g| s.group_context(g).unwrap(),
+            write: |s, g, v| s.write_context(g, v).unwrap(),
+            delete: |s, g| s.delete_context(g).unwrap(),
+        },
+        Entry {
+            name: "group state",
+            read: |s, g| s.group_state(g).unwrap(),
+            write: |s, g, v| s.write_group_state(g, v).unwrap(),
+            delete: |s, g| s.delete_group_state(g).unwrap(),
+        },
+        Entry {
+            name: "confirmation tag",
+            read: |s, g| s.confirmation_tag(g).unwrap(),
+            write: |s, g, v| s.write_confirmation_tag(g, v).unwrap(),
+            delete: |s, g| s.delete_confirmation_tag(g).unwrap(),
+        },
+        Entry {
+            name: "message secrets",
+            read: |s, g| s.message_secrets(g).unwrap(),
+            write: |s, g, v| s.write_message_secrets(g, v).unwrap(),
+            delete: |s, g| s.delete_message_secrets(g).unwrap(),
+        },
+        Entry {
+            name: "resumption psk store",
+            read: |s, g| s.resumption_psk_store(g).unwrap(),
+            write: |s, g, v| s.write_resumption_psk_store(g, v).unwrap(),
+            delete: |s, g| s.delete_all_resumption_psk_secrets(g).unwrap(),
+        },
+        Entry {
+            name: "own leaf index",
+            read: |s, g| s.own_leaf_index(g).unwrap(),
+            write: |s, g, v| s.write_own_leaf_index(g, v).unwrap(),
+            delete: |s, g| s.delete_own_leaf_index(g).unwrap(),
+        },
+        Entry {
+            name: "group epoch secrets",
+            read: |s, g| s.group_epoch_secrets(g).unwrap(),
+            write: |s, g, v| s.write_group_epoch_secrets(g, v).unwrap(),
+            delete: |s, g| s.delete_group_epoch_secrets(g).unwrap(),
+        },
+        Entry {
+            name: "join config",
+            read: |s, g| s.mls_group_join_config(g).unwrap(),
+            write: |s, g, v| s.write_mls_join_config(g, v).unwrap(),
+            delete: |s, g| s.delete_group_config(g).unwrap(),
+        },
+    ]
+}
+
+/// The values stored under keys other than group ids.
+fn key_entries<S: StorageProvider<CURRENT_VERSION>>() -> Vec<Entry<S, TestKey>> {
+    vec![
+        Entry {
+            name: "signature key pair",
+            read: |s, k| s.signature_key_pair(k).unwrap(),
+            write: |s, k, v| s.write_signature_key_pair(k, v).unwrap(),
+            delete: |s, k| s.delete_signature_key_pair(k).unwrap(),
+        },
+        Entry {
+            name: "encryption key pair",
+            read: |s, k| s.encryption_key_pair(k).unwrap(),
+            write: |s, k, v| s.write_encryption_key_pair(k, v).unwrap(),
+            delete: |s, k| s.delete_encryption_key_pair(k).unwrap(),
+        },
+        Entry {
+            name: "key package",
+            read: |s, k| s.key_package(k).unwrap(),
+            write: |s, k, v| s.write_key_package(k, v).unwrap(),
+            delete: |s, k| s.delete_key_package(k).unwrap(),
+        },
+        Entry {
+            name: "psk",
+            read: |s, k| s.psk(k).unwrap(),
+            write: |s, k, v| s.write_psk(k, v).unwrap(),
+            delete: |s, k| s.delete_psk(k).unwrap(),
+        },
+    ]
+}
+
+/// Checks the values stored under a single key, for two ids where the bytes of
+/// the first are a prefix of the bytes of the second.
+fn check_entries<S, K>(storage: &S, entries: &[Entry<S, K>], first: K, second: K) {
+    for (n, entry) in entries.iter().enumerate() {
+        let name = entry.name;
+        let value = |n| TestEntity::new(name, n);
+
+        // Missing values read as nothing and can be deleted.
+        assert_eq!((entry.read)(storage, &first), None, "{name}: missing value");
+        (entry.delete)(storage, &first);
+
+        // Round trip and overwrite.
+        (entry.write)(storage, &first, &value(1));
+        assert_eq!(
+            (entry.read)(storage, &first),
+            Some(value(1)),
+            "{name}: round trip"
+        );
+        (entry.write)(storage, &first, &value(2));
+        assert_eq!(
+            (entry.read)(storage, &first),
+            Some(value(2)),
+            "{name}: overwrite"
+        );
+
+        // Isolation between ids.
+        assert_eq!((entry.read)(storage, &second), None, "{name}: other id");
+        (entry.write)(storage, &second, &value(3));
+        assert_eq!(
+            (entry.read)(storage, &first),
+            Some(value(2)),
+            "{name}: isolation"
+        );
+
+        // Isolation from the other kinds of values under the same id.
+        for other in &entries[..n] {
+            assert_eq!(
+                (other.read)(storage, &first),
+                Some(TestEntity::new(other.name, 2)),
+                "{name}: overwrote {}",
+                other.name
+            );
+        }
+
+        // Deletion only affects the given id.
+        (entry.delete)(storage, &second);
+        assert_eq!((entry.read)(storage, &second), None, "{name}: delete");
+        assert_eq!(
+            (entry.read)(storage, &first),
+            Some(value(2)),
+            "{name}: delete other id"
+        );
+    }
+
+    for entry in entries {
+        (entry.delete)(storage, &first);
+        assert_eq!(
+            (entry.read)(storage, &first),
+            None,
+            "{}: delete",
+            entry.name
+        );
+    }
+}
+
+/// Checks every method storing a single value per group.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test.
+pub fn check_group_values<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    check_entries(
+        storage,
+        &group_entries(),
+        TestGroupId(b"values".to_vec()),
+        TestGroupId(b"values2".to_vec()),
+    );
+}
+
+/// Checks the methods storing a single value under keys other than group ids.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test.
+pub fn check_key_values<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    check_entries(
+        storage,
+        &key_entries(),
+        TestKey(b"key".to_vec()),
+        TestKey(b"key2".to_vec()),
+    );
+}
+
+/// Checks the proposal queue methods.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test.
+pub fn check_proposal_queue<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    let group = TestGroupId(b"proposals".to_vec());
+    let other = TestGroupId(b"proposals2".to_vec());
+    let proposal = |n| TestEntity::new("proposal", n);
+    let refs = |g| -> Vec<TestProposalRef> { storage.queued_proposal_refs(g).unwrap() };
+    let proposals =
+        |g| -> Vec<(TestProposalRef, TestEntity)> { storage.queued_proposals(g).unwrap() };
+
+    assert!(refs(&group).is_empty());
+    assert!(proposals(&group).is_empty());
+    storage
+        .clear_proposal_queue::<_, TestProposalRef>(&group)
+        .unwrap();
+
+    // Proposals are returned in the order they were queued.
+    for n in [2, 0, 1] {
+        storage
+            .queue_proposal(&group, &TestProposalRef(n), &proposal(n))
+            .unwrap();
+    }
+    storage
+        .queue_proposal(&other, &TestProposalRef(0), &proposal(10))
+        .unwrap();
+    assert_eq!(
+        refs(&group),
+        vec![TestProposalRef(2), TestProposalRef(0), TestProposalRef(1)]
+    );
+    assert_eq!(
+        proposals(&group),
+        vec![
+            (TestProposalRef(2), proposal(2)),
+            (TestProposalRef(0), proposal(0)),
+            (TestProposalRef(1), proposal(1)),
+        ]
+    );
+
+    // Removing a proposal keeps the others, also the one with the same
+    // reference in the other group.
+    storage
+        .remove_proposal(&group, &TestProposalRef(0))
+        .unwrap();
+    storage
+        .remove_proposal(&group, &TestProposalRef(7))
+        .unwrap();
+    assert_eq!(
+        proposals(&group),
+        vec![
+            (TestProposalRef(2), proposal(2)),
+            (TestProposalRef(1), proposal(1)),
+        ]
+    );
+    assert_eq!(proposals(&other), vec![(TestProposalRef(0), proposal(10))]);
+
+    storage
+        .clear_proposal_queue::<_, TestProposalRef>(&group)
+        .unwrap();
+    assert!(refs(&group).is_empty());
+    assert!(proposals(&group).is_empty());
+    assert_eq!(proposals(&other), vec![(TestProposalRef(0), proposal(10))]);
+
+    // A cleared queue can be used again.
+    storage
+        .queue_proposal(&group, &TestProposalRef(3), &proposal(3))
+        .unwrap();
+    assert_eq!(proposals(&group), vec![(TestProposalRef(3), proposal(3))]);
+
+    storage
+        .clear_proposal_queue::<_, TestProposalRef>(&group)
+        .unwrap();
+    storage
+        .clear_proposal_queue::<_, TestProposalRef>(&other)
+        .unwrap();
+    assert!(proposals(&other).is_empty());
+}
+
+/// Checks the own leaf node methods.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test.
+pub fn check_own_leaf_nodes<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    let group = TestGroupId(b"leaf nodes".to_vec());
+    let other = TestGroupId(b"leaf nodes2".to_vec());
+    let leaf_node = |n| TestEntity::new("leaf node", n);
+    let leaf_nodes = |g| -> Vec<TestEntity> { storage.own_leaf_nodes(g).unwrap() };
+
+    assert!(leaf_nodes(&group).is_empty());
+    storage.delete_own_leaf_nodes(&group).unwrap();
+
+    for n in 0..3 {
+        storage.append_own_leaf_node(&group, &leaf_node(n)).unwrap();
+    }
+    storage
+        .append_own_leaf_node(&other, &leaf_node(10))
+        .unwrap();
+    assert_eq!(
+        leaf_nodes(&group),
+        vec![leaf_node(0), leaf_node(1), leaf_node(2)]
+    );
+
+    storage.delete_own_leaf_nodes(&group).unwrap();
+    assert!(leaf_nodes(&group).is_empty());
+    assert_eq!(leaf_nodes(&other), vec![leaf_node(10)]);
+
+    storage.append_own_leaf_node(&group, &leaf_node(3)).unwrap();
+    assert_eq!(leaf_nodes(&group), vec![leaf_node(3)]);
+
+    storage.delete_own_leaf_nodes(&group).unwrap();
+    storage.delete_own_leaf_nodes(&other).unwrap();
+    assert!(leaf_nodes(&other).is_empty());
+}
+
+/// Checks the encryption epoch key pair methods.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test.
+pub fn check_encryption_epoch_key_pairs<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    let group = TestGroupId(b"epoch key pairs".to_vec());
+    let other = TestGroupId(b"epoch key pairs2".to_vec());
+    let key_pairs = |n: usize| -> Vec<TestEntity> {
+        (0..n)
+            .map(|i| TestEntity::new("key pair", n * 10 + i))
+            .collect()
+    };
+    let read = |g, epoch, leaf| -> Vec<TestEntity> {
+        storage
+            .encryption_epoch_key_pairs(g, &TestEpochKey(epoch), leaf)
+            .unwrap()
+    };
+    let slots = [
+        (&group, 1, 0),
+        (&group, 1, 1),
+        (&group, 2, 0),
+        (&other, 1, 0),
+    ];
+
+    assert!(read(&group, 1, 0).is_empty());
+    storage
+        .delete_encryption_epoch_key_pairs(&group, &TestEpochKey(1), 0)
+        .unwrap();
+
+    // Every group, epoch and leaf index has its own key pairs.
+    for (n, (g, epoch, leaf)) in slots.into_iter().enumerate() {
+        storage
+            .write_encryption_epoch_key_pairs(g, &TestEpochKey(epoch), leaf, &key_pairs(n + 1))
+            .unwrap();
+    }
+    for (n, (g, epoch, leaf)) in slots.into_iter().enumerate() {
+        assert_eq!(read(g, epoch, leaf), key_pairs(n + 1));
+    }
+
+    // Writing replaces the previous key pairs.
+    storage
+        .write_encryption_epoch_key_pairs(&group, &TestEpochKey(1), 1, &key_pairs(5))
+        .unwrap();
+    assert_eq!(read(&group, 1, 1), key_pairs(5));
+    storage
+        .write_encryption_epoch_key_pairs::<_, _, TestEntity>(&group, &TestEpochKey(1), 1, &[])
+        .unwrap();
+    assert!(read(&group, 1, 1).is_empty());
+
+    storage
+        .delete_encryption_epoch_key_pairs(&group, &TestEpochKey(1), 0)
+        .unwrap();
+    assert!(read(&group, 1, 0).is_empty());
+    assert_eq!(read(&group, 2, 0), key_pairs(3));
+    assert_eq!(read(&other, 1, 0), key_pairs(4));
+
+    for (g, epoch, leaf) in slots {
+        storage
+            .delete_encryption_epoch_key_pairs(g, &TestEpochKey(epoch), leaf)
+            .unwrap();
+        assert!(read(g, epoch, leaf).is_empty());
+    }
+}
+
+/// Runs every check of the suite.
+///
+/// # Arguments
+///
+/// * `storage` - The storage provider under test. It should be empty.
+pub fn run_conformance_suite<S: StorageProvider<CURRENT_VERSION>>(storage: &S) {
+    check_group_values(storage);
+    check_key_values(storage);
+    check_proposal_queue(storage);
+    check_own_leaf_nodes(storage);
+    check_encryption_epoch_key_pairs(storage);
+}
@@ -1,32 +1,36 @@
-use crate::helpers::*;
-use crate::{SledStorage, SledStorageError};
+use crate::helpers::{self, serialize_secret};
+use crate::{DanglingProposalRefs, KeyFingerprint, Operation, SledStorage, SledStorageError};
 use openmls_traits::storage::*;
 
-const KEY_PACKAGE_TREE: &[u8] = b"KeyPackage";
-const PSK_TREE: &[u8] = b"Psk";
-const ENCRYPTION_KEY_PAIR_TREE: &[u8] = b"EncryptionKeyPair";
-const SIGNATURE_KEY_PAIR_TREE: &[u8] = b"SignatureKeyPair";
-const EPOCH_KEY_PAIRS_TREE: &[u8] = b"EpochKeyPairs";
+pub(crate) const KEY_PACKAGE_TREE: &[u8] = b"KeyPackage";
+pub(crate) const PSK_TREE: &[u8] = b"Psk";
+pub(crate) const ENCRYPTION_KEY_PAIR_TREE: &[u8] = b"EncryptionKeyPair";
+pub(crate) const SIGNATURE_KEY_PAIR_TREE: &[u8] = b"SignatureKeyPair";
+pub(crate) const EPOCH_KEY_PAIRS_TREE: &[u8] = b"EpochKeyPairs";
 
 // related to PublicGroup
-const RATCHET_TREE_TREE: &[u8] = b"RatchetTree";
-const GROUP_CONTEXT_TREE: &[u8] = b"GroupContext";
-const INTERIM_TRANSCRIPT_HASH_TREE: &[u8] = b"InterimTranscriptHash";
-const CONFIRMATION_TAG_TREE: &[u8] = b"ConfirmationTag";
+pub(crate) const RATCHET_TREE_TREE: &[u8] = b"RatchetTree";
+pub(crate) const GROUP_CONTEXT_TREE: &[u8] = b"GroupContext";
+pub(crate) const INTERIM_TRANSCRIPT_HASH_TREE: &[u8] = b"InterimTranscriptHash";
+pub(crate) const CONFIRMATION_TAG_TREE: &[u8] = b"ConfirmationTag";
 
 // related to MlsGroup
-const JOIN_CONFIG_TREE: &[u8] = b"MlsGroupJoinConfig";
-const OWN_LEAF_NODES_TREE: &[u8] = b"OwnLeafNodes";
-const GROUP_STATE_TREE: &[u8] = b"GroupState";
-const QUEUED_PROPOSAL_TREE: &[u8] = b"QueuedProposal";
-const PROPOSAL_QUEUE_REFS_TREE: &[u8] = b"ProposalQueueRefs";
-const OWN_LEAF_NODE_INDEX_TREE: &[u8] = b"OwnLeafNodeIndex";
-const EPOCH_SECRETS_TREE: &[u8] = b"EpochSecrets";
-const RESUMPTION_PSK_STORE_TREE: &[u8] = b"ResumptionPsk";
-const MESSAGE_SECRETS_TREE: &[u8] = b"MessageSecrets";
+pub(crate) const JOIN_CONFIG_TREE: &[u8] = b"MlsGroupJoinConfig";
+pub(crate) const OWN_LEAF_NODES_TREE: &[u8] = b"OwnLeafNodes";
+pub(crate) const GROUP_STATE_TREE: &[u8] = b"GroupState";
+pub(crate) const QUEUED_PROPOSAL_TREE: &[u8] = b"QueuedProposal";
+pub(crate) const PROPOSAL_QUEUE_REFS_TREE: &[u8] = b"ProposalQueueRefs";
+pub(crate) const OWN_LEAF_NODE_INDEX_TREE: &[u8] = b"OwnLeafNodeIndex";
+pub(crate) const EPOCH_SECRETS_TREE: &[u8] = b"EpochSecrets";
+pub(crate) const RESUMPTION_PSK_STORE_TREE: &[u8] = b"ResumptionPsk";
+pub(crate) const MESSAGE_SECRETS_TREE: &[u8] = b"MessageSecrets";
+
+// bookkeeping kept by this crate
+pub(crate) const KEY_PACKAGE_INFO_TREE: &[u8] = b"KeyPackageInfo";
+pub(crate) const EPOCH_KEY_PAIRS_COLLECTED_TREE: &[u8] = b"EpochKeyPairsCollected";
 
 /// Helper for removing all stored MLS state
-pub const TREES: [&[u8]; 18] = [
+pub const TREES: [&[u8]; 20] = [
     KEY_PACKAGE_TREE,
     PSK_TREE,
     ENCRYPTION_KEY_PAIR_TREE,
@@ -45,6 +49,39 @@ pub const TREES: [&[u8]; 18] = [
     EPOCH_SECRETS_TREE,
     RESUMPTION_PSK_STORE_TREE,
     MESSAGE_SECRETS_TREE,
+    KEY_PACKAGE_INFO_TREE,
+    EPOCH_KEY_PAIRS_COLLECTED_TREE,
+];
+
+/// Trees holding a single entity per group, stored under the lookup key of the
+/// group id.
+pub(crate) const GROUP_ENTITY_TREES: [&[u8]; 11] = [
+    RATCHET_TREE_TREE,
+    GROUP_CONTEXT_TREE,
+    INTERIM_TRANSCRIPT_HASH_TREE,
+    CONFIRMATION_TAG_TREE,
+    JOIN_CONFIG_TREE,
+    GROUP_STATE_TREE,
+    OWN_LEAF_NODE_INDEX_TREE,
+    EPOCH_SECRETS_TREE,
+    RESUMPTION_PSK_STORE_TREE,
+    MESSAGE_SECRETS_TREE,
+    EPOCH_KEY_PAIRS_COLLECTED_TREE,
+];
+
+/// Trees holding a list per group, stored under the lookup key of the group id.
+pub(crate) const GROUP_LIST_TREES: [&[u8]; 2] = [OWN_LEAF_NODES_TREE, PROPOSAL_QUEUE_REFS_TREE];
+
+/// Trees holding private keys or secrets. Values written to them are
+/// serialized with [`serialize_secret`].
+pub const SECRET_TREES: [&[u8]; 7] = [
+    SIGNATURE_KEY_PAIR_TREE,
+    ENCRYPTION_KEY_PAIR_TREE,
+    EPOCH_KEY_PAIRS_TREE,
+    EPOCH_SECRETS_TREE,
+    MESSAGE_SECRETS_TREE,
+    PSK_TREE,
+    RESUMPTION_PSK_STORE_TREE,
 ];
 
 impl StorageProvider<CURRENT_VERSION> for SledStorage {
@@ -60,17 +97,17 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         proposal_ref: &ProposalRef,
         proposal: &QueuedProposal,
     ) -> Result<(), Self::Error> {
-        // write proposal to key (group_id, proposal_ref)
-        let key = serde_json::to_vec(&(group_id, proposal_ref))?;
-        let value = serde_json::to_vec(proposal)?;
-        self.write::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE, &key, value)?;
+        self.atomically(|storage| {
+            // write proposal to key (group_id, proposal_ref)
+            let key = storage.proposal_key(group_id, proposal_ref)?;
+            let value = helpers::to_json(proposal)?;
+            storage.write::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE, &key, value)?;
 
-        // update proposal list for group_id
-        let key = serde_json::to_vec(group_id)?;
-        let value = serde_json::to_vec(proposal_ref)?;
-        self.append::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE, &key, value)?;
-
-        Ok(())
+            // update proposal list for group_id
+            let key = storage.lookup_key(group_id)?;
+            let value = helpers::to_json(proposal_ref)?;
+            storage.append::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE, &key, value)
+        })
     }
 
     fn remove_proposal<
@@ -81,13 +118,15 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         group_id: &GroupId,
         proposal_ref: &ProposalRef,
     ) -> Result<(), Self::Error> {
-        let key = serde_json::to_vec(group_id)?;
-        let value = serde_json::to_vec(proposal_ref)?;
+        self.atomically(|storage| {
+            let key = storage.lookup_key(group_id)?;
+            let value = helpers::to_json(proposal_ref)?;
 
-        self.remove_item::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE, &key, value)?;
+            storage.remove_item::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE, &key, value)?;
 
-        let key = serde_json::to_vec(&(group_id, proposal_ref))?;
-        self.delete::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE, &key)
+            let key = storage.proposal_key(group_id, proposal_ref)?;
+            storage.delete::<CURRENT_VERSION>(QUEUED_PROPOSAL_TREE, &key)
+        })
     }
 
     fn queued_proposal_refs<
@@ -97,7 +136,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Vec<ProposalRef>, Self::Error> {
-        self.read_list(PROPOSAL_QUEUE_REFS_TREE, &serde_json::to_vec(group_id)?)
+        self.read_list(PROPOSAL_QUEUE_REFS_TREE, &self.lookup_key(group_id)?)
     }
 
     fn queued_proposals<
@@ -108,20 +147,31 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Vec<(ProposalRef, QueuedProposal)>, Self::Error> {
-        let refs: Vec<ProposalRef> =
-            self.read_list(PROPOSAL_QUEUE_REFS_TREE, &serde_json::to_vec(group_id)?)?;
-
-        refs.into_iter()
-            .map(|proposal_ref| -> Result<_, _> {
-                let key = (group_id, &proposal_ref);
-                let key = serde_json::to_vec(&key)?;
-
-                let proposal = self
-                    .read::<CURRENT_VERSION, _>(QUEUED_PROPOSAL_TREE, &key)?
-                    .unwrap();
-                Ok((proposal_ref, proposal))
-            })
-            .collect::<Result<Vec<_>, _>>()
+        let group_key = self.lookup_key(group_id)?;
+        let refs: Vec<ProposalRef> = self.read_list(PROPOSAL_QUEUE_REFS_TREE, &group_key)?;
+
+        let mut proposals = Vec::with_capacity(refs.len());
+        for proposal_ref in refs {
+            let key = self.proposal_key(group_id, &proposal_ref)?;
+
+            match self.read::<CURRENT_VERSION, _>(QUEUED_PROPOSAL_TREE, &key)? {
+                Some(proposal) => proposals.push((proposal_ref, proposal)),
+                None if self.dangling_proposal_refs == DanglingProposalRefs::Repair => {
+                    tracing::warn!(target: "openmls_sled_storage", "Removing proposal reference without a queued proposal: {}", KeyFingerprint::of(&key));
+                    self.remove_item::<CURRENT_VERSION>(
+                        PROPOSAL_QUEUE_REFS_TREE,
+                        &group_key,
+                        helpers::to_json(&proposal_ref)?,
+                    )?;
+                }
+                None => {
+                    return Err(SledStorageError::not_found()
+                        .in_entry(QUEUED_PROPOSAL_TREE, &key)
+                        .during(Operation::Read))
+                }
+            }
+        }
+        Ok(proposals)
     }
 
     fn clear_proposal_queue<
@@ -131,17 +181,18 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        // Get all proposal refs for this group.
-        let proposal_refs: Vec<ProposalRef> =
-            self.read_list(PROPOSAL_QUEUE_REFS_TREE, &serde_json::to_vec(group_id)?)?;
-        for proposal_ref in proposal_refs {
-            // Delete all proposals.
-            self.remove_proposal(group_id, &proposal_ref)?;
-        }
+        self.atomically(|storage| {
+            // Get all proposal refs for this group.
+            let proposal_refs: Vec<ProposalRef> =
+                storage.read_list(PROPOSAL_QUEUE_REFS_TREE, &storage.lookup_key(group_id)?)?;
 
-        // Delete the proposal refs from the store.
-        let key = build_key::<CURRENT_VERSION, &GroupId>(PROPOSAL_QUEUE_REFS_TREE, group_id);
-        self.delete::<CURRENT_VERSION>(PROPOSAL_QUEUE_REFS_TREE, &key)
+            // Delete all proposals. Removing the last ref also removes the
+            // refs entry of the group.
+            for proposal_ref in proposal_refs {
+                storage.remove_proposal(group_id, &proposal_ref)?;
+            }
+            Ok(())
+        })
     }
 
     fn tree<
@@ -151,7 +202,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Option<TreeSync>, Self::Error> {
-        self.read::<CURRENT_VERSION, TreeSync>(RATCHET_TREE_TREE, &serde_json::to_vec(group_id)?)
+        self.read::<CURRENT_VERSION, TreeSync>(RATCHET_TREE_TREE, &self.lookup_key(group_id)?)
     }
 
     fn write_tree<
@@ -164,8 +215,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             RATCHET_TREE_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(tree)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(tree)?,
         )
     }
 
@@ -173,7 +224,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(RATCHET_TREE_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(RATCHET_TREE_TREE, &self.lookup_key(group_id)?)
     }
 
     fn interim_transcript_hash<
@@ -185,7 +236,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<InterimTranscriptHash>, Self::Error> {
         self.read::<CURRENT_VERSION, InterimTranscriptHash>(
             INTERIM_TRANSCRIPT_HASH_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -199,8 +250,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             INTERIM_TRANSCRIPT_HASH_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(&interim_transcript_hash)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(&interim_transcript_hash)?,
         )
     }
 
@@ -208,7 +259,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(INTERIM_TRANSCRIPT_HASH_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(INTERIM_TRANSCRIPT_HASH_TREE, &self.lookup_key(group_id)?)
     }
 
     fn group_context<
@@ -218,10 +269,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Option<GroupContext>, Self::Error> {
-        self.read::<CURRENT_VERSION, GroupContext>(
-            GROUP_CONTEXT_TREE,
-            &serde_json::to_vec(group_id)?,
-        )
+        self.read::<CURRENT_VERSION, GroupContext>(GROUP_CONTEXT_TREE, &self.lookup_key(group_id)?)
     }
 
     fn write_context<
@@ -234,8 +282,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             GROUP_CONTEXT_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(&group_context)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(&group_context)?,
         )
     }
 
@@ -243,7 +291,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(GROUP_CONTEXT_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(GROUP_CONTEXT_TREE, &self.lookup_key(group_id)?)
     }
 
     fn group_state<
@@ -253,7 +301,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Option<GroupState>, Self::Error> {
-        self.read::<CURRENT_VERSION, GroupState>(GROUP_STATE_TREE, &serde_json::to_vec(&group_id)?)
+        self.read::<CURRENT_VERSION, GroupState>(GROUP_STATE_TREE, &self.lookup_key(&group_id)?)
     }
 
     fn write_group_state<
@@ -266,8 +314,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             GROUP_STATE_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(group_state)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(group_state)?,
         )
     }
 
@@ -275,7 +323,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(GROUP_STATE_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(GROUP_STATE_TREE, &self.lookup_key(group_id)?)
     }
 
     fn confirmation_tag<
@@ -287,7 +335,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<ConfirmationTag>, Self::Error> {
         self.read::<CURRENT_VERSION, ConfirmationTag>(
             CONFIRMATION_TAG_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -301,8 +349,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             CONFIRMATION_TAG_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(confirmation_tag)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(confirmation_tag)?,
         )
     }
 
@@ -310,7 +358,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(CONFIRMATION_TAG_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(CONFIRMATION_TAG_TREE, &self.lookup_key(group_id)?)
     }
 
     fn signature_key_pair<
@@ -322,7 +370,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<SignatureKeyPair>, Self::Error> {
         self.read::<CURRENT_VERSION, SignatureKeyPair>(
             SIGNATURE_KEY_PAIR_TREE,
-            &serde_json::to_vec(public_key)?,
+            &self.lookup_key(public_key)?,
         )
     }
 
@@ -336,8 +384,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             SIGNATURE_KEY_PAIR_TREE,
-            &serde_json::to_vec(public_key)?,
-            serde_json::to_vec(signature_key_pair)?,
+            &self.lookup_key(public_key)?,
+            serialize_secret(signature_key_pair)?,
         )
     }
 
@@ -347,7 +395,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         public_key: &SignaturePublicKeuy,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(SIGNATURE_KEY_PAIR_TREE, &serde_json::to_vec(public_key)?)
+        self.delete::<CURRENT_VERSION>(SIGNATURE_KEY_PAIR_TREE, &self.lookup_key(public_key)?)
     }
 
     fn encryption_key_pair<
@@ -359,7 +407,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<HpkeKeyPair>, Self::Error> {
         self.read::<CURRENT_VERSION, HpkeKeyPair>(
             ENCRYPTION_KEY_PAIR_TREE,
-            &serde_json::to_vec(public_key)?,
+            &self.lookup_key(public_key)?,
         )
     }
 
@@ -373,8 +421,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             ENCRYPTION_KEY_PAIR_TREE,
-            &serde_json::to_vec(public_key)?,
-            serde_json::to_vec(key_pair)?,
+            &self.lookup_key(public_key)?,
+            serialize_secret(key_pair)?,
         )
     }
 
@@ -382,7 +430,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         public_key: &EncryptionKey,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(ENCRYPTION_KEY_PAIR_TREE, &serde_json::to_vec(&public_key)?)
+        self.delete::<CURRENT_VERSION>(ENCRYPTION_KEY_PAIR_TREE, &self.lookup_key(&public_key)?)
     }
 
     fn key_package<
@@ -392,7 +440,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         hash_ref: &KeyPackageRef,
     ) -> Result<Option<KeyPackage>, Self::Error> {
-        self.read::<CURRENT_VERSION, KeyPackage>(KEY_PACKAGE_TREE, &serde_json::to_vec(&hash_ref)?)
+        self.read::<CURRENT_VERSION, KeyPackage>(KEY_PACKAGE_TREE, &self.lookup_key(&hash_ref)?)
     }
 
     fn write_key_package<
@@ -403,25 +451,21 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         hash_ref: &HashReference,
         key_package: &KeyPackage,
     ) -> Result<(), Self::Error> {
-        self.write::<CURRENT_VERSION>(
-            KEY_PACKAGE_TREE,
-            &serde_json::to_vec(&hash_ref)?,
-            serde_json::to_vec(&key_package)?,
-        )
+        self.write_key_package_with_lifetime(hash_ref, key_package, self.key_package_lifetime)
     }
 
     fn delete_key_package<KeyPackageRef: traits::HashReference<CURRENT_VERSION>>(
         &self,
         hash_ref: &KeyPackageRef,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(KEY_PACKAGE_TREE, &serde_json::to_vec(&hash_ref)?)
+        self.use_key_package(&self.lookup_key(&hash_ref)?)
     }
 
     fn psk<PskBundle: traits::PskBundle<CURRENT_VERSION>, PskId: traits::PskId<CURRENT_VERSION>>(
         &self,
         psk_id: &PskId,
     ) -> Result<Option<PskBundle>, Self::Error> {
-        self.read::<CURRENT_VERSION, PskBundle>(PSK_TREE, &serde_json::to_vec(&psk_id)?)
+        self.read::<CURRENT_VERSION, PskBundle>(PSK_TREE, &self.lookup_key(&psk_id)?)
     }
 
     fn write_psk<
@@ -434,8 +478,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             PSK_TREE,
-            &serde_json::to_vec(&psk_id)?,
-            serde_json::to_vec(&psk)?,
+            &self.lookup_key(&psk_id)?,
+            serialize_secret(&psk)?,
         )
     }
 
@@ -443,7 +487,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         psk_id: &PskKey,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(PSK_TREE, &serde_json::to_vec(&psk_id)?)
+        self.delete::<CURRENT_VERSION>(PSK_TREE, &self.lookup_key(&psk_id)?)
     }
 
     fn message_secrets<
@@ -455,7 +499,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<MessageSecrets>, Self::Error> {
         self.read::<CURRENT_VERSION, MessageSecrets>(
             MESSAGE_SECRETS_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -469,8 +513,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             MESSAGE_SECRETS_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(message_secrets)?,
+            &self.lookup_key(group_id)?,
+            serialize_secret(message_secrets)?,
         )
     }
 
@@ -478,7 +522,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(MESSAGE_SECRETS_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(MESSAGE_SECRETS_TREE, &self.lookup_key(group_id)?)
     }
 
     fn resumption_psk_store<
@@ -490,7 +534,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<ResumptionPskStore>, Self::Error> {
         self.read::<CURRENT_VERSION, ResumptionPskStore>(
             RESUMPTION_PSK_STORE_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -504,8 +548,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             RESUMPTION_PSK_STORE_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(resumption_psk_store)?,
+            &self.lookup_key(group_id)?,
+            serialize_secret(resumption_psk_store)?,
         )
     }
 
@@ -513,7 +557,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(RESUMPTION_PSK_STORE_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(RESUMPTION_PSK_STORE_TREE, &self.lookup_key(group_id)?)
     }
 
     fn own_leaf_index<
@@ -525,7 +569,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<LeafNodeIndex>, Self::Error> {
         self.read::<CURRENT_VERSION, LeafNodeIndex>(
             OWN_LEAF_NODE_INDEX_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -539,8 +583,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             OWN_LEAF_NODE_INDEX_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(own_leaf_index)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(own_leaf_index)?,
         )
     }
 
@@ -548,7 +592,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(OWN_LEAF_NODE_INDEX_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(OWN_LEAF_NODE_INDEX_TREE, &self.lookup_key(group_id)?)
     }
 
     fn group_epoch_secrets<
@@ -560,7 +604,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<GroupEpochSecrets>, Self::Error> {
         self.read::<CURRENT_VERSION, GroupEpochSecrets>(
             EPOCH_SECRETS_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -574,8 +618,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             EPOCH_SECRETS_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(group_epoch_secrets)?,
+            &self.lookup_key(group_id)?,
+            serialize_secret(group_epoch_secrets)?,
         )
     }
 
@@ -583,7 +627,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(EPOCH_SECRETS_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(EPOCH_SECRETS_TREE, &self.lookup_key(group_id)?)
     }
 
     fn encryption_epoch_key_pairs<
@@ -596,7 +640,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         epoch: &EpochKey,
         leaf_index: u32,
     ) -> Result<Vec<HpkeKeyPair>, Self::Error> {
-        let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
+        let key = self.epoch_key_pairs_key(group_id, epoch, leaf_index)?;
         self.read_list::<CURRENT_VERSION, HpkeKeyPair>(EPOCH_KEY_PAIRS_TREE, &key)
     }
 
@@ -611,8 +655,12 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         leaf_index: u32,
         key_pairs: &[HpkeKeyPair],
     ) -> Result<(), Self::Error> {
-        let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
-        self.write::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE, &key, serde_json::to_vec(key_pairs)?)
+        let key = self.epoch_key_pairs_key(group_id, epoch, leaf_index)?;
+        let values = key_pairs
+            .iter()
+            .map(serialize_secret)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write_list::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE, &key, values)
     }
 
     fn delete_encryption_epoch_key_pairs<
@@ -624,8 +672,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         epoch: &EpochKey,
         leaf_index: u32,
     ) -> Result<(), Self::Error> {
-        let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
-        self.delete::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE, &key)
+        let key = self.epoch_key_pairs_key(group_id, epoch, leaf_index)?;
+        self.delete_list::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_TREE, &key)
     }
 
     fn mls_group_join_config<
@@ -637,7 +685,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<Option<MlsGroupJoinConfig>, Self::Error> {
         self.read::<CURRENT_VERSION, MlsGroupJoinConfig>(
             JOIN_CONFIG_TREE,
-            &serde_json::to_vec(group_id)?,
+            &self.lookup_key(group_id)?,
         )
     }
 
@@ -651,8 +699,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.write::<CURRENT_VERSION>(
             JOIN_CONFIG_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(config)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(config)?,
         )
     }
 
@@ -663,7 +711,7 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<Vec<LeafNode>, Self::Error> {
-        self.read_list(OWN_LEAF_NODES_TREE, &serde_json::to_vec(group_id)?)
+        self.read_list(OWN_LEAF_NODES_TREE, &self.lookup_key(group_id)?)
     }
 
     fn append_own_leaf_node<
@@ -676,8 +724,8 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
     ) -> Result<(), Self::Error> {
         self.append::<CURRENT_VERSION>(
             OWN_LEAF_NODES_TREE,
-            &serde_json::to_vec(group_id)?,
-            serde_json::to_vec(leaf_node)?,
+            &self.lookup_key(group_id)?,
+            helpers::to_json(leaf_node)?,
         )
     }
 
@@ -685,13 +733,13 @@ impl StorageProvider<CURRENT_VERSION> for SledStorage {
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(OWN_LEAF_NODES_TREE, &serde_json::to_vec(group_id)?)
+        self.delete_list::<CURRENT_VERSION>(OWN_LEAF_NODES_TREE, &self.lookup_key(group_id)?)
     }
 
     fn delete_group_config<GroupId: traits::GroupId<CURRENT_VERSION>>(
         &self,
         group_id: &GroupId,
     ) -> Result<(), Self::Error> {
-        self.delete::<CURRENT_VERSION>(JOIN_CONFIG_TREE, &serde_json::to_vec(group_id)?)
+        self.delete::<CURRENT_VERSION>(JOIN_CONFIG_TREE, &self.lookup_key(group_id)?)
     }
 }
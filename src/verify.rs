@@ -0,0 +1,150 @@
+//! Integrity checks of a database.
+//!
+//! [`SledStorage::verify`] walks every tree and reports what it finds wrong
+//! instead of stopping at the first problem, so that a broken store can be
+//! diagnosed as a whole.
+
+use std::collections::BTreeSet;
+
+use openmls_traits::storage::CURRENT_VERSION;
+
+use crate::traits::{
+    CONFIRMATION_TAG_TREE, EPOCH_SECRETS_TREE, GROUP_CONTEXT_TREE, GROUP_STATE_TREE,
+    INTERIM_TRANSCRIPT_HASH_TREE, MESSAGE_SECRETS_TREE, OWN_LEAF_NODE_INDEX_TREE,
+    PROPOSAL_QUEUE_REFS_TREE, QUEUED_PROPOSAL_TREE, RATCHET_TREE_TREE, TREES,
+};
+use crate::{SledStorage, SledStorageError};
+
+/// Trees every group needs an entry in.
+const REQUIRED_GROUP_TREES: [&[u8]; 8] = [
+    GROUP_STATE_TREE,
+    GROUP_CONTEXT_TREE,
+    RATCHET_TREE_TREE,
+    INTERIM_TRANSCRIPT_HASH_TREE,
+    CONFIRMATION_TAG_TREE,
+    EPOCH_SECRETS_TREE,
+    MESSAGE_SECRETS_TREE,
+    OWN_LEAF_NODE_INDEX_TREE,
+];
+
+/// The outcome of [`SledStorage::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    /// The number of entries checked.
+    pub entries: usize,
+    /// The number of groups checked.
+    pub groups: usize,
+    /// Everything found wrong, in the order it was found.
+    pub issues: Vec<Issue>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if nothing was found wrong.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Something found wrong with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// The name of the tree.
+    pub tree: String,
+    /// The key of the entry. For a missing group entry, the lookup key of the
+    /// group.
+    pub key: Vec<u8>,
+    /// What is wrong.
+    pub kind: IssueKind,
+}
+
+/// What is wrong with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The stored value could not be decoded or decrypted.
+    Undecodable(SledStorageError),
+    /// The value was decoded, but is not valid JSON.
+    InvalidJson,
+    /// The proposal the reference points at is missing.
+    ProposalMissing,
+    /// No proposal reference points at the queued proposal.
+    ProposalUnreferenced,
+    /// The group has no entry in the tree, although every group needs one.
+    GroupEntryMissing,
+}
+
+impl SledStorage {
+    /// Checks the database for consistency.
+    ///
+    /// This checks that
+    ///
+    /// * every value in every tree can be decoded and holds valid JSON,
+    /// * every proposal reference points at a queued proposal and every queued
+    ///   proposal is referenced, and
+    /// * every group with a group state or a group context has a group state,
+    ///   group context, ratchet tree, interim transcript hash, confirmation
+    ///   tag, epoch secrets, message secrets and own leaf index.
+    ///
+    /// Nothing is changed.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `VerifyReport`, or a `SledStorageError` if the
+    /// database could not be read at all.
+    pub fn verify(&self) -> Result<VerifyReport, SledStorageError> {
+        tracing::debug!(target: "openmls_sled_storage::verify", "Verifying database");
+
+        let mut report = VerifyReport::default();
+        let mut issue = |tree: &[u8], key: &[u8], kind| {
+            report.issues.push(Issue {
+                tree: String::from_utf8_lossy(tree).into_owned(),
+                key: key.to_vec(),
+                kind,
+            })
+        };
+
+        let mut entries = 0;
+        for tree in TREES {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            for (key, value) in self.scan_prefix(&active_tree, &[])? {
+                entries += 1;
+                match self.decode_value(tree, &key, &value) {
+                    Ok(entity) => {
+                        if serde_json::from_slice::<serde::de::IgnoredAny>(&entity).is_err() {
+                            issue(tree, &key, IssueKind::InvalidJson);
+                        }
+                    }
+                    Err(error) => issue(tree, &key, IssueKind::Undecodable(error)),
+                }
+            }
+        }
+
+        let (missing, unreferenced) = self.proposal_queue_mismatches(|_| true)?;
+        for item_key in missing {
+            issue(
+                PROPOSAL_QUEUE_REFS_TREE,
+                &item_key,
+                IssueKind::ProposalMissing,
+            );
+        }
+        for key in unreferenced {
+            issue(QUEUED_PROPOSAL_TREE, &key, IssueKind::ProposalUnreferenced);
+        }
+
+        let groups = self.group_ids()?.into_iter().collect::<BTreeSet<_>>();
+        for tree in REQUIRED_GROUP_TREES {
+            let active_tree = self.open_tree::<CURRENT_VERSION>(tree)?;
+            for group_key in &groups {
+                if self.get(&active_tree, group_key)?.is_none() {
+                    issue(tree, group_key, IssueKind::GroupEntryMissing);
+                }
+            }
+        }
+
+        report.entries = entries;
+        report.groups = groups.len();
+        if !report.is_ok() {
+            tracing::warn!(target: "openmls_sled_storage::verify", "Found {} issues", report.issues.len());
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,102 @@
+use openmls_sled_storage::SledStorage;
+use openmls_traits::storage::{
+    traits::{self},
+    Entity, Key, StorageProvider, CURRENT_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+const THREADS: usize = 8;
+const ITEMS_PER_THREAD: usize = 50;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct LeafNode(usize, usize);
+impl traits::LeafNode<CURRENT_VERSION> for LeafNode {}
+impl Entity<CURRENT_VERSION> for LeafNode {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct ProposalRef(usize);
+impl traits::ProposalRef<CURRENT_VERSION> for ProposalRef {}
+impl Key<CURRENT_VERSION> for ProposalRef {}
+impl Entity<CURRENT_VERSION> for ProposalRef {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Proposal(Vec<u8>);
+impl traits::QueuedProposal<CURRENT_VERSION> for Proposal {}
+impl Entity<CURRENT_VERSION> for Proposal {}
+
+/// Own leaf nodes appended from many threads at once are all kept
+#[test]
+fn concurrent_own_leaf_node_appends() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    std::thread::scope(|s| {
+        for thread in 0..THREADS {
+            let (storage, group_id) = (&storage, &group_id);
+            s.spawn(move || {
+                for i in 0..ITEMS_PER_THREAD {
+                    storage
+                        .append_own_leaf_node(group_id, &LeafNode(thread, i))
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let leaf_nodes: Vec<LeafNode> = storage.own_leaf_nodes(&group_id).unwrap();
+    assert_eq!(leaf_nodes.len(), THREADS * ITEMS_PER_THREAD);
+    for thread in 0..THREADS {
+        let appended: Vec<usize> = leaf_nodes
+            .iter()
+            .filter(|leaf_node| leaf_node.0 == thread)
+            .map(|leaf_node| leaf_node.1)
+            .collect();
+        assert_eq!(appended, (0..ITEMS_PER_THREAD).collect::<Vec<_>>());
+    }
+}
+
+/// Proposals queued and removed from many threads at once keep the queue consistent
+#[test]
+fn concurrent_proposal_queue_updates() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+    let proposal_ref = |thread: usize, i: usize| ProposalRef(thread * ITEMS_PER_THREAD + i);
+
+    std::thread::scope(|s| {
+        for thread in 0..THREADS {
+            let (storage, group_id) = (&storage, &group_id);
+            s.spawn(move || {
+                for i in 0..ITEMS_PER_THREAD {
+                    let proposal = Proposal(format!("{thread}-{i}").into_bytes());
+                    storage
+                        .queue_proposal(group_id, &proposal_ref(thread, i), &proposal)
+                        .unwrap();
+                }
+                // Remove every other proposal while the other threads are still queueing.
+                for i in (0..ITEMS_PER_THREAD).step_by(2) {
+                    storage
+                        .remove_proposal(group_id, &proposal_ref(thread, i))
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    let proposals: Vec<(ProposalRef, Proposal)> = storage.queued_proposals(&group_id).unwrap();
+    assert_eq!(proposals.len(), THREADS * ITEMS_PER_THREAD / 2);
+    for (proposal_ref, proposal) in proposals {
+        let (thread, i) = (
+            proposal_ref.0 / ITEMS_PER_THREAD,
+            proposal_ref.0 % ITEMS_PER_THREAD,
+        );
+        assert_eq!(i % 2, 1);
+        assert_eq!(proposal, Proposal(format!("{thread}-{i}").into_bytes()));
+    }
+}
@@ -0,0 +1,32 @@
+use openmls_sled_storage::test_utils::run_conformance_suite;
+use openmls_sled_storage::{Encryption, SledStorage, SledStorageConfig, StorageKey};
+use tempfile::tempdir;
+
+/// SledStorage passes the conformance suite
+#[test]
+fn conformance() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    run_conformance_suite(&SledStorage::new_from_db(db).unwrap());
+}
+
+/// SledStorage passes the conformance suite with encryption and hashed lookup keys
+#[test]
+fn conformance_with_encryption() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    run_conformance_suite(&SledStorage::new_from_db_with_config(db, config).unwrap());
+}
+
+/// Writes made through a batch pass the conformance suite
+#[test]
+fn conformance_in_batch() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db).unwrap();
+    let batch = storage.begin_batch();
+    run_conformance_suite(&*batch);
+    batch.commit().unwrap();
+}
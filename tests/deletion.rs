@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+
+use openmls_sled_storage::{Encryption, SledStorage, SledStorageConfig, StorageKey};
+use openmls_traits::storage::{
+    traits::{self},
+    Entity, Key, StorageProvider, CURRENT_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestKey(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestKey {}
+impl traits::SignaturePublicKey<CURRENT_VERSION> for TestKey {}
+impl traits::EncryptionKey<CURRENT_VERSION> for TestKey {}
+impl traits::HashReference<CURRENT_VERSION> for TestKey {}
+impl traits::PskId<CURRENT_VERSION> for TestKey {}
+impl traits::EpochKey<CURRENT_VERSION> for TestKey {}
+impl Key<CURRENT_VERSION> for TestKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct ProposalRef(usize);
+impl traits::ProposalRef<CURRENT_VERSION> for ProposalRef {}
+impl Key<CURRENT_VERSION> for ProposalRef {}
+impl Entity<CURRENT_VERSION> for ProposalRef {}
+
+/// An entity standing in for every kind of value.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Value(String);
+impl Entity<CURRENT_VERSION> for Value {}
+impl traits::TreeSync<CURRENT_VERSION> for Value {}
+impl traits::GroupContext<CURRENT_VERSION> for Value {}
+impl traits::InterimTranscriptHash<CURRENT_VERSION> for Value {}
+impl traits::ConfirmationTag<CURRENT_VERSION> for Value {}
+impl traits::GroupState<CURRENT_VERSION> for Value {}
+impl traits::MessageSecrets<CURRENT_VERSION> for Value {}
+impl traits::ResumptionPskStore<CURRENT_VERSION> for Value {}
+impl traits::LeafNodeIndex<CURRENT_VERSION> for Value {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for Value {}
+impl traits::SignatureKeyPair<CURRENT_VERSION> for Value {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for Value {}
+impl traits::KeyPackage<CURRENT_VERSION> for Value {}
+impl traits::PskBundle<CURRENT_VERSION> for Value {}
+impl traits::MlsGroupJoinConfig<CURRENT_VERSION> for Value {}
+impl traits::LeafNode<CURRENT_VERSION> for Value {}
+impl traits::QueuedProposal<CURRENT_VERSION> for Value {}
+
+type Op = fn(&SledStorage, &TestKey);
+
+/// Every way of storing something, paired with the call that deletes it again.
+const CASES: &[(&str, Op, Op)] = &[
+    (
+        "tree",
+        |s, k| s.write_tree(k, &value(k)).unwrap(),
+        |s, k| s.delete_tree(k).unwrap(),
+    ),
+    (
+        "interim transcript hash",
+        |s, k| s.write_interim_transcript_hash(k, &value(k)).unwrap(),
+        |s, k| s.delete_interim_transcript_hash(k).unwrap(),
+    ),
+    (
+        "context",
+        |s, k| s.write_context(k, &value(k)).unwrap(),
+        |s, k| s.delete_context(k).unwrap(),
+    ),
+    (
+        "group state",
+        |s, k| s.write_group_state(k, &value(k)).unwrap(),
+        |s, k| s.delete_group_state(k).unwrap(),
+    ),
+    (
+        "confirmation tag",
+        |s, k| s.write_confirmation_tag(k, &value(k)).unwrap(),
+        |s, k| s.delete_confirmation_tag(k).unwrap(),
+    ),
+    (
+        "signature key pair",
+        |s, k| s.write_signature_key_pair(k, &value(k)).unwrap(),
+        |s, k| s.delete_signature_key_pair::<TestKey>(k).unwrap(),
+    ),
+    (
+        "encryption key pair",
+        |s, k| s.write_encryption_key_pair(k, &value(k)).unwrap(),
+        |s, k| s.delete_encryption_key_pair(k).unwrap(),
+    ),
+    (
+        "key package",
+        |s, k| s.write_key_package(k, &value(k)).unwrap(),
+        |s, k| s.delete_key_package(k).unwrap(),
+    ),
+    (
+        "psk",
+        |s, k| s.write_psk(k, &value(k)).unwrap(),
+        |s, k| s.delete_psk(k).unwrap(),
+    ),
+    (
+        "message secrets",
+        |s, k| s.write_message_secrets(k, &value(k)).unwrap(),
+        |s, k| s.delete_message_secrets(k).unwrap(),
+    ),
+    (
+        "resumption psk store",
+        |s, k| s.write_resumption_psk_store(k, &value(k)).unwrap(),
+        |s, k| s.delete_all_resumption_psk_secrets(k).unwrap(),
+    ),
+    (
+        "own leaf index",
+        |s, k| s.write_own_leaf_index(k, &value(k)).unwrap(),
+        |s, k| s.delete_own_leaf_index(k).unwrap(),
+    ),
+    (
+        "group epoch secrets",
+        |s, k| s.write_group_epoch_secrets(k, &value(k)).unwrap(),
+        |s, k| s.delete_group_epoch_secrets(k).unwrap(),
+    ),
+    (
+        "encryption epoch key pairs",
+        |s, k| {
+            s.write_encryption_epoch_key_pairs(k, k, 7, &[value(k), value(k)])
+                .unwrap()
+        },
+        |s, k| s.delete_encryption_epoch_key_pairs(k, k, 7).unwrap(),
+    ),
+    (
+        "join config",
+        |s, k| s.write_mls_join_config(k, &value(k)).unwrap(),
+        |s, k| s.delete_group_config(k).unwrap(),
+    ),
+    (
+        "own leaf nodes",
+        |s, k| {
+            s.append_own_leaf_node(k, &value(k)).unwrap();
+            s.append_own_leaf_node(k, &value(k)).unwrap();
+        },
+        |s, k| s.delete_own_leaf_nodes(k).unwrap(),
+    ),
+    ("removed proposals", queue_proposals, |s, k| {
+        for i in 0..3 {
+            s.remove_proposal(k, &ProposalRef(i)).unwrap();
+        }
+    }),
+    ("cleared proposal queue", queue_proposals, |s, k| {
+        s.clear_proposal_queue::<TestKey, ProposalRef>(k).unwrap()
+    }),
+];
+
+fn value(key: &TestKey) -> Value {
+    Value(String::from_utf8(key.0.clone()).unwrap())
+}
+
+fn queue_proposals(storage: &SledStorage, key: &TestKey) {
+    for i in 0..3 {
+        storage
+            .queue_proposal(key, &ProposalRef(i), &value(key))
+            .unwrap();
+    }
+}
+
+/// Returns every entry of the database, except for storage metadata.
+fn entries(db: &sled::Db) -> BTreeMap<(Vec<u8>, Vec<u8>), Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    for name in db.tree_names() {
+        if name == "__openmls_sled_storage_meta" || name == "__sled__default" {
+            continue;
+        }
+        for entry in db.open_tree(&name).unwrap().iter() {
+            let (key, value) = entry.unwrap();
+            entries.insert((name.to_vec(), key.to_vec()), value.to_vec());
+        }
+    }
+    entries
+}
+
+fn check_no_residual_keys(storage: SledStorage, db: sled::Db) {
+    // The JSON encoding of the first key is a prefix of the second one's
+    // bytes, but not of its encoding.
+    let key = TestKey(b"key".to_vec());
+    let other = TestKey(b"key1".to_vec());
+
+    for (name, write, delete) in CASES {
+        write(&storage, &other);
+        let before = entries(&db);
+
+        write(&storage, &key);
+        assert_ne!(entries(&db), before, "{name} wrote nothing");
+        delete(&storage, &key);
+        assert_eq!(entries(&db), before, "{name} left entries behind");
+
+        delete(&storage, &other);
+        assert!(entries(&db).is_empty(), "{name} left entries behind");
+    }
+}
+
+/// Deleting something removes every key written for it, and nothing else
+#[test]
+fn no_residual_keys() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    check_no_residual_keys(SledStorage::new_from_db(db.clone()).unwrap(), db);
+}
+
+/// Deleting something removes every key written for it with hashed lookup keys
+#[test]
+fn no_residual_keys_with_hashed_keys() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    let storage = SledStorage::new_from_db_with_config(db.clone(), config).unwrap();
+    check_no_residual_keys(storage, db);
+}
@@ -0,0 +1,267 @@
+use openmls_sled_storage::{
+    helpers::versioned_tree, Encryption, ErrorContext, KeyFingerprint, Operation, SledStorage,
+    SledStorageConfig, SledStorageError, StorageKey,
+};
+use openmls_traits::storage::{
+    traits::{self},
+    Entity, Key, StorageProvider, CURRENT_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct EpochSecrets(String);
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for EpochSecrets {}
+impl Entity<CURRENT_VERSION> for EpochSecrets {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct GroupContext(String);
+impl traits::GroupContext<CURRENT_VERSION> for GroupContext {}
+impl Entity<CURRENT_VERSION> for GroupContext {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct LeafNode(String);
+impl traits::LeafNode<CURRENT_VERSION> for LeafNode {}
+impl Entity<CURRENT_VERSION> for LeafNode {}
+
+const SECRET: &str = "TopSecretEpochSecret";
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Values are encrypted on disk and only readable with the right key
+#[test]
+fn values_are_encrypted_at_rest() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage_key = StorageKey::generate();
+    let storage = SledStorage::new_from_db_with_key(db.clone(), storage_key.clone()).unwrap();
+    assert!(storage.is_encrypted());
+
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+    storage
+        .write_group_epoch_secrets(&group_id, &EpochSecrets(SECRET.to_string()))
+        .unwrap();
+    storage
+        .append_own_leaf_node(&group_id, &LeafNode(SECRET.to_string()))
+        .unwrap();
+
+    // Nothing readable ends up in sled.
+    for name in db.tree_names() {
+        for entry in db.open_tree(&name).unwrap().iter() {
+            let (_, value) = entry.unwrap();
+            assert!(!contains(&value, SECRET.as_bytes()));
+        }
+    }
+
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+    let leaf_nodes: Vec<LeafNode> = storage.own_leaf_nodes(&group_id).unwrap();
+    assert_eq!(leaf_nodes, vec![LeafNode(SECRET.to_string())]);
+
+    // Another key, or no key at all, cannot read the values.
+    let other = SledStorage::new_from_db_with_key(db.clone(), StorageKey::generate()).unwrap();
+    assert!(matches!(
+        other.group_epoch_secrets::<TestGroupId, EpochSecrets>(&group_id),
+        Err(SledStorageError::DecryptionError { .. })
+    ));
+    let plaintext = SledStorage::new_from_db(db.clone()).unwrap();
+    assert!(matches!(
+        plaintext.own_leaf_nodes::<TestGroupId, LeafNode>(&group_id),
+        Err(SledStorageError::DecryptionError { .. })
+    ));
+
+    // Reopening with the same key works.
+    drop(storage);
+    let storage = SledStorage::new_from_db_with_key(db, storage_key).unwrap();
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+}
+
+/// A value copied to another key cannot be read there
+#[test]
+fn values_cannot_be_swapped() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db_with_key(db.clone(), StorageKey::generate()).unwrap();
+
+    let alice = TestGroupId(b"alice".to_vec());
+    let bob = TestGroupId(b"bob".to_vec());
+    storage
+        .write_group_epoch_secrets(&alice, &EpochSecrets("alice".to_string()))
+        .unwrap();
+    storage
+        .write_group_epoch_secrets(&bob, &EpochSecrets("bob".to_string()))
+        .unwrap();
+
+    let tree = db
+        .open_tree(versioned_tree::<CURRENT_VERSION>(b"EpochSecrets"))
+        .unwrap();
+    let alice_key = serde_json::to_vec(&alice).unwrap();
+    let bob_key = serde_json::to_vec(&bob).unwrap();
+    tree.insert(&bob_key, tree.get(&alice_key).unwrap().unwrap())
+        .unwrap();
+
+    assert_eq!(
+        storage.group_epoch_secrets::<TestGroupId, EpochSecrets>(&bob),
+        Err(SledStorageError::DecryptionError {
+            context: ErrorContext {
+                operation: Some(Operation::Read),
+                tree: Some("EpochSecrets".to_string()),
+                key: Some(KeyFingerprint::of(&bob_key)),
+            }
+        })
+    );
+
+    // Moving a value to another tree does not work either.
+    let context = db
+        .open_tree(versioned_tree::<CURRENT_VERSION>(b"GroupContext"))
+        .unwrap();
+    context
+        .insert(&alice_key, tree.get(&alice_key).unwrap().unwrap())
+        .unwrap();
+    assert!(matches!(
+        storage.group_context::<TestGroupId, GroupContext>(&alice),
+        Err(SledStorageError::DecryptionError { .. })
+    ));
+}
+
+/// The storage key can be derived from a passphrase
+#[test]
+fn open_with_passphrase() {
+    let dir = tempdir().unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let storage = SledStorage::open_with_passphrase(dir.path(), "correct horse").unwrap();
+    assert!(storage.is_encrypted());
+    storage
+        .write_group_epoch_secrets(&group_id, &EpochSecrets(SECRET.to_string()))
+        .unwrap();
+    drop(storage);
+
+    assert_eq!(
+        SledStorage::open_with_passphrase(dir.path(), "battery staple").err(),
+        Some(SledStorageError::WrongPassphrase)
+    );
+
+    let storage = SledStorage::open_with_passphrase(dir.path(), "correct horse").unwrap();
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+}
+
+/// Changing the passphrase re-encrypts the database with the new key
+#[test]
+fn change_passphrase() {
+    let dir = tempdir().unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let mut storage = SledStorage::open_with_passphrase(dir.path(), "correct horse").unwrap();
+    storage
+        .write_group_epoch_secrets(&group_id, &EpochSecrets(SECRET.to_string()))
+        .unwrap();
+
+    assert_eq!(
+        storage.rekey(&StorageKey::generate(), &StorageKey::generate()),
+        Err(SledStorageError::PassphraseProtected)
+    );
+    assert_eq!(
+        storage.change_passphrase("wrong", "battery staple"),
+        Err(SledStorageError::WrongPassphrase)
+    );
+    storage
+        .change_passphrase("correct horse", "battery staple")
+        .unwrap();
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+    drop(storage);
+
+    assert_eq!(
+        SledStorage::open_with_passphrase(dir.path(), "correct horse").err(),
+        Some(SledStorageError::WrongPassphrase)
+    );
+    let storage = SledStorage::open_with_passphrase(dir.path(), "battery staple").unwrap();
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+}
+
+fn meta_entries(db: &sled::Db) -> Vec<(sled::IVec, sled::IVec)> {
+    db.open_tree(b"__openmls_sled_storage_meta")
+        .unwrap()
+        .iter()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+/// A rejected passphrase change leaves the meta tree untouched
+#[test]
+fn rejected_change_passphrase_writes_nothing() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut storage =
+        SledStorage::new_from_db_with_key(db.clone(), StorageKey::generate()).unwrap();
+    let meta = meta_entries(&db);
+    assert!(matches!(
+        storage.change_passphrase("correct horse", "battery staple"),
+        Err(SledStorageError::InvalidConfig(_))
+    ));
+    assert_eq!(meta_entries(&db), meta);
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut storage =
+        SledStorage::new_from_db_with_passphrase(db.clone(), "correct horse").unwrap();
+    let meta = meta_entries(&db);
+    assert_eq!(
+        storage.change_passphrase("wrong", "battery staple"),
+        Err(SledStorageError::WrongPassphrase)
+    );
+    assert_eq!(meta_entries(&db), meta);
+}
+
+/// Lookup keys can be stored as keyed hashes that survive a rekey
+#[test]
+fn lookup_keys_are_hashed() {
+    let dir = tempdir().unwrap();
+    let storage_key = StorageKey::generate();
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(storage_key.clone()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    let mut storage = SledStorage::new_from_path_with_config(dir.path(), config).unwrap();
+    assert!(storage.hashes_lookup_keys());
+
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+    storage
+        .write_group_epoch_secrets(&group_id, &EpochSecrets(SECRET.to_string()))
+        .unwrap();
+    storage
+        .append_own_leaf_node(&group_id, &LeafNode(SECRET.to_string()))
+        .unwrap();
+
+    let new_key = StorageKey::generate();
+    storage.rekey(&storage_key, &new_key).unwrap();
+    drop(storage);
+
+    // The group id does not appear in any key.
+    let db = sled::open(dir.path()).unwrap();
+    for name in db.tree_names() {
+        for entry in db.open_tree(&name).unwrap().iter() {
+            let (key, _) = entry.unwrap();
+            assert!(!contains(&key, b"TestGroupId"));
+        }
+    }
+    drop(db);
+
+    let storage = SledStorage::new_from_path_with_key(dir.path(), new_key).unwrap();
+    assert!(storage.hashes_lookup_keys());
+    let secrets: Option<EpochSecrets> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, Some(EpochSecrets(SECRET.to_string())));
+    let leaf_nodes: Vec<LeafNode> = storage.own_leaf_nodes(&group_id).unwrap();
+    assert_eq!(leaf_nodes, vec![LeafNode(SECRET.to_string())]);
+}
@@ -0,0 +1,451 @@
+use openmls_sled_storage::{
+    helpers::versioned_tree, CleanupOptions, Encryption, IssueKind, Orphan, OrphanReason,
+    OrphanReport, SledStorage, SledStorageConfig, StorageKey,
+};
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct TestEpochKey(u64);
+impl traits::EpochKey<CURRENT_VERSION> for TestEpochKey {}
+impl Key<CURRENT_VERSION> for TestEpochKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct GroupContext {
+    epoch: u64,
+}
+impl traits::GroupContext<CURRENT_VERSION> for GroupContext {}
+impl traits::TreeSync<CURRENT_VERSION> for GroupContext {}
+impl Entity<CURRENT_VERSION> for GroupContext {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct ContextWithoutEpoch(String);
+impl traits::GroupContext<CURRENT_VERSION> for ContextWithoutEpoch {}
+impl Entity<CURRENT_VERSION> for ContextWithoutEpoch {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct LeafIndex(u32);
+impl traits::LeafNodeIndex<CURRENT_VERSION> for LeafIndex {}
+impl Entity<CURRENT_VERSION> for LeafIndex {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct KeyPair(u64);
+impl traits::HpkeKeyPair<CURRENT_VERSION> for KeyPair {}
+impl Entity<CURRENT_VERSION> for KeyPair {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct ProposalRef(u8);
+impl traits::ProposalRef<CURRENT_VERSION> for ProposalRef {}
+impl Key<CURRENT_VERSION> for ProposalRef {}
+impl Entity<CURRENT_VERSION> for ProposalRef {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Proposal(String);
+impl traits::QueuedProposal<CURRENT_VERSION> for Proposal {}
+impl Entity<CURRENT_VERSION> for Proposal {}
+
+/// A public key wrapped the way OpenMLS wraps key bytes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct PublicKey {
+    value: Bytes,
+}
+impl traits::EncryptionKey<CURRENT_VERSION> for PublicKey {}
+impl traits::SignaturePublicKey<CURRENT_VERSION> for PublicKey {}
+impl Key<CURRENT_VERSION> for PublicKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Bytes {
+    vec: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct LeafNode {
+    encryption_key: Bytes,
+    signature_key: Bytes,
+}
+impl traits::LeafNode<CURRENT_VERSION> for LeafNode {}
+impl Entity<CURRENT_VERSION> for LeafNode {}
+
+impl traits::SignatureKeyPair<CURRENT_VERSION> for KeyPair {}
+
+const LEAF_INDEX: u32 = 3;
+const OTHER_LEAF_INDEX: u32 = 5;
+
+fn public_key(byte: u8) -> PublicKey {
+    PublicKey {
+        value: Bytes {
+            vec: vec![byte; 32],
+        },
+    }
+}
+
+fn write_key_pairs(storage: &SledStorage, group_id: &TestGroupId, epoch: u64) {
+    write_leaf_key_pairs(storage, group_id, epoch, LEAF_INDEX);
+}
+
+fn write_leaf_key_pairs(storage: &SledStorage, group_id: &TestGroupId, epoch: u64, leaf: u32) {
+    storage
+        .write_encryption_epoch_key_pairs(group_id, &TestEpochKey(epoch), leaf, &[KeyPair(epoch)])
+        .unwrap();
+}
+
+fn has_key_pairs(storage: &SledStorage, group_id: &TestGroupId, epoch: u64, leaf: u32) -> bool {
+    !storage
+        .encryption_epoch_key_pairs::<TestGroupId, TestEpochKey, KeyPair>(
+            group_id,
+            &TestEpochKey(epoch),
+            leaf,
+        )
+        .unwrap()
+        .is_empty()
+}
+
+fn check_collect_stale_epoch_key_pairs(storage: SledStorage, hashed: bool) {
+    let group = TestGroupId(b"group".to_vec());
+    let unknown_epoch = TestGroupId(b"unknown epoch".to_vec());
+    for epoch in 0..12 {
+        write_leaf_key_pairs(&storage, &group, epoch, LEAF_INDEX);
+        write_leaf_key_pairs(&storage, &group, epoch, OTHER_LEAF_INDEX);
+        write_key_pairs(&storage, &unknown_epoch, epoch);
+    }
+    storage
+        .write_context(&group, &GroupContext { epoch: 10 })
+        .unwrap();
+    storage
+        .write_own_leaf_index(&group, &LeafIndex(LEAF_INDEX))
+        .unwrap();
+    // A group whose context has no epoch is left alone.
+    storage
+        .write_context(&unknown_epoch, &ContextWithoutEpoch("no epoch".to_string()))
+        .unwrap();
+    storage
+        .write_own_leaf_index(&unknown_epoch, &LeafIndex(LEAF_INDEX))
+        .unwrap();
+
+    // Epochs 8 and 9 are retained, as are the current and the next epoch.
+    // Other leaves can only be told apart with plain lookup keys.
+    let deleted = if hashed { 8 } else { 16 };
+    assert_eq!(storage.collect_stale_epoch_key_pairs(2).unwrap(), deleted);
+    for epoch in 0..12 {
+        assert_eq!(
+            has_key_pairs(&storage, &group, epoch, LEAF_INDEX),
+            epoch >= 8,
+            "{epoch}"
+        );
+        assert_eq!(
+            has_key_pairs(&storage, &group, epoch, OTHER_LEAF_INDEX),
+            hashed || epoch >= 8,
+            "{epoch}"
+        );
+        assert!(has_key_pairs(&storage, &unknown_epoch, epoch, LEAF_INDEX));
+    }
+    assert_eq!(storage.collect_stale_epoch_key_pairs(2).unwrap(), 0);
+
+    storage
+        .write_context(&group, &GroupContext { epoch: 11 })
+        .unwrap();
+    let deleted = if hashed { 3 } else { 6 };
+    assert_eq!(storage.collect_stale_epoch_key_pairs(0).unwrap(), deleted);
+    assert!(has_key_pairs(&storage, &group, 11, LEAF_INDEX));
+    assert!(!has_key_pairs(&storage, &group, 10, LEAF_INDEX));
+    assert!(has_key_pairs(&storage, &group, 11, OTHER_LEAF_INDEX));
+}
+
+/// Key pairs of epochs outside the retention window are deleted
+#[test]
+fn collect_stale_epoch_key_pairs() {
+    check_collect_stale_epoch_key_pairs(
+        SledStorage::new_from_path(tempdir().unwrap().path()).unwrap(),
+        false,
+    );
+}
+
+/// Stale key pairs of the own leaf are found with hashed lookup keys
+#[test]
+fn collect_stale_epoch_key_pairs_with_hashed_keys() {
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    check_collect_stale_epoch_key_pairs(
+        SledStorage::new_from_path_with_config(tempdir().unwrap().path(), config).unwrap(),
+        true,
+    );
+}
+
+/// With hashed lookup keys, epochs that have been collected are not looked up
+/// again
+#[test]
+fn collected_epochs_are_not_looked_up_again() {
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    let storage =
+        SledStorage::new_from_path_with_config(tempdir().unwrap().path(), config).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    for epoch in 0..4 {
+        write_leaf_key_pairs(&storage, &group, epoch, LEAF_INDEX);
+    }
+    storage
+        .write_context(&group, &GroupContext { epoch: 3 })
+        .unwrap();
+    storage
+        .write_own_leaf_index(&group, &LeafIndex(LEAF_INDEX))
+        .unwrap();
+    assert_eq!(storage.collect_stale_epoch_key_pairs(0).unwrap(), 3);
+
+    // Only epochs from 3 on are looked up from now on.
+    write_leaf_key_pairs(&storage, &group, 0, LEAF_INDEX);
+    storage
+        .write_context(&group, &GroupContext { epoch: 5 })
+        .unwrap();
+    assert_eq!(storage.collect_stale_epoch_key_pairs(0).unwrap(), 1);
+    assert!(has_key_pairs(&storage, &group, 0, LEAF_INDEX));
+    assert!(!has_key_pairs(&storage, &group, 3, LEAF_INDEX));
+}
+
+/// A group whose context cannot be decoded is skipped
+#[test]
+fn undecodable_context_is_skipped() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db.clone()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    for epoch in 0..3 {
+        write_key_pairs(&storage, &group, epoch);
+    }
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"GroupContext"))
+        .unwrap()
+        .insert(serde_json::to_vec(&group).unwrap(), b"garbage".to_vec())
+        .unwrap();
+
+    assert_eq!(storage.collect_stale_epoch_key_pairs(0).unwrap(), 0);
+    assert!(has_key_pairs(&storage, &group, 0, LEAF_INDEX));
+}
+
+/// Entries nothing leads to are found and cleaned up
+#[test]
+fn orphans() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db.clone()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    let gone = TestGroupId(b"gone".to_vec());
+
+    storage
+        .write_context(&group, &GroupContext { epoch: 1 })
+        .unwrap();
+    storage
+        .append_own_leaf_node(
+            &group,
+            &LeafNode {
+                encryption_key: public_key(1).value,
+                signature_key: public_key(2).value,
+            },
+        )
+        .unwrap();
+    storage
+        .write_encryption_key_pair(&public_key(1), &KeyPair(1))
+        .unwrap();
+    storage
+        .write_signature_key_pair(&public_key(2), &KeyPair(2))
+        .unwrap();
+    write_key_pairs(&storage, &group, 1);
+    for i in 0..2 {
+        storage
+            .queue_proposal(&group, &ProposalRef(i), &Proposal(i.to_string()))
+            .unwrap();
+    }
+    assert_eq!(storage.orphans().unwrap(), OrphanReport::default());
+
+    // State of a group that was not deleted completely.
+    write_key_pairs(&storage, &gone, 1);
+    storage
+        .write_tree(&gone, &GroupContext { epoch: 1 })
+        .unwrap();
+    // Key pairs nothing uses anymore.
+    storage
+        .write_encryption_key_pair(&public_key(3), &KeyPair(3))
+        .unwrap();
+    storage
+        .write_signature_key_pair(&public_key(4), &KeyPair(4))
+        .unwrap();
+    // A proposal reference without its proposal and a proposal without a
+    // reference.
+    let proposals = db
+        .open_tree(versioned_tree::<CURRENT_VERSION>(b"QueuedProposal"))
+        .unwrap();
+    let missing = serde_json::to_vec(&(&group, ProposalRef(0))).unwrap();
+    let unreferenced = serde_json::to_vec(&(&group, ProposalRef(9))).unwrap();
+    let proposal = proposals.remove(&missing).unwrap().unwrap();
+    proposals.insert(&unreferenced, proposal).unwrap();
+
+    let report = storage.orphans().unwrap();
+    assert!(report.issues.is_empty());
+    let orphans = report.orphans.clone();
+    let reasons = |tree: &str| {
+        orphans
+            .iter()
+            .filter(|orphan| orphan.tree == tree)
+            .map(|orphan| orphan.reason)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(orphans.len(), 6);
+    assert_eq!(reasons("RatchetTree"), [OrphanReason::GroupMissing]);
+    assert_eq!(reasons("EpochKeyPairs"), [OrphanReason::GroupMissing]);
+    assert_eq!(
+        reasons("ProposalQueueRefs"),
+        [OrphanReason::ProposalMissing]
+    );
+    assert_eq!(
+        orphans
+            .iter()
+            .find(|orphan| orphan.tree == "QueuedProposal"),
+        Some(&Orphan {
+            tree: "QueuedProposal".to_string(),
+            key: unreferenced,
+            reason: OrphanReason::ProposalUnreferenced,
+        })
+    );
+    assert_eq!(
+        reasons("EncryptionKeyPair"),
+        [OrphanReason::PublicKeyUnreferenced]
+    );
+    assert_eq!(
+        reasons("SignatureKeyPair"),
+        [OrphanReason::PublicKeyUnreferenced]
+    );
+
+    // A dry run only reports. Signature key pairs are kept by default.
+    let mut deleted = report.clone();
+    deleted
+        .orphans
+        .retain(|orphan| orphan.tree != "SignatureKeyPair");
+    let dry_run = CleanupOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    assert_eq!(storage.cleanup_orphans(dry_run).unwrap(), deleted);
+    assert_eq!(storage.orphans().unwrap(), report);
+
+    assert_eq!(
+        storage.cleanup_orphans(CleanupOptions::default()).unwrap(),
+        deleted
+    );
+    assert_eq!(
+        storage.orphans().unwrap().orphans,
+        [Orphan {
+            tree: "SignatureKeyPair".to_string(),
+            key: serde_json::to_vec(&public_key(4)).unwrap(),
+            reason: OrphanReason::PublicKeyUnreferenced,
+        }]
+    );
+    let key_pair: Option<KeyPair> = storage.encryption_key_pair(&public_key(1)).unwrap();
+    assert_eq!(key_pair, Some(KeyPair(1)));
+    let key_pair: Option<KeyPair> = storage.signature_key_pair(&public_key(4)).unwrap();
+    assert_eq!(key_pair, Some(KeyPair(4)));
+    let proposals = storage
+        .queued_proposals::<TestGroupId, ProposalRef, Proposal>(&group)
+        .unwrap();
+    assert_eq!(proposals, vec![(ProposalRef(1), Proposal("1".to_string()))]);
+
+    // Deleting signature key pairs has to be asked for.
+    let options = CleanupOptions {
+        delete_signature_key_pairs: true,
+        ..Default::default()
+    };
+    assert_eq!(storage.cleanup_orphans(options).unwrap().orphans.len(), 1);
+    assert!(storage.orphans().unwrap().orphans.is_empty());
+    let key_pair: Option<KeyPair> = storage.signature_key_pair(&public_key(2)).unwrap();
+    assert_eq!(key_pair, Some(KeyPair(2)));
+    let key_pair: Option<KeyPair> = storage.signature_key_pair(&public_key(4)).unwrap();
+    assert_eq!(key_pair, None);
+}
+
+/// The signature key pair of an account is kept before anything uses it
+#[test]
+fn unused_signature_key_pair_is_kept() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    storage
+        .write_signature_key_pair(&public_key(1), &KeyPair(1))
+        .unwrap();
+
+    let report = storage.cleanup_orphans(CleanupOptions::default()).unwrap();
+    assert!(report.orphans.is_empty());
+    assert_eq!(storage.orphans().unwrap().orphans.len(), 1);
+    let key_pair: Option<KeyPair> = storage.signature_key_pair(&public_key(1)).unwrap();
+    assert_eq!(key_pair, Some(KeyPair(1)));
+}
+
+/// Public keys are matched against arrays of bytes, not text that looks like
+/// one
+#[test]
+fn public_keys_match_arrays_of_bytes() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    storage
+        .write_context(&group, &GroupContext { epoch: 1 })
+        .unwrap();
+    storage
+        .write_encryption_key_pair(&public_key(5), &KeyPair(5))
+        .unwrap();
+    let text = serde_json::to_string(&public_key(5).value.vec).unwrap();
+    storage
+        .queue_proposal(&group, &ProposalRef(0), &Proposal(text))
+        .unwrap();
+
+    assert_eq!(
+        storage.orphans().unwrap().orphans,
+        [Orphan {
+            tree: "EncryptionKeyPair".to_string(),
+            key: serde_json::to_vec(&public_key(5)).unwrap(),
+            reason: OrphanReason::PublicKeyUnreferenced,
+        }]
+    );
+}
+
+/// A value that cannot be decoded is reported and the scan goes on
+#[test]
+fn undecodable_value_is_an_issue() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db.clone()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    let gone = TestGroupId(b"gone".to_vec());
+    storage
+        .write_context(&group, &GroupContext { epoch: 1 })
+        .unwrap();
+    storage
+        .write_tree(&gone, &GroupContext { epoch: 1 })
+        .unwrap();
+    storage
+        .write_encryption_key_pair(&public_key(1), &KeyPair(1))
+        .unwrap();
+    let key = serde_json::to_vec(&group).unwrap();
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"GroupContext"))
+        .unwrap()
+        .insert(&key, b"garbage".to_vec())
+        .unwrap();
+
+    let report = storage.orphans().unwrap();
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].tree, "GroupContext");
+    assert_eq!(report.issues[0].key, key);
+    assert!(matches!(report.issues[0].kind, IssueKind::Undecodable(_)));
+    // The key pair may be used by the value that could not be checked.
+    assert_eq!(
+        report.orphans,
+        [Orphan {
+            tree: "RatchetTree".to_string(),
+            key: serde_json::to_vec(&gone).unwrap(),
+            reason: OrphanReason::GroupMissing,
+        }]
+    );
+}
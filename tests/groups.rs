@@ -0,0 +1,272 @@
+use openmls_sled_storage::{Encryption, SledStorage, SledStorageConfig, StorageKey};
+use openmls_traits::storage::{
+    traits::{self},
+    Entity, Key, StorageProvider, CURRENT_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestEpochKey(u64);
+impl traits::EpochKey<CURRENT_VERSION> for TestEpochKey {}
+impl Key<CURRENT_VERSION> for TestEpochKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct ProposalRef(usize);
+impl traits::ProposalRef<CURRENT_VERSION> for ProposalRef {}
+impl Key<CURRENT_VERSION> for ProposalRef {}
+impl Entity<CURRENT_VERSION> for ProposalRef {}
+
+/// An entity standing in for every kind of group state.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Value(String);
+impl Entity<CURRENT_VERSION> for Value {}
+impl traits::TreeSync<CURRENT_VERSION> for Value {}
+impl traits::GroupContext<CURRENT_VERSION> for Value {}
+impl traits::InterimTranscriptHash<CURRENT_VERSION> for Value {}
+impl traits::ConfirmationTag<CURRENT_VERSION> for Value {}
+impl traits::GroupState<CURRENT_VERSION> for Value {}
+impl traits::MessageSecrets<CURRENT_VERSION> for Value {}
+impl traits::ResumptionPskStore<CURRENT_VERSION> for Value {}
+impl traits::LeafNodeIndex<CURRENT_VERSION> for Value {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for Value {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for Value {}
+impl traits::MlsGroupJoinConfig<CURRENT_VERSION> for Value {}
+impl traits::LeafNode<CURRENT_VERSION> for Value {}
+impl traits::QueuedProposal<CURRENT_VERSION> for Value {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct LeafIndex(u32);
+impl traits::LeafNodeIndex<CURRENT_VERSION> for LeafIndex {}
+impl Entity<CURRENT_VERSION> for LeafIndex {}
+
+fn value(group_id: &TestGroupId) -> Value {
+    Value(String::from_utf8(group_id.0.clone()).unwrap())
+}
+
+fn write_group(storage: &SledStorage, group_id: &TestGroupId) {
+    let value = value(group_id);
+    storage.write_tree(group_id, &value).unwrap();
+    storage.write_context(group_id, &value).unwrap();
+    storage
+        .write_interim_transcript_hash(group_id, &value)
+        .unwrap();
+    storage.write_confirmation_tag(group_id, &value).unwrap();
+    storage.write_group_state(group_id, &value).unwrap();
+    storage.write_message_secrets(group_id, &value).unwrap();
+    storage
+        .write_resumption_psk_store(group_id, &value)
+        .unwrap();
+    storage.write_own_leaf_index(group_id, &value).unwrap();
+    storage.write_group_epoch_secrets(group_id, &value).unwrap();
+    storage.write_mls_join_config(group_id, &value).unwrap();
+    storage.append_own_leaf_node(group_id, &value).unwrap();
+    for epoch in 0..3 {
+        for leaf_index in 0..2 {
+            storage
+                .write_encryption_epoch_key_pairs(
+                    group_id,
+                    &TestEpochKey(epoch),
+                    leaf_index,
+                    &[value.clone(), value.clone()],
+                )
+                .unwrap();
+        }
+    }
+    for i in 0..3 {
+        storage
+            .queue_proposal(group_id, &ProposalRef(i), &value)
+            .unwrap();
+    }
+}
+
+/// Returns `true` if any state of the group can be read.
+fn has_state(storage: &SledStorage, group_id: &TestGroupId) -> bool {
+    let value = Some(value(group_id));
+    let mut found = vec![
+        storage.tree(group_id).unwrap() == value,
+        storage.group_context(group_id).unwrap() == value,
+        storage.interim_transcript_hash(group_id).unwrap() == value,
+        storage.confirmation_tag(group_id).unwrap() == value,
+        storage.group_state(group_id).unwrap() == value,
+        storage.message_secrets(group_id).unwrap() == value,
+        storage.resumption_psk_store(group_id).unwrap() == value,
+        storage.own_leaf_index(group_id).unwrap() == value,
+        storage.group_epoch_secrets(group_id).unwrap() == value,
+        storage.mls_group_join_config(group_id).unwrap() == value,
+        !storage
+            .own_leaf_nodes::<TestGroupId, Value>(group_id)
+            .unwrap()
+            .is_empty(),
+        !storage
+            .queued_proposals::<TestGroupId, ProposalRef, Value>(group_id)
+            .unwrap()
+            .is_empty(),
+    ];
+    for epoch in 0..3 {
+        for leaf_index in 0..2 {
+            found.push(
+                !storage
+                    .encryption_epoch_key_pairs::<TestGroupId, TestEpochKey, Value>(
+                        group_id,
+                        &TestEpochKey(epoch),
+                        leaf_index,
+                    )
+                    .unwrap()
+                    .is_empty(),
+            );
+        }
+    }
+    assert!(found.iter().all(|&f| f) || found.iter().all(|&f| !f));
+    found[0]
+}
+
+fn check_delete_group(storage: SledStorage, db: sled::Db) {
+    // The JSON encoding of the first id is a prefix of the second one's
+    // bytes, but not of its encoding.
+    let group = TestGroupId(b"group".to_vec());
+    let other = TestGroupId(b"group1".to_vec());
+    write_group(&storage, &group);
+    write_group(&storage, &other);
+    assert!(has_state(&storage, &group));
+
+    storage.delete_group(&group).unwrap();
+    assert!(!has_state(&storage, &group));
+    assert!(has_state(&storage, &other));
+
+    // Nothing is left behind, including list items of every epoch.
+    storage.delete_group(&other).unwrap();
+    for name in db.tree_names() {
+        if name == "__openmls_sled_storage_meta" || name == "__sled__default" {
+            continue;
+        }
+        assert!(db.open_tree(&name).unwrap().is_empty(), "{name:?}");
+    }
+}
+
+/// All state of a group is deleted in one call
+#[test]
+fn delete_group() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    check_delete_group(SledStorage::new_from_db(db.clone()).unwrap(), db);
+}
+
+/// Groups can be deleted with hashed lookup keys
+#[test]
+fn delete_group_with_hashed_keys() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    check_delete_group(
+        SledStorage::new_from_db_with_config(db.clone(), config).unwrap(),
+        db,
+    );
+}
+
+/// Deleting a group through a batch only takes effect on commit
+#[test]
+fn delete_group_in_batch() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    write_group(&storage, &group);
+
+    let batch = storage.begin_batch();
+    batch.delete_group(&group).unwrap();
+    assert!(!has_state(&batch, &group));
+    assert!(has_state(&storage, &group));
+
+    batch.commit().unwrap();
+    assert!(!has_state(&storage, &group));
+}
+
+/// Stored groups can be listed
+#[test]
+fn group_ids() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let alice = TestGroupId(b"alice".to_vec());
+    let bob = TestGroupId(b"bob".to_vec());
+    assert!(storage.group_ids().unwrap().is_empty());
+
+    write_group(&storage, &alice);
+    // A group with only a context is listed as well.
+    storage.write_context(&bob, &value(&bob)).unwrap();
+
+    assert_eq!(
+        storage.group_ids().unwrap(),
+        vec![
+            serde_json::to_vec(&alice).unwrap(),
+            serde_json::to_vec(&bob).unwrap()
+        ]
+    );
+    assert_eq!(
+        storage.group_ids_typed::<TestGroupId>().unwrap(),
+        vec![alice.clone(), bob]
+    );
+
+    storage.delete_group(&alice).unwrap();
+    assert_eq!(storage.group_ids().unwrap().len(), 1);
+}
+
+/// Group ids cannot be recovered from hashed lookup keys
+#[test]
+fn group_ids_with_hashed_keys() {
+    let config = SledStorageConfig {
+        encryption: Encryption::Key(StorageKey::generate()),
+        hash_lookup_keys: true,
+        ..Default::default()
+    };
+    let storage =
+        SledStorage::new_from_path_with_config(tempdir().unwrap().path(), config).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    write_group(&storage, &group);
+
+    let group_ids = storage.group_ids().unwrap();
+    assert_eq!(group_ids.len(), 1);
+    assert!(storage.group_ids_typed::<TestGroupId>().is_err());
+    assert_eq!(
+        storage.group_summary(&group_ids[0]).unwrap().trees.len(),
+        14
+    );
+}
+
+/// The summary of a group tells what is stored for it
+#[test]
+fn group_summary() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    write_group(&storage, &group);
+    storage.write_own_leaf_index(&group, &LeafIndex(7)).unwrap();
+
+    let group_key = storage.group_ids().unwrap().remove(0);
+    let summary = storage.group_summary(&group_key).unwrap();
+    assert_eq!(summary.own_leaf_index, Some(7));
+    assert_eq!(summary.trees.len(), 14);
+
+    let entries = |name: &str| {
+        summary
+            .trees
+            .iter()
+            .find(|tree| tree.name == name)
+            .map(|tree| tree.entries)
+    };
+    assert_eq!(entries("GroupState"), Some(1));
+    assert_eq!(entries("EpochKeyPairs"), Some(12));
+    assert_eq!(entries("QueuedProposal"), Some(3));
+    assert_eq!(entries("ProposalQueueRefs"), Some(3));
+    assert_eq!(entries("KeyPackage"), None);
+    assert!(summary.trees.iter().all(|tree| tree.bytes > 0));
+
+    storage.delete_group(&group).unwrap();
+    let summary = storage.group_summary(&group_key).unwrap();
+    assert!(summary.trees.is_empty());
+    assert_eq!(summary.own_leaf_index, None);
+}
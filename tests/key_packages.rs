@@ -0,0 +1,213 @@
+use std::time::{Duration, SystemTime};
+
+use openmls_sled_storage::{SledStorage, SledStorageConfig, SledStorageError};
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct HashRef(Vec<u8>);
+impl traits::HashReference<CURRENT_VERSION> for HashRef {}
+impl Key<CURRENT_VERSION> for HashRef {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct KeyPackage(String);
+impl traits::KeyPackage<CURRENT_VERSION> for KeyPackage {}
+impl Entity<CURRENT_VERSION> for KeyPackage {}
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn hash_ref(i: u8) -> HashRef {
+    HashRef(vec![i])
+}
+
+fn key_package(i: u8) -> KeyPackage {
+    KeyPackage(format!("key package {i}"))
+}
+
+/// Stored key packages can be listed and counted
+#[test]
+fn key_package_inventory() {
+    let config = SledStorageConfig {
+        key_package_lifetime: Some(DAY),
+        ..Default::default()
+    };
+    let storage =
+        SledStorage::new_from_path_with_config(tempdir().unwrap().path(), config).unwrap();
+    assert_eq!(storage.key_package_count().unwrap(), 0);
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    for i in 0..3 {
+        storage
+            .write_key_package(&hash_ref(i), &key_package(i))
+            .unwrap();
+    }
+    assert_eq!(storage.key_package_count().unwrap(), 3);
+
+    let key_packages = storage.key_packages().unwrap();
+    assert_eq!(key_packages.len(), 3);
+    assert_eq!(
+        key_packages[0].key,
+        serde_json::to_vec(&hash_ref(0)).unwrap()
+    );
+    for info in &key_packages {
+        assert!(info.created_at.unwrap() >= before);
+        assert_eq!(info.lifetime, Some(DAY));
+    }
+
+    storage.delete_key_package(&hash_ref(1)).unwrap();
+    assert_eq!(storage.key_package_count().unwrap(), 2);
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(0)).unwrap();
+    assert_eq!(read, Some(key_package(0)));
+}
+
+/// Expired key packages are reported and purged, the others are kept
+#[test]
+fn purge_expired_key_packages() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    storage
+        .write_key_package_with_lifetime(&hash_ref(0), &key_package(0), Some(DAY))
+        .unwrap();
+    storage
+        .write_key_package_with_lifetime(&hash_ref(1), &key_package(1), Some(7 * DAY))
+        .unwrap();
+    // Without a configured lifetime key packages never expire.
+    storage
+        .write_key_package(&hash_ref(2), &key_package(2))
+        .unwrap();
+
+    let now = SystemTime::now();
+    assert!(storage.expired_key_packages(now).unwrap().is_empty());
+
+    let in_two_days = now + 2 * DAY;
+    let expired = storage.expired_key_packages(in_two_days).unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].key, serde_json::to_vec(&hash_ref(0)).unwrap());
+
+    let purged = storage.purge_expired_key_packages(in_two_days).unwrap();
+    assert_eq!(purged, expired);
+    assert_eq!(storage.key_package_count().unwrap(), 2);
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(0)).unwrap();
+    assert_eq!(read, None);
+
+    let purged = storage.purge_expired_key_packages(now + 365 * DAY).unwrap();
+    assert_eq!(purged.len(), 1);
+    let remaining = storage.key_packages().unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].lifetime, None);
+}
+
+/// Deleting a last-resort key package only counts a use
+#[test]
+fn last_resort_key_packages() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    for i in 0..2 {
+        storage
+            .write_key_package(&hash_ref(i), &key_package(i))
+            .unwrap();
+    }
+    assert!(storage.mark_last_resort_key_package(&hash_ref(9)).is_err());
+    storage.mark_last_resort_key_package(&hash_ref(0)).unwrap();
+
+    // OpenMLS deletes a key package after every join.
+    for _ in 0..3 {
+        storage.delete_key_package(&hash_ref(0)).unwrap();
+    }
+    storage.delete_key_package(&hash_ref(1)).unwrap();
+
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(0)).unwrap();
+    assert_eq!(read, Some(key_package(0)));
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(1)).unwrap();
+    assert_eq!(read, None);
+
+    let last_resort = storage.last_resort_key_packages().unwrap();
+    assert_eq!(last_resort.len(), 1);
+    assert_eq!(
+        last_resort[0].key,
+        serde_json::to_vec(&hash_ref(0)).unwrap()
+    );
+    assert_eq!(last_resort[0].uses, 3);
+}
+
+/// A last-resort key package is replaced by rotating it
+#[test]
+fn rotate_last_resort_key_package() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    storage
+        .write_key_package(&hash_ref(0), &key_package(0))
+        .unwrap();
+    storage.mark_last_resort_key_package(&hash_ref(0)).unwrap();
+    storage.delete_key_package(&hash_ref(0)).unwrap();
+
+    storage
+        .rotate_last_resort_key_package(&hash_ref(0), &hash_ref(1), &key_package(1))
+        .unwrap();
+    assert_eq!(storage.key_package_count().unwrap(), 1);
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(0)).unwrap();
+    assert_eq!(read, None);
+
+    let last_resort = storage.last_resort_key_packages().unwrap();
+    assert_eq!(last_resort.len(), 1);
+    assert_eq!(
+        last_resort[0].key,
+        serde_json::to_vec(&hash_ref(1)).unwrap()
+    );
+    assert_eq!(last_resort[0].uses, 0);
+}
+
+/// Only a stored last-resort key package can be rotated
+#[test]
+fn rotate_needs_last_resort_key_package() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    storage
+        .write_key_package(&hash_ref(0), &key_package(0))
+        .unwrap();
+
+    for old in [hash_ref(0), hash_ref(9)] {
+        assert!(matches!(
+            storage.rotate_last_resort_key_package(&old, &hash_ref(1), &key_package(1)),
+            Err(SledStorageError::NotFound { .. })
+        ));
+    }
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(0)).unwrap();
+    assert_eq!(read, Some(key_package(0)));
+    let read: Option<KeyPackage> = storage.key_package(&hash_ref(1)).unwrap();
+    assert_eq!(read, None);
+}
+
+/// Key packages written and deleted in a batch are listed and counted before
+/// the batch is committed
+#[test]
+fn key_package_inventory_in_batch() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    for i in 0..2 {
+        storage
+            .write_key_package(&hash_ref(i), &key_package(i))
+            .unwrap();
+    }
+
+    let batch = storage.begin_batch();
+    batch.delete_key_package(&hash_ref(0)).unwrap();
+    batch
+        .write_key_package(&hash_ref(2), &key_package(2))
+        .unwrap();
+    assert_eq!(batch.key_package_count().unwrap(), 2);
+    let keys = batch
+        .key_packages()
+        .unwrap()
+        .into_iter()
+        .map(|info| info.key)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        [
+            serde_json::to_vec(&hash_ref(1)).unwrap(),
+            serde_json::to_vec(&hash_ref(2)).unwrap(),
+        ]
+    );
+    assert_eq!(storage.key_package_count().unwrap(), 2);
+
+    batch.commit().unwrap();
+    assert_eq!(storage.key_package_count().unwrap(), 2);
+}
@@ -1,4 +1,7 @@
-use openmls_sled_storage::SledStorage;
+use openmls_sled_storage::{
+    helpers::versioned_tree, DanglingProposalRefs, ErrorContext, KeyFingerprint, Operation,
+    SledStorage, SledStorageConfig, SledStorageError,
+};
 use openmls_traits::storage::{
     traits::{self},
     Entity, Key, StorageProvider, CURRENT_VERSION,
@@ -77,3 +80,141 @@ fn read_write_delete() {
     let proposals_read: Vec<(ProposalRef, Proposal)> = storage.queued_proposals(&group_id).unwrap();
     assert!(proposals_read.is_empty());
 }
+
+/// Queueing and removing proposals never leaves one tree updated without the other
+#[test]
+fn queue_is_atomic() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db.clone()).unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+    let proposals = db
+        .open_tree(versioned_tree::<CURRENT_VERSION>(b"QueuedProposal"))
+        .unwrap();
+    let refs = db
+        .open_tree(versioned_tree::<CURRENT_VERSION>(b"ProposalQueueRefs"))
+        .unwrap();
+
+    storage
+        .queue_proposal(
+            &group_id,
+            &ProposalRef(0),
+            &Proposal(b"TestProposal".to_vec()),
+        )
+        .unwrap();
+    assert_eq!(proposals.len(), 1);
+    assert_eq!(refs.len(), 1);
+
+    // A corrupted ref makes the first half of the removal fail...
+    let (ref_key, ref_value) = refs.first().unwrap().unwrap();
+    refs.insert(&ref_key, b"garbage".to_vec()).unwrap();
+    assert!(storage.remove_proposal(&group_id, &ProposalRef(0)).is_err());
+
+    // ...and the proposal is not removed either.
+    assert_eq!(proposals.len(), 1);
+
+    refs.insert(&ref_key, ref_value).unwrap();
+
+    // Clearing removes both the proposals and the refs.
+    storage
+        .clear_proposal_queue::<TestGroupId, ProposalRef>(&group_id)
+        .unwrap();
+    assert!(proposals.is_empty());
+    assert!(refs.is_empty());
+}
+
+/// Queues three proposals and removes the middle one behind the storage's
+/// back, leaving its reference dangling.
+fn queue_with_dangling_ref(config: SledStorageConfig) -> (sled::Db, SledStorage, TestGroupId) {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db_with_config(db.clone(), config).unwrap();
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+    for i in 0..3 {
+        storage
+            .queue_proposal(&group_id, &ProposalRef(i), &Proposal(vec![i as u8]))
+            .unwrap();
+    }
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"QueuedProposal"))
+        .unwrap()
+        .remove(proposal_key(&group_id, 1))
+        .unwrap();
+    (db, storage, group_id)
+}
+
+fn proposal_key(group_id: &TestGroupId, i: usize) -> Vec<u8> {
+    serde_json::to_vec(&(group_id, ProposalRef(i))).unwrap()
+}
+
+/// By default a proposal ref without a proposal is reported, and kept
+#[test]
+fn dangling_proposal_ref_is_an_error() {
+    let (_db, storage, group_id) = queue_with_dangling_ref(SledStorageConfig::default());
+
+    assert_eq!(
+        storage.queued_proposals::<TestGroupId, ProposalRef, Proposal>(&group_id),
+        Err(SledStorageError::NotFound {
+            context: ErrorContext {
+                operation: Some(Operation::Read),
+                tree: Some("QueuedProposal".to_string()),
+                key: Some(KeyFingerprint::of(&proposal_key(&group_id, 1))),
+            }
+        })
+    );
+    let refs: Vec<ProposalRef> = storage.queued_proposal_refs(&group_id).unwrap();
+    assert_eq!(refs, vec![ProposalRef(0), ProposalRef(1), ProposalRef(2)]);
+}
+
+/// With the repair policy a proposal ref without a proposal is skipped and removed
+#[test]
+fn dangling_proposal_ref_is_repaired() {
+    let config = SledStorageConfig {
+        dangling_proposal_refs: DanglingProposalRefs::Repair,
+        ..Default::default()
+    };
+    let (_db, storage, group_id) = queue_with_dangling_ref(config);
+    let expected = vec![
+        (ProposalRef(0), Proposal(vec![0])),
+        (ProposalRef(2), Proposal(vec![2])),
+    ];
+
+    let proposals: Vec<(ProposalRef, Proposal)> = storage.queued_proposals(&group_id).unwrap();
+    assert_eq!(proposals, expected);
+    let refs: Vec<ProposalRef> = storage.queued_proposal_refs(&group_id).unwrap();
+    assert_eq!(refs, vec![ProposalRef(0), ProposalRef(2)]);
+
+    let proposals: Vec<(ProposalRef, Proposal)> = storage.queued_proposals(&group_id).unwrap();
+    assert_eq!(proposals, expected);
+}
+
+/// A damaged proposal or proposal ref is an error under either policy
+#[test]
+fn damaged_proposal_queue_is_an_error() {
+    for policy in [DanglingProposalRefs::Error, DanglingProposalRefs::Repair] {
+        let config = SledStorageConfig {
+            dangling_proposal_refs: policy,
+            ..Default::default()
+        };
+        let (db, storage, group_id) = queue_with_dangling_ref(config);
+
+        let proposals = db
+            .open_tree(versioned_tree::<CURRENT_VERSION>(b"QueuedProposal"))
+            .unwrap();
+        let key = proposal_key(&group_id, 0);
+        proposals.insert(&key, b"garbage".to_vec()).unwrap();
+        assert!(matches!(
+            storage.queued_proposals::<TestGroupId, ProposalRef, Proposal>(&group_id),
+            Err(SledStorageError::Corrupted { .. })
+        ));
+        proposals.remove(&key).unwrap();
+
+        let refs = db
+            .open_tree(versioned_tree::<CURRENT_VERSION>(b"ProposalQueueRefs"))
+            .unwrap();
+        let (ref_key, _) = refs.first().unwrap().unwrap();
+        refs.insert(&ref_key, b"garbage".to_vec()).unwrap();
+        assert!(matches!(
+            storage.queued_proposals::<TestGroupId, ProposalRef, Proposal>(&group_id),
+            Err(SledStorageError::Corrupted { .. })
+        ));
+        assert_eq!(refs.len(), 3);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs;
+use std::path::Path;
+
+use openmls_sled_storage::{SledStorage, SledStorageConfig, SledStorageError};
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestEpochKey(u64);
+impl traits::EpochKey<CURRENT_VERSION> for TestEpochKey {}
+impl Key<CURRENT_VERSION> for TestEpochKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Secret(String);
+impl Entity<CURRENT_VERSION> for Secret {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for Secret {}
+impl traits::MessageSecrets<CURRENT_VERSION> for Secret {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for Secret {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct GroupContext(String);
+impl traits::GroupContext<CURRENT_VERSION> for GroupContext {}
+impl Entity<CURRENT_VERSION> for GroupContext {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct LeafNode(u8);
+impl traits::LeafNode<CURRENT_VERSION> for LeafNode {}
+impl Entity<CURRENT_VERSION> for LeafNode {}
+
+const SECRET: &str = "ForwardSecrecyMaterial";
+
+fn secure_config() -> SledStorageConfig {
+    SledStorageConfig {
+        secure_delete: true,
+        ..Default::default()
+    }
+}
+
+/// Returns `true` if any file below `dir` contains the needle.
+fn files_contain(dir: &Path, needle: &[u8]) -> bool {
+    fs::read_dir(dir).unwrap().any(|entry| {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            return files_contain(&path, needle);
+        }
+        fs::read(&path)
+            .unwrap()
+            .windows(needle.len())
+            .any(|window| window == needle)
+    })
+}
+
+fn write_secrets(storage: &SledStorage, group_id: &TestGroupId) {
+    let secret = Secret(SECRET.to_string());
+    storage
+        .write_group_epoch_secrets(group_id, &secret)
+        .unwrap();
+    storage.write_message_secrets(group_id, &secret).unwrap();
+    storage
+        .write_encryption_epoch_key_pairs(
+            group_id,
+            &TestEpochKey(1),
+            0,
+            std::slice::from_ref(&secret),
+        )
+        .unwrap();
+    storage
+        .write_context(group_id, &GroupContext("context".to_string()))
+        .unwrap();
+    storage.flush().unwrap();
+}
+
+fn delete_secrets(storage: &SledStorage, group_id: &TestGroupId) {
+    storage.delete_group_epoch_secrets(group_id).unwrap();
+    storage.delete_message_secrets(group_id).unwrap();
+    storage
+        .delete_encryption_epoch_key_pairs(group_id, &TestEpochKey(1), 0)
+        .unwrap();
+}
+
+/// Deleted secrets are gone from the database files after a scrub
+#[test]
+fn deleted_secrets_are_scrubbed() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let mut storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    write_secrets(&storage, &group_id);
+    assert!(files_contain(dir.path(), SECRET.as_bytes()));
+
+    delete_secrets(&storage, &group_id);
+    assert!(storage.scrub_pending().unwrap());
+    storage.scrub().unwrap();
+    storage.flush().unwrap();
+
+    assert!(!storage.scrub_pending().unwrap());
+    assert!(!files_contain(dir.path(), SECRET.as_bytes()));
+
+    // Everything else is still there.
+    let context: Option<GroupContext> = storage.group_context(&group_id).unwrap();
+    assert_eq!(context, Some(GroupContext("context".to_string())));
+}
+
+/// A scrub that was not carried out runs when the database is opened again
+#[test]
+fn pending_scrub_runs_on_open() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    write_secrets(&storage, &group_id);
+    let batch = storage.begin_batch();
+    delete_secrets(&batch, &group_id);
+    batch.commit().unwrap();
+    drop(storage);
+    assert!(files_contain(dir.path(), SECRET.as_bytes()));
+
+    let storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    assert!(!storage.scrub_pending().unwrap());
+    assert!(!files_contain(dir.path(), SECRET.as_bytes()));
+    let secrets: Option<Secret> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, None);
+}
+
+/// Scrub directories are left alone unless secure deletion is enabled
+#[test]
+fn recovery_needs_secure_delete() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let staged = dir.path().join("db.scrub");
+    fs::create_dir(&staged).unwrap();
+
+    drop(SledStorage::new_from_path(&path).unwrap());
+    assert!(staged.exists());
+
+    drop(SledStorage::new_from_path_with_config(&path, secure_config()).unwrap());
+    assert!(!staged.exists());
+}
+
+/// Databases can be opened from paths without a file name
+#[test]
+fn path_without_file_name() {
+    let dir = tempdir().unwrap();
+    let inner = dir.path().join("inner");
+    fs::create_dir(&inner).unwrap();
+    let path = inner.join("..");
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    drop(SledStorage::new_from_path(&path).unwrap());
+
+    let mut storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    write_secrets(&storage, &group_id);
+    delete_secrets(&storage, &group_id);
+    storage.scrub().unwrap();
+    let context: Option<GroupContext> = storage.group_context(&group_id).unwrap();
+    assert_eq!(context, Some(GroupContext("context".to_string())));
+}
+
+/// Writes made right before a scrub are still there after reopening
+#[test]
+fn scrub_keeps_recent_writes() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let mut storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    write_secrets(&storage, &group_id);
+    delete_secrets(&storage, &group_id);
+    // Not flushed explicitly.
+    storage
+        .write_context(&group_id, &GroupContext("latest".to_string()))
+        .unwrap();
+    storage.scrub().unwrap();
+    drop(storage);
+
+    let storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    let context: Option<GroupContext> = storage.group_context(&group_id).unwrap();
+    assert_eq!(context, Some(GroupContext("latest".to_string())));
+    let secrets: Option<Secret> = storage.group_epoch_secrets(&group_id).unwrap();
+    assert_eq!(secrets, None);
+}
+
+/// A scrub is refused while a batch holds a handle to the database
+#[test]
+fn scrub_with_live_batch_is_refused() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("db");
+    let group_id = TestGroupId(b"TestGroupId".to_vec());
+
+    let mut storage = SledStorage::new_from_path_with_config(&path, secure_config()).unwrap();
+    write_secrets(&storage, &group_id);
+    let batch = storage.begin_batch();
+    assert!(matches!(
+        storage.scrub(),
+        Err(SledStorageError::InvalidConfig(_))
+    ));
+    assert!(files_contain(dir.path(), SECRET.as_bytes()));
+
+    delete_secrets(&batch, &group_id);
+    batch.commit().unwrap();
+    storage.scrub().unwrap();
+    assert!(!files_contain(dir.path(), SECRET.as_bytes()));
+}
+
+/// List items appended after a scrub come after the ones appended before it
+#[test]
+fn scrub_keeps_list_order() {
+    let dir = tempdir().unwrap();
+    let group_id = TestGroupId(b"group".to_vec());
+    // Every reopen moves the id generator of sled ahead.
+    for i in 0..3 {
+        let storage = SledStorage::new_from_path(dir.path()).unwrap();
+        storage
+            .append_own_leaf_node(&group_id, &LeafNode(i))
+            .unwrap();
+    }
+
+    let mut storage = SledStorage::new_from_path(dir.path()).unwrap();
+    storage.scrub().unwrap();
+    storage
+        .append_own_leaf_node(&group_id, &LeafNode(3))
+        .unwrap();
+    drop(storage);
+
+    let storage = SledStorage::new_from_path(dir.path()).unwrap();
+    storage
+        .append_own_leaf_node(&group_id, &LeafNode(4))
+        .unwrap();
+    let leaf_nodes: Vec<LeafNode> = storage.own_leaf_nodes(&group_id).unwrap();
+    assert_eq!(leaf_nodes, (0..5).map(LeafNode).collect::<Vec<_>>());
+}
@@ -0,0 +1,116 @@
+use openmls_sled_storage::{
+    codec, helpers::versioned_tree, ErrorContext, IssueKind, KeyFingerprint, SledStorage,
+    SledStorageError,
+};
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestGroupId(Vec<u8>);
+impl traits::GroupId<CURRENT_VERSION> for TestGroupId {}
+impl Key<CURRENT_VERSION> for TestGroupId {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+struct ProposalRef(u8);
+impl traits::ProposalRef<CURRENT_VERSION> for ProposalRef {}
+impl Key<CURRENT_VERSION> for ProposalRef {}
+impl Entity<CURRENT_VERSION> for ProposalRef {}
+
+/// An entity standing in for every kind of group state.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Value(String);
+impl Entity<CURRENT_VERSION> for Value {}
+impl traits::TreeSync<CURRENT_VERSION> for Value {}
+impl traits::GroupContext<CURRENT_VERSION> for Value {}
+impl traits::InterimTranscriptHash<CURRENT_VERSION> for Value {}
+impl traits::ConfirmationTag<CURRENT_VERSION> for Value {}
+impl traits::GroupState<CURRENT_VERSION> for Value {}
+impl traits::MessageSecrets<CURRENT_VERSION> for Value {}
+impl traits::LeafNodeIndex<CURRENT_VERSION> for Value {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for Value {}
+impl traits::QueuedProposal<CURRENT_VERSION> for Value {}
+
+fn write_group(storage: &SledStorage, group_id: &TestGroupId) {
+    let value = Value("value".to_string());
+    storage.write_group_state(group_id, &value).unwrap();
+    storage.write_context(group_id, &value).unwrap();
+    storage.write_tree(group_id, &value).unwrap();
+    storage
+        .write_interim_transcript_hash(group_id, &value)
+        .unwrap();
+    storage.write_confirmation_tag(group_id, &value).unwrap();
+    storage.write_group_epoch_secrets(group_id, &value).unwrap();
+    storage.write_message_secrets(group_id, &value).unwrap();
+    storage.write_own_leaf_index(group_id, &value).unwrap();
+    for i in 0..2 {
+        storage
+            .queue_proposal(group_id, &ProposalRef(i), &value)
+            .unwrap();
+    }
+}
+
+/// A consistent database passes verification
+#[test]
+fn verify_consistent_database() {
+    let storage = SledStorage::new_from_path(tempdir().unwrap().path()).unwrap();
+    assert!(storage.verify().unwrap().is_ok());
+
+    write_group(&storage, &TestGroupId(b"group".to_vec()));
+    let report = storage.verify().unwrap();
+    assert!(report.is_ok(), "{report:?}");
+    assert_eq!(report.groups, 1);
+    // Eight entities, and two proposals with their references.
+    assert_eq!(report.entries, 12);
+}
+
+/// Every problem found is reported
+#[test]
+fn verify_reports_issues() {
+    let db = sled::open(tempdir().unwrap().path()).unwrap();
+    let storage = SledStorage::new_from_db(db.clone()).unwrap();
+    let group = TestGroupId(b"group".to_vec());
+    write_group(&storage, &group);
+    let group_key = serde_json::to_vec(&group).unwrap();
+
+    storage.delete_confirmation_tag(&group).unwrap();
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"GroupState"))
+        .unwrap()
+        .insert(&group_key, b"garbage".to_vec())
+        .unwrap();
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"RatchetTree"))
+        .unwrap()
+        .insert(&group_key, codec::encode_entity(b"{not json"))
+        .unwrap();
+    db.open_tree(versioned_tree::<CURRENT_VERSION>(b"QueuedProposal"))
+        .unwrap()
+        .remove(serde_json::to_vec(&(&group, ProposalRef(0))).unwrap())
+        .unwrap();
+
+    let report = storage.verify().unwrap();
+    let issues = report
+        .issues
+        .iter()
+        .map(|issue| (issue.tree.as_str(), issue.kind.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        issues,
+        vec![
+            ("RatchetTree", IssueKind::InvalidJson),
+            (
+                "GroupState",
+                IssueKind::Undecodable(SledStorageError::Corrupted {
+                    context: ErrorContext {
+                        operation: None,
+                        tree: Some("GroupState".to_string()),
+                        key: Some(KeyFingerprint::of(&group_key)),
+                    }
+                })
+            ),
+            ("ProposalQueueRefs", IssueKind::ProposalMissing),
+            ("ConfirmationTag", IssueKind::GroupEntryMissing),
+        ]
+    );
+    assert_eq!(report.issues[3].key, group_key);
+}
@@ -0,0 +1,116 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use openmls_sled_storage::{SledStorage, StorageKey};
+use openmls_traits::storage::{traits, Entity, Key, StorageProvider, CURRENT_VERSION};
+use serde::{Deserialize, Serialize};
+use tempfile::tempdir;
+
+/// Counts freed allocations that still hold [`SECRET`] while scanning is on.
+struct ScanningAllocator;
+
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static LEAKS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for ScanningAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if SCANNING.load(Ordering::SeqCst) {
+            let freed = std::slice::from_raw_parts(ptr, layout.size());
+            if contains(freed, SECRET.as_bytes()) {
+                LEAKS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+const SECRET: &str = "TopSecretKeyMaterial";
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Runs `f` and returns the number of freed allocations it left the secret in.
+fn leaks<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    LEAKS.store(0, Ordering::SeqCst);
+    SCANNING.store(true, Ordering::SeqCst);
+    let result = f();
+    SCANNING.store(false, Ordering::SeqCst);
+    (result, LEAKS.load(Ordering::SeqCst))
+}
+
+// Test types
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct TestKey(Vec<u8>);
+impl Key<CURRENT_VERSION> for TestKey {}
+impl traits::GroupId<CURRENT_VERSION> for TestKey {}
+impl traits::SignaturePublicKey<CURRENT_VERSION> for TestKey {}
+impl traits::EncryptionKey<CURRENT_VERSION> for TestKey {}
+impl traits::PskId<CURRENT_VERSION> for TestKey {}
+impl traits::EpochKey<CURRENT_VERSION> for TestKey {}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Secret(String);
+impl Entity<CURRENT_VERSION> for Secret {}
+impl traits::SignatureKeyPair<CURRENT_VERSION> for Secret {}
+impl traits::HpkeKeyPair<CURRENT_VERSION> for Secret {}
+impl traits::GroupEpochSecrets<CURRENT_VERSION> for Secret {}
+impl traits::MessageSecrets<CURRENT_VERSION> for Secret {}
+impl traits::PskBundle<CURRENT_VERSION> for Secret {}
+impl traits::ResumptionPskStore<CURRENT_VERSION> for Secret {}
+
+/// No plaintext copies of secrets are left behind in freed memory
+#[test]
+fn secret_buffers_are_wiped() {
+    // The allocator catches a secret that is dropped without being wiped.
+    let (_, count) = leaks(|| drop(SECRET.to_string()));
+    assert_eq!(count, 1);
+
+    let storage =
+        SledStorage::new_from_path_with_key(tempdir().unwrap().path(), StorageKey::generate())
+            .unwrap();
+    let key = TestKey(b"key".to_vec());
+    let secret = Secret(SECRET.to_string());
+    let key_pairs = vec![secret.clone(), secret.clone()];
+
+    let (result, count) = leaks(|| {
+        storage.write_signature_key_pair(&key, &secret)?;
+        storage.write_encryption_key_pair(&key, &secret)?;
+        storage.write_encryption_epoch_key_pairs(&key, &key, 0, &key_pairs)?;
+        storage.write_group_epoch_secrets(&key, &secret)?;
+        storage.write_message_secrets(&key, &secret)?;
+        storage.write_psk(&key, &secret)?;
+        storage.write_resumption_psk_store(&key, &secret)
+    });
+    result.unwrap();
+    assert_eq!(count, 0);
+
+    let (result, count) = leaks(|| {
+        let values = [
+            storage.signature_key_pair::<TestKey, Secret>(&key)?,
+            storage.encryption_key_pair::<Secret, TestKey>(&key)?,
+            storage.group_epoch_secrets::<TestKey, Secret>(&key)?,
+            storage.message_secrets::<TestKey, Secret>(&key)?,
+            storage.psk::<Secret, TestKey>(&key)?,
+            storage.resumption_psk_store::<TestKey, Secret>(&key)?,
+        ];
+        let epoch_key_pairs =
+            storage.encryption_epoch_key_pairs::<TestKey, TestKey, Secret>(&key, &key, 0)?;
+        Ok::<_, openmls_sled_storage::SledStorageError>((values, epoch_key_pairs))
+    });
+    assert_eq!(count, 0);
+    let (values, epoch_key_pairs) = result.unwrap();
+    for value in values {
+        assert_eq!(value, Some(secret.clone()));
+    }
+    assert_eq!(epoch_key_pairs, key_pairs);
+}